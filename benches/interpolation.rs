@@ -0,0 +1,30 @@
+/*
+    Times how long the oscillator takes per sample with each interpolation method, on the default
+    table size, a length that is not a power of two and a large table. Run with `cargo bench`.
+ */
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use wavetable_synth::interpolation::Interpolation;
+use wavetable_synth::oscillator::WavetableOscillator;
+use wavetable_synth::wavetable::{WaveTable, DEFAULT_TABLE_SIZE};
+
+const SAMPLES: usize = 2_000_000;
+
+fn main() {
+    for size in [DEFAULT_TABLE_SIZE, 100, 2048] {
+        println!("{} samples per cycle", size);
+        for interpolation in Interpolation::ALL {
+            let mut oscillator = WavetableOscillator::new(48000, WaveTable::saw(size));
+            oscillator.set_interpolation(interpolation);
+            oscillator.set_frequency(261.63);
+            let started = Instant::now();
+            for _ in 0..SAMPLES {
+                black_box(oscillator.get_sample());
+            }
+            let nanos = started.elapsed().as_nanos() as f64 / SAMPLES as f64;
+            println!("  {:<10}{:>8.2} ns per sample", interpolation.to_string(), nanos);
+        }
+    }
+}
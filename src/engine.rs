@@ -0,0 +1,227 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::envelope::Adsr;
+use crate::filter::{FilterModulation, FilterSettings};
+use crate::mipmap::MipMappedTable;
+use crate::spsc::{self, Consumer, Producer};
+use crate::voice::{StealPolicy, VoiceEvent, VoiceManager, CHANNELS};
+
+/*
+    Live playing goes through one long-lived `Engine`, a `Source` that owns the `VoiceManager` and
+    runs on the audio thread. Everything else talks to it through `Controller`s: each one is the
+    sending end of its own bounded lock-free queue (see `spsc`), so the UI thread and the MIDI
+    input thread each get one and never share a queue. The engine takes whatever commands are
+    waiting before every frame, and since the voices, queues and buffers all exist before
+    playback starts, rendering neither locks nor allocates.
+
+    Swapping the wave table is the one command that would free memory on the audio thread, when
+    the old table's last reference went away. Instead the engine passes the old table back to the
+    controller that sent the new one, which drops it the next time it sends anything.
+ */
+
+/// Commands queued per controller unless asked otherwise.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// A change for the engine to make, sent through a [`Controller`].
+#[derive(Clone, Debug)]
+pub enum Command {
+    Voice(VoiceEvent),
+    SetEnvelope(Adsr),
+    SetFilter(Option<FilterSettings>),
+    SetFilterModulation(FilterModulation),
+    SetGain(f32),
+    SetPolicy(StealPolicy),
+    SwapTable(Arc<MipMappedTable>),
+}
+
+impl From<VoiceEvent> for Command {
+    fn from(event: VoiceEvent) -> Self {
+        Command::Voice(event)
+    }
+}
+
+struct Link {
+    commands: Consumer<Command>,
+    old_tables: Producer<Arc<MipMappedTable>>,
+}
+
+/// Plays a [`VoiceManager`] as a never-ending source of stereo frames, changed only by commands
+/// from its controllers.
+pub struct Engine {
+    voices: VoiceManager,
+    links: Vec<Link>,
+    frame: [f32; 2],
+    channel: usize,
+}
+
+impl Engine {
+    pub fn new(voices: VoiceManager) -> Engine {
+        Engine { voices, links: Vec::new(), frame: [0.0; 2], channel: 0 }
+    }
+
+    /// Opens a queue of `capacity` commands to the engine. Controllers have to be made before the
+    /// engine starts playing, as this allocates.
+    pub fn controller(&mut self, capacity: usize) -> Controller {
+        let (commands, commands_in) = spsc::channel(capacity);
+        // never fuller than the commands that could have swapped a table since it was emptied
+        let (old_tables_out, old_tables) = spsc::channel(commands.capacity());
+        self.links.push(Link { commands: commands_in, old_tables: old_tables_out });
+        Controller { commands, old_tables }
+    }
+
+    pub fn voices(&self) -> &VoiceManager {
+        &self.voices
+    }
+
+    fn apply(voices: &mut VoiceManager, link: &mut Link, command: Command) {
+        match command {
+            Command::Voice(event) => voices.handle(event),
+            Command::SetEnvelope(envelope) => voices.set_envelope(envelope),
+            Command::SetFilter(filter) => voices.set_filter(filter),
+            Command::SetFilterModulation(modulation) => voices.set_filter_modulation(modulation),
+            Command::SetGain(gain) => voices.set_gain(gain),
+            Command::SetPolicy(policy) => voices.set_policy(policy),
+            Command::SwapTable(table) => {
+                let old = voices.set_wave_table(table);
+                // the controller empties this queue before every command it sends, so it has room
+                let _ = link.old_tables.push(old);
+            }
+        }
+    }
+}
+
+impl Iterator for Engine {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.channel == 0 {
+            for link in &mut self.links {
+                while let Some(command) = link.commands.pop() {
+                    Engine::apply(&mut self.voices, link, command);
+                }
+            }
+            self.frame = self.voices.render_frame();
+        }
+        let sample = self.frame[self.channel];
+        self.channel = (self.channel + 1) % CHANNELS as usize;
+        Some(sample)
+    }
+}
+
+impl Source for Engine {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.voices.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Sending side of one queue to an [`Engine`], for a single thread.
+///
+/// The queue is only full when the audio thread has stopped taking commands; the shorthand
+/// methods drop the command then, while [`Controller::send`] hands it back.
+pub struct Controller {
+    commands: Producer<Command>,
+    old_tables: Consumer<Arc<MipMappedTable>>,
+}
+
+impl Controller {
+    pub fn send(&mut self, command: impl Into<Command>) -> Result<(), Command> {
+        // tables the engine has swapped out are freed here, off the audio thread
+        while self.old_tables.pop().is_some() {}
+        self.commands.push(command.into())
+    }
+
+    pub fn handle(&mut self, event: VoiceEvent) {
+        let _ = self.send(event);
+    }
+
+    pub fn note_on(&mut self, note: u8, frequency: f32, velocity: f32) {
+        self.handle(VoiceEvent::NoteOn { note, frequency, velocity });
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.handle(VoiceEvent::NoteOff { note });
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.handle(VoiceEvent::AllNotesOff);
+    }
+
+    /// Moves every voice to `table`; notes keep sounding at their pitch.
+    pub fn swap_table(&mut self, table: impl Into<Arc<MipMappedTable>>) {
+        let _ = self.send(Command::SwapTable(table.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::EnvelopeCurve;
+    use crate::wavetable::WaveTable;
+
+    fn engine() -> Engine {
+        let mut voices = VoiceManager::new(44100, MipMappedTable::from(WaveTable::sine(64)), 2, StealPolicy::Oldest);
+        voices.set_envelope(Adsr::new(0.0, 0.0, 1.0, 0.01, EnvelopeCurve::Linear));
+        Engine::new(voices)
+    }
+
+    #[test]
+    fn follows_commands_from_every_controller() {
+        let mut engine = engine();
+        let mut keys = engine.controller(4);
+        let mut midi = engine.controller(4);
+        assert!(engine.by_ref().take(64).all(|sample| sample == 0.0));
+
+        keys.note_on(69, 440.0, 1.0);
+        midi.send(Command::SetGain(0.0)).unwrap();
+        assert!(engine.by_ref().take(64).all(|sample| sample == 0.0));
+        assert_eq!(engine.voices().active_notes(), vec![69]);
+
+        midi.send(Command::SetGain(1.0)).unwrap();
+        let samples: Vec<f32> = engine.by_ref().take(64).collect();
+        assert!(samples.iter().any(|sample| sample.abs() > 0.1));
+        keys.handle(VoiceEvent::AllSoundOff);
+        assert!(engine.take(64).all(|sample| sample == 0.0));
+    }
+
+    #[test]
+    fn hands_commands_back_when_full() {
+        let mut engine = engine();
+        let mut controller = engine.controller(2);
+        controller.send(Command::SetGain(0.5)).unwrap();
+        controller.send(Command::SetGain(0.5)).unwrap();
+        assert!(matches!(controller.send(Command::SetGain(0.25)), Err(Command::SetGain(_))));
+        engine.next();
+        assert!(controller.send(Command::SetGain(0.25)).is_ok());
+    }
+
+    #[test]
+    fn frees_swapped_tables_on_the_controller() {
+        let mut engine = engine();
+        let mut controller = engine.controller(4);
+        let first = engine.voices().wave_table().clone();
+        let second = Arc::new(MipMappedTable::from(WaveTable::saw(64)));
+
+        controller.swap_table(second.clone());
+        engine.next();
+        assert!(Arc::ptr_eq(engine.voices().wave_table(), &second));
+        // the engine and the controller's queue still hold the first table
+        assert_eq!(Arc::strong_count(&first), 2);
+        controller.note_off(69);
+        assert_eq!(Arc::strong_count(&first), 1);
+    }
+}
@@ -0,0 +1,448 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rodio::{Sample, Source};
+
+/*
+    An ADSR envelope shapes a note's loudness over time: it rises to full level during the attack,
+    falls to the sustain level during the decay, holds there while the note is held, and fades to
+    silence during the release once the note is let go.
+
+    Exponential stages follow a one-pole curve aimed slightly past their target, so each stage
+    still ends after its set time instead of creeping towards the target forever.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvelopeCurve {
+    Linear,
+    Exponential,
+}
+
+impl FromStr for EnvelopeCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "linear" | "lin" => Ok(EnvelopeCurve::Linear),
+            "exponential" | "exp" => Ok(EnvelopeCurve::Exponential),
+            _ => Err(format!("unknown envelope curve '{}' (expected linear or exponential)", s)),
+        }
+    }
+}
+
+impl fmt::Display for EnvelopeCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeCurve::Linear => write!(f, "linear"),
+            EnvelopeCurve::Exponential => write!(f, "exponential"),
+        }
+    }
+}
+
+/// Envelope settings. Times are in seconds, `sustain` is a level from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adsr {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub curve: EnvelopeCurve,
+}
+
+impl Adsr {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32, curve: EnvelopeCurve) -> Adsr {
+        Adsr {
+            attack: attack.max(0.0),
+            decay: decay.max(0.0),
+            sustain: sustain.clamp(0.0, 1.0),
+            release: release.max(0.0),
+            curve,
+        }
+    }
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr::new(0.005, 0.1, 0.8, 0.2, EnvelopeCurve::Exponential)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// How far past its target an exponential stage aims; smaller is more curved.
+const ATTACK_OVERSHOOT: f32 = 0.3;
+const DECAY_OVERSHOOT: f32 = 0.0001;
+
+/// A running envelope, advanced one sample at a time.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    sample_rate: u32,
+    settings: Adsr,
+    stage: Stage,
+    level: f32,
+    // Per-sample update for the current stage: linear step and samples left, or one-pole
+    // coefficient and base.
+    step: f32,
+    remaining: u32,
+    coefficient: f32,
+    base: f32,
+}
+
+impl Envelope {
+    pub fn new(sample_rate: u32, settings: Adsr) -> Envelope {
+        Envelope {
+            sample_rate,
+            settings,
+            stage: Stage::Idle,
+            level: 0.0,
+            step: 0.0,
+            remaining: 0,
+            coefficient: 0.0,
+            base: 0.0,
+        }
+    }
+
+    /// Changes the settings; a stage already running keeps its rate until the next stage.
+    pub fn set_settings(&mut self, settings: Adsr) {
+        self.settings = settings;
+    }
+
+    pub fn settings(&self) -> Adsr {
+        self.settings
+    }
+
+    /// Starts the attack from the current level, so retriggering a sounding note does not click.
+    pub fn gate_on(&mut self) {
+        self.enter(Stage::Attack);
+    }
+
+    /// Moves to the release stage from wherever the envelope is.
+    pub fn gate_off(&mut self) {
+        if self.stage != Stage::Idle {
+            self.enter(Stage::Release);
+        }
+    }
+
+    /// Silences the envelope at once.
+    pub fn reset(&mut self) {
+        self.stage = Stage::Idle;
+        self.level = 0.0;
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// True until the release has faded out.
+    pub fn is_active(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    /// True between gate on and gate off.
+    pub fn is_held(&self) -> bool {
+        matches!(self.stage, Stage::Attack | Stage::Decay | Stage::Sustain)
+    }
+
+    fn enter(&mut self, stage: Stage) {
+        self.stage = stage;
+        let (time, target, overshoot) = match stage {
+            Stage::Attack => (self.settings.attack, 1.0, ATTACK_OVERSHOOT),
+            Stage::Decay => (self.settings.decay, self.settings.sustain, -DECAY_OVERSHOOT),
+            Stage::Release => (self.settings.release, 0.0, -DECAY_OVERSHOOT),
+            Stage::Sustain => {
+                self.level = self.settings.sustain;
+                return;
+            }
+            Stage::Idle => {
+                self.level = 0.0;
+                return;
+            }
+        };
+
+        let samples = time * self.sample_rate as f32;
+        if samples < 1.0 {
+            self.level = target;
+            self.advance_stage();
+            return;
+        }
+        match self.settings.curve {
+            EnvelopeCurve::Linear => {
+                // Attack and decay move at the rate of their full range, so an attack retriggered
+                // part-way up ends early; the release always takes its set time from wherever it
+                // starts.
+                self.step = match stage {
+                    Stage::Attack => 1.0 / samples,
+                    Stage::Decay => -(1.0 - self.settings.sustain) / samples,
+                    _ => -self.level / samples,
+                };
+                // Counting samples rather than comparing levels keeps rounding from adding a
+                // sample to the stage.
+                let stage_samples = match stage {
+                    Stage::Attack => (1.0 - self.level) * samples,
+                    _ => samples,
+                };
+                self.remaining = (stage_samples.round() as u32).max(1);
+            }
+            EnvelopeCurve::Exponential => {
+                let ratio = overshoot.abs();
+                self.coefficient = (-((1.0 + ratio) / ratio).ln() / samples).exp();
+                self.base = (target + overshoot) * (1.0 - self.coefficient);
+            }
+        }
+    }
+
+    /// Moves on once the current stage has reached its target.
+    fn advance_stage(&mut self) {
+        match self.stage {
+            Stage::Attack => self.enter(Stage::Decay),
+            Stage::Decay => self.enter(Stage::Sustain),
+            Stage::Release => self.enter(Stage::Idle),
+            Stage::Sustain | Stage::Idle => {}
+        }
+    }
+
+    /// Returns the level for this sample and advances by one sample.
+    pub fn next_level(&mut self) -> f32 {
+        let level = self.level;
+        let (target, rising) = match self.stage {
+            Stage::Idle | Stage::Sustain => return level,
+            Stage::Attack => (1.0, true),
+            Stage::Decay => (self.settings.sustain, false),
+            Stage::Release => (0.0, false),
+        };
+        let reached = match self.settings.curve {
+            EnvelopeCurve::Linear => {
+                self.level += self.step;
+                self.remaining -= 1;
+                self.remaining == 0
+            }
+            EnvelopeCurve::Exponential => {
+                self.level = self.base + self.level * self.coefficient;
+                (rising && self.level >= target) || (!rising && self.level <= target)
+            }
+        };
+        if reached {
+            self.level = target;
+            self.advance_stage();
+        }
+        level
+    }
+}
+
+/// Applies an [`Envelope`] to a source, ending the source once the release has finished.
+///
+/// The gate opens when the source starts. It closes either when [`EnvelopeSource::release`] is
+/// called or, when built with [`EnvelopeSource::with_gate`], after a fixed note length.
+pub struct EnvelopeSource<S> {
+    source: S,
+    envelope: Envelope,
+    gate_frames: Option<u64>,
+    frames_played: u64,
+    channel: u16,
+    level: f32,
+}
+
+impl<S> EnvelopeSource<S>
+    where
+        S: Source,
+        S::Item: Sample,
+{
+    pub fn new(source: S, settings: Adsr) -> Self {
+        let mut envelope = Envelope::new(source.sample_rate(), settings);
+        envelope.gate_on();
+        EnvelopeSource {
+            source,
+            envelope,
+            gate_frames: None,
+            frames_played: 0,
+            channel: 0,
+            level: 0.0,
+        }
+    }
+
+    /// Holds the note for `gate` and then releases it.
+    pub fn with_gate(source: S, settings: Adsr, gate: Duration) -> Self {
+        let mut envelope_source = EnvelopeSource::new(source, settings);
+        let frames = (gate.as_secs_f64() * envelope_source.source.sample_rate() as f64).round() as u64;
+        envelope_source.gate_frames = Some(frames);
+        envelope_source
+    }
+
+    /// Note-off: enters the release stage.
+    pub fn release(&mut self) {
+        self.envelope.gate_off();
+    }
+
+    pub fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+}
+
+impl<S> Iterator for EnvelopeSource<S>
+    where
+        S: Source,
+        S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The level changes once per frame so all channels of a frame get the same gain.
+        if self.channel == 0 {
+            if self.gate_frames == Some(self.frames_played) {
+                self.envelope.gate_off();
+            }
+            if !self.envelope.is_active() {
+                return None;
+            }
+            self.level = self.envelope.next_level();
+            self.frames_played += 1;
+        }
+        let sample = self.source.next()?;
+        self.channel = (self.channel + 1) % self.source.channels().max(1);
+        Some(sample.amplify(self.level))
+    }
+}
+
+impl<S> Source for EnvelopeSource<S>
+    where
+        S: Source,
+        S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let sample_rate = self.source.sample_rate() as u64;
+        let release_frames = (self.envelope.settings().release * sample_rate as f32).round() as u64;
+        let frames = self.gate_frames? + release_frames;
+        Some(Duration::from_nanos(frames * 1_000_000_000 / sample_rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::WavetableOscillator;
+    use crate::wavetable::WaveTable;
+
+    fn run(envelope: &mut Envelope, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| envelope.next_level()).collect()
+    }
+
+    #[test]
+    fn linear_stages_take_their_set_time() {
+        let mut envelope = Envelope::new(1000, Adsr::new(0.01, 0.02, 0.5, 0.04, EnvelopeCurve::Linear));
+        envelope.gate_on();
+
+        let attack = run(&mut envelope, 10);
+        assert_eq!(attack[0], 0.0);
+        assert!((attack[5] - 0.5).abs() < 1e-5);
+        assert_eq!(envelope.stage(), Stage::Decay);
+        assert_eq!(envelope.level(), 1.0);
+
+        run(&mut envelope, 20);
+        assert_eq!(envelope.stage(), Stage::Sustain);
+        assert_eq!(run(&mut envelope, 100), vec![0.5; 100]);
+
+        envelope.gate_off();
+        let release = run(&mut envelope, 20);
+        assert!((release[10] - 0.375).abs() < 1e-5);
+        run(&mut envelope, 20);
+        assert!(!envelope.is_active());
+        assert_eq!(envelope.level(), 0.0);
+    }
+
+    #[test]
+    fn exponential_stages_finish_on_time() {
+        let mut envelope = Envelope::new(1000, Adsr::new(0.01, 0.02, 0.5, 0.04, EnvelopeCurve::Exponential));
+        envelope.gate_on();
+        let attack = run(&mut envelope, 10);
+        // Concave attack: past halfway at the midpoint.
+        assert!(attack[5] > 0.5);
+        assert_eq!(envelope.stage(), Stage::Decay);
+
+        let decay = run(&mut envelope, 20);
+        // Convex decay: most of the drop happens early.
+        assert!(decay[10] < 0.55);
+        assert_eq!(envelope.stage(), Stage::Sustain);
+
+        envelope.gate_off();
+        run(&mut envelope, 40);
+        assert!(!envelope.is_active());
+    }
+
+    #[test]
+    fn release_starts_from_the_current_level() {
+        let mut envelope = Envelope::new(1000, Adsr::new(0.1, 0.1, 1.0, 0.01, EnvelopeCurve::Linear));
+        envelope.gate_on();
+        run(&mut envelope, 20);
+        let level = envelope.level();
+        envelope.gate_off();
+        assert_eq!(envelope.next_level(), level);
+        assert!(envelope.next_level() < level);
+        run(&mut envelope, 10);
+        assert!(!envelope.is_active());
+    }
+
+    #[test]
+    fn zero_times_jump_straight_to_sustain() {
+        let mut envelope = Envelope::new(44100, Adsr::new(0.0, 0.0, 0.7, 0.0, EnvelopeCurve::Linear));
+        envelope.gate_on();
+        assert_eq!(envelope.stage(), Stage::Sustain);
+        assert_eq!(envelope.next_level(), 0.7);
+        envelope.gate_off();
+        assert!(!envelope.is_active());
+    }
+
+    #[test]
+    fn gated_source_releases_instead_of_cutting_off() {
+        let mut oscillator = WavetableOscillator::new(1000, WaveTable::sine(64));
+        oscillator.set_frequency(100.0);
+        let settings = Adsr::new(0.005, 0.0, 1.0, 0.05, EnvelopeCurve::Linear);
+        let source = EnvelopeSource::with_gate(oscillator, settings, Duration::from_millis(100));
+        assert_eq!(source.total_duration(), Some(Duration::from_millis(150)));
+
+        let samples: Vec<f32> = source.collect();
+        assert_eq!(samples.len(), 150);
+        // Fades in and out instead of starting and stopping at full level.
+        assert!(samples[1].abs() < 0.2);
+        assert!(samples[149].abs() < 0.05);
+        let peak_in_release = samples[100..].iter().fold(0.0f32, |p, s| p.max(s.abs()));
+        assert!(peak_in_release > 0.5);
+    }
+
+    #[test]
+    fn gates_stereo_sources_by_the_frame() {
+        let stereo = rodio::buffer::SamplesBuffer::new(2, 1000, vec![1.0f32; 1000]);
+        let settings = Adsr::new(0.005, 0.0, 1.0, 0.005, EnvelopeCurve::Linear);
+        let source = EnvelopeSource::with_gate(stereo, settings, Duration::from_millis(10));
+        assert_eq!(source.total_duration(), Some(Duration::from_millis(15)));
+
+        let samples: Vec<f32> = source.collect();
+        // 10 frames held and 5 released, with both channels of a frame at the same level
+        assert_eq!(samples.len(), 30);
+        assert!(samples.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert!(samples[2] > samples[0] && samples[28] < samples[20]);
+    }
+}
@@ -0,0 +1,406 @@
+use std::f32::consts::{PI, SQRT_2};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::envelope::{Adsr, EnvelopeCurve};
+
+/*
+    Each voice runs its oscillator through its own resonant filter, so notes keep separate filter
+    state and, later, separate cutoff movements.
+
+    Both models are built from trapezoidal (TPT) integrators, also called zero-delay feedback
+    filters: the analog circuit is discretised with the bilinear transform and the feedback loop
+    is solved for the current sample instead of being delayed by one. That keeps the cutoff where
+    it was set right up to the top of the range, and keeps the filter stable while the cutoff and
+    resonance change from sample to sample.
+
+    - The state-variable filter has two integrators and gives all four responses at once, at
+      12 dB per octave. Resonance 0 is a Butterworth response (Q of 1/√2); resonance 1 is a Q of
+      50. Its band pass is scaled to unity at its peak.
+    - The ladder is four one-pole low passes in a row with the output fed back negatively, as in
+      Moog's design, at 24 dB per octave. High pass, band pass and notch are mixed from the
+      stage outputs, as on the Oberheim Xpander. Resonance 1 sits just below self-oscillation,
+      and like the original, a lot of resonance thins out the pass band. This model is linear:
+      there is no saturation in the loop.
+
+    The cutoff a voice actually uses moves in octaves around the set one: its filter envelope adds
+    up to `amount` octaves at the envelope's peak (or takes them away, for a negative amount),
+    scaled down for soft notes by the velocity sensitivity, and key tracking adds the note's
+    distance from middle C, so with full tracking the filter opens an octave per octave played.
+ */
+
+/// Lowest damping of the state-variable filter, i.e. a Q of 50.
+const MIN_DAMPING: f32 = 0.02;
+
+/// Ladder feedback at full resonance; 4 is where it oscillates on its own.
+const MAX_LADDER_FEEDBACK: f32 = 3.9;
+
+/// Highest cutoff as a share of the sample rate, safely under Nyquist.
+const MAX_CUTOFF_RATIO: f32 = 0.49;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+}
+
+impl FromStr for FilterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lowpass" | "lp" => Ok(FilterMode::Lowpass),
+            "highpass" | "hp" => Ok(FilterMode::Highpass),
+            "bandpass" | "bp" => Ok(FilterMode::Bandpass),
+            "notch" => Ok(FilterMode::Notch),
+            _ => Err(format!("unknown filter mode '{}' (expected lowpass, highpass, bandpass or notch)", s)),
+        }
+    }
+}
+
+impl fmt::Display for FilterMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FilterMode::Lowpass => "lowpass",
+            FilterMode::Highpass => "highpass",
+            FilterMode::Bandpass => "bandpass",
+            FilterMode::Notch => "notch",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterModel {
+    /// 12 dB per octave state-variable filter.
+    StateVariable,
+    /// 24 dB per octave Moog-style ladder.
+    Ladder,
+}
+
+impl FromStr for FilterModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "svf" | "state-variable" => Ok(FilterModel::StateVariable),
+            "ladder" | "moog" => Ok(FilterModel::Ladder),
+            _ => Err(format!("unknown filter model '{}' (expected svf or ladder)", s)),
+        }
+    }
+}
+
+impl fmt::Display for FilterModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterModel::StateVariable => write!(f, "svf"),
+            FilterModel::Ladder => write!(f, "ladder"),
+        }
+    }
+}
+
+/// Filter settings. `cutoff` is in Hz, `resonance` from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterSettings {
+    pub model: FilterModel,
+    pub mode: FilterMode,
+    pub cutoff: f32,
+    pub resonance: f32,
+}
+
+impl FilterSettings {
+    pub fn new(model: FilterModel, mode: FilterMode, cutoff: f32, resonance: f32) -> FilterSettings {
+        FilterSettings { model, mode, cutoff: cutoff.max(0.0), resonance: resonance.clamp(0.0, 1.0) }
+    }
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings::new(FilterModel::StateVariable, FilterMode::Lowpass, 2000.0, 0.0)
+    }
+}
+
+/// Note frequency at which key tracking leaves the cutoff alone: middle C.
+pub const KEY_TRACKING_CENTRE: f32 = 261.6256;
+
+/// How each voice moves its filter's cutoff while a note plays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterModulation {
+    /// Filter envelope, triggered with the note.
+    pub envelope: Adsr,
+    /// Octaves the envelope moves the cutoff at full level; negative amounts close the filter.
+    pub amount: f32,
+    /// Share of the note's pitch the cutoff follows, from 0 to 1.
+    pub key_tracking: f32,
+    /// How much softer notes shrink the envelope amount, from 0 (not at all) to 1 (in proportion).
+    pub velocity: f32,
+}
+
+impl FilterModulation {
+    pub fn new(envelope: Adsr, amount: f32, key_tracking: f32, velocity: f32) -> FilterModulation {
+        FilterModulation { envelope, amount, key_tracking: key_tracking.clamp(0.0, 1.0), velocity: velocity.clamp(0.0, 1.0) }
+    }
+
+    /// The cutoff for a note at `frequency` played at `velocity`, with its filter envelope at
+    /// `level`.
+    pub fn cutoff(&self, cutoff: f32, level: f32, frequency: f32, velocity: f32) -> f32 {
+        let depth = self.amount * (1.0 - self.velocity + self.velocity * velocity);
+        let tracking = self.key_tracking * (frequency / KEY_TRACKING_CENTRE).log2();
+        cutoff * (depth * level + tracking).exp2()
+    }
+}
+
+impl Default for FilterModulation {
+    /// No movement at all.
+    fn default() -> Self {
+        FilterModulation::new(Adsr::new(0.0, 0.0, 1.0, 0.0, EnvelopeCurve::Linear), 0.0, 0.0, 0.0)
+    }
+}
+
+/// One channel of a resonant filter with its own state.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    sample_rate: u32,
+    settings: FilterSettings,
+    // Integrator gain, tan(π fc / fs).
+    g: f32,
+    // Damping (state-variable) or feedback (ladder).
+    k: f32,
+    // State-variable integrator states.
+    ic1: f32,
+    ic2: f32,
+    // Ladder stage states.
+    stages: [f32; 4],
+}
+
+impl Filter {
+    pub fn new(sample_rate: u32, settings: FilterSettings) -> Filter {
+        let mut filter = Filter { sample_rate, settings, g: 0.0, k: 0.0, ic1: 0.0, ic2: 0.0, stages: [0.0; 4] };
+        filter.set_settings(settings);
+        filter
+    }
+
+    pub fn settings(&self) -> FilterSettings {
+        self.settings
+    }
+
+    /// Changes the settings without clearing the state, so it can be done while a note plays.
+    pub fn set_settings(&mut self, settings: FilterSettings) {
+        self.settings = settings;
+        self.k = match settings.model {
+            FilterModel::StateVariable => (SQRT_2 * (1.0 - settings.resonance)).max(MIN_DAMPING),
+            FilterModel::Ladder => MAX_LADDER_FEEDBACK * settings.resonance,
+        };
+        self.set_cutoff(settings.cutoff);
+    }
+
+    /// Moves the cutoff alone, e.g. once per sample under modulation.
+    pub fn set_cutoff(&mut self, cutoff: f32) {
+        let max = self.sample_rate as f32 * MAX_CUTOFF_RATIO;
+        self.settings.cutoff = cutoff.clamp(1.0, max);
+        self.g = (PI * self.settings.cutoff / self.sample_rate as f32).tan();
+    }
+
+    /// Clears the state, as if the filter had only ever heard silence.
+    pub fn reset(&mut self) {
+        self.ic1 = 0.0;
+        self.ic2 = 0.0;
+        self.stages = [0.0; 4];
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        match self.settings.model {
+            FilterModel::StateVariable => self.process_svf(input),
+            FilterModel::Ladder => self.process_ladder(input),
+        }
+    }
+
+    fn process_svf(&mut self, input: f32) -> f32 {
+        let (g, k) = (self.g, self.k);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        let v3 = input - self.ic2;
+        let band = a1 * self.ic1 + a2 * v3;
+        let low = self.ic2 + a2 * self.ic1 + a3 * v3;
+        self.ic1 = 2.0 * band - self.ic1;
+        self.ic2 = 2.0 * low - self.ic2;
+        match self.settings.mode {
+            FilterMode::Lowpass => low,
+            FilterMode::Highpass => input - k * band - low,
+            FilterMode::Bandpass => k * band,
+            FilterMode::Notch => input - k * band,
+        }
+    }
+
+    /*
+        Each stage is a TPT one-pole low pass, y = G x + (1 - G) s with G = g / (1 + g), so the
+        last stage's output is G⁴ u plus a sum of the states. With u = x - k y4 that solves to
+        y4 = (G⁴ x + S) / (1 + k G⁴), and the stages then run forward from u as usual.
+     */
+    fn process_ladder(&mut self, input: f32) -> f32 {
+        let big_g = self.g / (1.0 + self.g);
+        let states = self.stages.iter().fold(0.0, |sum, state| sum * big_g + (1.0 - big_g) * state);
+        let g4 = big_g.powi(4);
+        let y4 = (g4 * input + states) / (1.0 + self.k * g4);
+        let u = input - self.k * y4;
+
+        let mut outputs = [0.0; 4];
+        let mut x = u;
+        for (state, output) in self.stages.iter_mut().zip(outputs.iter_mut()) {
+            let v = big_g * (x - *state);
+            let y = v + *state;
+            *state = y + v;
+            *output = y;
+            x = y;
+        }
+        let [y1, y2, y3, y4] = outputs;
+        match self.settings.mode {
+            FilterMode::Lowpass => y4,
+            FilterMode::Highpass => u - 4.0 * y1 + 6.0 * y2 - 4.0 * y3 + y4,
+            FilterMode::Bandpass => 4.0 * (y2 - 2.0 * y3 + y4),
+            FilterMode::Notch => u - 2.0 * y1 + 2.0 * y2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Steady-state gain for a unit sine at `frequency`, from the RMS level of the second half
+    /// second, which holds a whole number of cycles for every frequency tested.
+    fn gain(settings: FilterSettings, frequency: f32) -> f32 {
+        let mut filter = Filter::new(SAMPLE_RATE, settings);
+        let step = 2.0 * std::f64::consts::PI * frequency as f64 / SAMPLE_RATE as f64;
+        let output: Vec<f32> = (0..48000).map(|n| filter.process((n as f64 * step).sin() as f32)).collect();
+        let power = output[24000..].iter().map(|sample| sample * sample).sum::<f32>() / 24000.0;
+        (2.0 * power).sqrt()
+    }
+
+    fn svf(mode: FilterMode, resonance: f32) -> FilterSettings {
+        FilterSettings::new(FilterModel::StateVariable, mode, 1000.0, resonance)
+    }
+
+    fn ladder(mode: FilterMode, resonance: f32) -> FilterSettings {
+        FilterSettings::new(FilterModel::Ladder, mode, 1000.0, resonance)
+    }
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn state_variable_low_and_high_pass_at_12_db_per_octave() {
+        let low = svf(FilterMode::Lowpass, 0.0);
+        assert_near(gain(low, 50.0), 1.0, 0.01);
+        assert_near(gain(low, 1000.0), 0.5f32.sqrt(), 0.01);
+        // two octaves up is 24 dB down, a little more with the bilinear transform
+        assert!(gain(low, 4000.0) < 0.065);
+
+        let high = svf(FilterMode::Highpass, 0.0);
+        assert_near(gain(high, 16000.0), 1.0, 0.02);
+        assert_near(gain(high, 1000.0), 0.5f32.sqrt(), 0.01);
+        assert!(gain(high, 250.0) < 0.065);
+    }
+
+    #[test]
+    fn state_variable_band_pass_and_notch_centre_on_the_cutoff() {
+        let band = svf(FilterMode::Bandpass, 0.5);
+        assert_near(gain(band, 1000.0), 1.0, 0.01);
+        assert!(gain(band, 100.0) < 0.1 && gain(band, 10000.0) < 0.1);
+
+        let notch = svf(FilterMode::Notch, 0.5);
+        assert!(gain(notch, 1000.0) < 0.01);
+        assert_near(gain(notch, 50.0), 1.0, 0.01);
+        assert_near(gain(notch, 16000.0), 1.0, 0.02);
+    }
+
+    #[test]
+    fn resonance_peaks_at_the_cutoff() {
+        let mut previous = 0.0;
+        for resonance in [0.0, 0.5, 0.8, 0.95] {
+            let peak = gain(svf(FilterMode::Lowpass, resonance), 1000.0);
+            assert!(peak > previous, "resonance {}: {} after {}", resonance, peak, previous);
+            previous = peak;
+        }
+        assert!(previous > 5.0);
+        // at full resonance the Q is 50
+        assert_near(gain(svf(FilterMode::Lowpass, 1.0), 1000.0), 50.0, 1.0);
+    }
+
+    #[test]
+    fn ladder_low_pass_at_24_db_per_octave() {
+        let low = ladder(FilterMode::Lowpass, 0.0);
+        assert_near(gain(low, 20.0), 1.0, 0.01);
+        // four poles at the cutoff give (1/√2)⁴
+        assert_near(gain(low, 1000.0), 0.25, 0.01);
+        assert!(gain(low, 4000.0) < 0.005);
+
+        // resonance lifts the cutoff above the pass band, which drops to 1 / (1 + k)
+        let resonant = ladder(FilterMode::Lowpass, 0.9);
+        assert_near(gain(resonant, 20.0), 1.0 / (1.0 + 0.9 * MAX_LADDER_FEEDBACK), 0.01);
+        assert!(gain(resonant, 1000.0) > 2.0 * gain(resonant, 20.0));
+    }
+
+    #[test]
+    fn ladder_mixes_the_other_modes_from_its_stages() {
+        let high = ladder(FilterMode::Highpass, 0.0);
+        assert_near(gain(high, 20000.0), 1.0, 0.02);
+        assert!(gain(high, 250.0) < 0.005);
+
+        let band = ladder(FilterMode::Bandpass, 0.0);
+        assert_near(gain(band, 1000.0), 1.0, 0.02);
+        assert!(gain(band, 100.0) < 0.05 && gain(band, 10000.0) < 0.05);
+
+        let notch = ladder(FilterMode::Notch, 0.0);
+        assert!(gain(notch, 1000.0) < 0.01);
+        assert_near(gain(notch, 20.0), 1.0, 0.02);
+    }
+
+    #[test]
+    fn stays_stable_at_full_resonance_and_extreme_cutoffs() {
+        for model in [FilterModel::StateVariable, FilterModel::Ladder] {
+            for cutoff in [1.0, 20000.0, 1e9] {
+                let mut filter = Filter::new(SAMPLE_RATE, FilterSettings::new(model, FilterMode::Lowpass, cutoff, 1.0));
+                // a square wave hits the resonance as hard as anything
+                let peak = (0..SAMPLE_RATE)
+                    .map(|n| filter.process(if n / 50 % 2 == 0 { 1.0 } else { -1.0 }))
+                    .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+                assert!(peak.is_finite() && peak < 100.0, "{} at {} Hz: {}", model, cutoff, peak);
+            }
+        }
+    }
+
+    #[test]
+    fn modulation_moves_the_cutoff_in_octaves() {
+        let modulation = FilterModulation::new(Adsr::default(), 2.0, 0.0, 0.0);
+        assert_near(modulation.cutoff(500.0, 0.0, 440.0, 1.0), 500.0, 1e-3);
+        assert_near(modulation.cutoff(500.0, 1.0, 440.0, 1.0), 2000.0, 1e-2);
+        assert_near(modulation.cutoff(500.0, 0.5, 440.0, 0.1), 1000.0, 1e-2);
+
+        let closing = FilterModulation::new(Adsr::default(), -1.0, 0.0, 1.0);
+        assert_near(closing.cutoff(500.0, 1.0, 440.0, 1.0), 250.0, 1e-2);
+        // with full velocity sensitivity a silent note leaves the cutoff alone
+        assert_near(closing.cutoff(500.0, 1.0, 440.0, 0.0), 500.0, 1e-2);
+
+        let tracking = FilterModulation::new(Adsr::default(), 0.0, 1.0, 0.0);
+        assert_near(tracking.cutoff(500.0, 1.0, KEY_TRACKING_CENTRE, 1.0), 500.0, 1e-2);
+        assert_near(tracking.cutoff(500.0, 1.0, 2.0 * KEY_TRACKING_CENTRE, 1.0), 1000.0, 1e-2);
+        let half = FilterModulation { key_tracking: 0.5, ..tracking };
+        assert_near(half.cutoff(500.0, 1.0, 4.0 * KEY_TRACKING_CENTRE, 1.0), 1000.0, 1e-2);
+    }
+
+    #[test]
+    fn parses_mode_and_model_names() {
+        assert_eq!("LP".parse::<FilterMode>(), Ok(FilterMode::Lowpass));
+        assert_eq!("notch".parse::<FilterMode>(), Ok(FilterMode::Notch));
+        assert!("comb".parse::<FilterMode>().is_err());
+        assert_eq!("moog".parse::<FilterModel>(), Ok(FilterModel::Ladder));
+        assert_eq!(FilterModel::StateVariable.to_string().parse::<FilterModel>(), Ok(FilterModel::StateVariable));
+    }
+}
@@ -0,0 +1,205 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/*
+    The oscillator's index almost never lands exactly on an entry of its table, so every sample is
+    read from the entries around it. The cheapest reading takes the entry before the index as it
+    is, which turns a smooth wave into a staircase; a straight line between the two neighbours
+    is much closer, but still leaves corners that sound as noise and dulls the top of small
+    tables.
+
+    Cubic Hermite (Catmull-Rom) and 4-point Lagrange both fit a cubic through the four entries
+    around the index. Hermite also matches the slope at the two inner entries, so the curve stays
+    smooth from one pair to the next; Lagrange passes through all four exactly. A windowed sinc
+    is the closest to ideal band-limited reconstruction: a sinc over `SINC_TAPS` entries, tapered
+    with a Blackman window so it ends at zero. Its weights are worked out once, for
+    `SINC_PHASES` points between two entries, and read with a straight line between them.
+
+    Every method reproduces the table exactly at whole indices. Tables are single cycles, so the
+    neighbours of the first and last entries come from the other end of the table.
+ */
+
+/// Entries the windowed sinc reads around each index.
+pub const SINC_TAPS: usize = 8;
+
+/// Points between two table entries at which the sinc weights are worked out.
+const SINC_PHASES: usize = 512;
+
+/// How the oscillator reads its table between entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    /// The entry at or before the index, unchanged.
+    None,
+    #[default]
+    Linear,
+    /// Catmull-Rom spline through the four entries around the index.
+    Hermite,
+    /// Third-order polynomial through the four entries around the index.
+    Lagrange,
+    /// Blackman-windowed sinc over `SINC_TAPS` entries.
+    Sinc,
+}
+
+impl Interpolation {
+    pub const ALL: [Interpolation; 5] =
+        [Interpolation::None, Interpolation::Linear, Interpolation::Hermite, Interpolation::Lagrange, Interpolation::Sinc];
+
+    /// Works out anything the method needs ahead of time, so that reading never allocates.
+    pub fn prepare(self) {
+        if self == Interpolation::Sinc {
+            sinc_kernel();
+        }
+    }
+
+    /// Reads `samples`, one cycle of a wave, at `index` from 0 up to its length. `wrap` brings
+    /// indices past the end, up to a cycle and a few entries, back into the cycle.
+    pub fn read(self, samples: &[f32], index: f32, wrap: impl Fn(usize) -> usize) -> f32 {
+        let len = samples.len();
+        let position = index as usize;
+        let t = index - position as f32;
+        // the entries before `position` are read one cycle later, so the index never goes negative
+        let at = |offset: usize| samples[wrap(position + len + offset - 1)];
+        match self {
+            Interpolation::None => samples[position],
+            Interpolation::Linear => (1.0 - t) * samples[position] + t * samples[wrap(position + 1)],
+            Interpolation::Hermite => {
+                let (before, x0, x1, after) = (at(0), at(1), at(2), at(3));
+                let c1 = 0.5 * (x1 - before);
+                let c2 = before - 2.5 * x0 + 2.0 * x1 - 0.5 * after;
+                let c3 = 0.5 * (after - before) + 1.5 * (x0 - x1);
+                ((c3 * t + c2) * t + c1) * t + x0
+            }
+            Interpolation::Lagrange => {
+                let (before, x0, x1, after) = (at(0), at(1), at(2), at(3));
+                let (d0, d1, d2, d3) = (t + 1.0, t, t - 1.0, t - 2.0);
+                -before * d1 * d2 * d3 / 6.0 + x0 * d0 * d2 * d3 / 2.0 - x1 * d0 * d1 * d3 / 2.0 + after * d0 * d1 * d2 / 6.0
+            }
+            Interpolation::Sinc => {
+                let kernel = sinc_kernel();
+                let scaled = t * SINC_PHASES as f32;
+                let phase = (scaled as usize).min(SINC_PHASES - 1);
+                let mix = scaled - phase as f32;
+                let (weights, next) = (&kernel[phase * SINC_TAPS..], &kernel[(phase + 1) * SINC_TAPS..]);
+                let first = position + len + 1 - SINC_TAPS / 2;
+                (0..SINC_TAPS)
+                    .map(|tap| (weights[tap] + mix * (next[tap] - weights[tap])) * samples[wrap(first + tap)])
+                    .sum()
+            }
+        }
+    }
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "truncate" => Ok(Interpolation::None),
+            "linear" => Ok(Interpolation::Linear),
+            "hermite" | "cubic" => Ok(Interpolation::Hermite),
+            "lagrange" => Ok(Interpolation::Lagrange),
+            "sinc" => Ok(Interpolation::Sinc),
+            _ => Err(format!("unknown interpolation '{}' (expected none, linear, hermite, lagrange or sinc)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interpolation::None => write!(f, "none"),
+            Interpolation::Linear => write!(f, "linear"),
+            Interpolation::Hermite => write!(f, "hermite"),
+            Interpolation::Lagrange => write!(f, "lagrange"),
+            Interpolation::Sinc => write!(f, "sinc"),
+        }
+    }
+}
+
+/// `SINC_TAPS` weights for each of `SINC_PHASES + 1` points from one entry to the next, each set
+/// adding up to 1 so a constant table reads back unchanged.
+fn sinc_kernel() -> &'static [f32] {
+    static KERNEL: OnceLock<Vec<f32>> = OnceLock::new();
+    KERNEL.get_or_init(|| {
+        let half_width = (SINC_TAPS / 2) as f32;
+        let mut kernel = Vec::with_capacity((SINC_PHASES + 1) * SINC_TAPS);
+        for phase in 0..=SINC_PHASES {
+            let t = phase as f32 / SINC_PHASES as f32;
+            let weights: Vec<f32> = (0..SINC_TAPS)
+                .map(|tap| {
+                    // distance from the index to the entry this tap reads
+                    let x = (tap as f32 + 1.0 - half_width) - t;
+                    let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                    let window = 0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2.0 * PI * x / half_width).cos();
+                    sinc * window
+                })
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            kernel.extend(weights.iter().map(|weight| weight / sum));
+        }
+        kernel
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(len: usize) -> impl Fn(usize) -> usize {
+        move |index| index % len
+    }
+
+    #[test]
+    fn every_method_hits_the_entries_exactly() {
+        let samples = [0.1, 0.9, -0.4, 0.3, -0.8, 0.5];
+        for method in Interpolation::ALL {
+            for (index, sample) in samples.iter().enumerate() {
+                let read = method.read(&samples, index as f32, wrap(samples.len()));
+                assert!((read - sample).abs() < 1e-5, "{} at {}: {}", method, index, read);
+            }
+        }
+    }
+
+    #[test]
+    fn cubics_follow_polynomials_and_wrap_around_the_cycle() {
+        // Lagrange reproduces any cubic, Hermite's slopes are exact for a quadratic
+        let cubic = |x: f32| 0.05 * x * x * x - 0.3 * x * x + 0.2 * x;
+        let quadratic = |x: f32| -0.1 * x * x + 0.7 * x - 0.2;
+        for (method, curve) in [(Interpolation::Lagrange, &cubic as &dyn Fn(f32) -> f32), (Interpolation::Hermite, &quadratic)] {
+            let samples: Vec<f32> = (0..8).map(|x| curve(x as f32)).collect();
+            let read = method.read(&samples, 3.25, wrap(8));
+            assert!((read - curve(3.25)).abs() < 1e-5, "{}: {} vs {}", method, read, curve(3.25));
+            let linear = Interpolation::Linear.read(&samples, 3.25, wrap(8));
+            assert!((linear - (0.75 * samples[3] + 0.25 * samples[4])).abs() < 1e-6);
+        }
+
+        // between the last entry and the first, with the entries before and after from the other end
+        let ramp = [0.0, 1.0, 2.0, 3.0];
+        assert_eq!(Interpolation::None.read(&ramp, 3.5, wrap(4)), 3.0);
+        assert!((Interpolation::Linear.read(&ramp, 3.5, wrap(4)) - 1.5).abs() < 1e-6);
+        let lagrange = Interpolation::Lagrange.read(&ramp, 3.5, wrap(4));
+        let expected = 2.0 * -0.0625 + 3.0 * 0.5625 + 0.0 * 0.5625 + 1.0 * -0.0625;
+        assert!((lagrange - expected).abs() < 1e-6, "{}", lagrange);
+    }
+
+    #[test]
+    fn sinc_keeps_a_constant_table_constant() {
+        let samples = [0.5; 16];
+        for index in [0.0, 0.3, 7.77, 15.99] {
+            assert!((Interpolation::Sinc.read(&samples, index, wrap(16)) - 0.5).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn parses_method_names() {
+        assert_eq!("truncate".parse::<Interpolation>(), Ok(Interpolation::None));
+        assert_eq!("Cubic".parse::<Interpolation>(), Ok(Interpolation::Hermite));
+        for method in Interpolation::ALL {
+            assert_eq!(method.to_string().parse::<Interpolation>(), Ok(method));
+        }
+        assert!("quadratic".parse::<Interpolation>().is_err());
+        assert_eq!(Interpolation::default(), Interpolation::Linear);
+    }
+}
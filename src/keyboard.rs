@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use device_query::Keycode;
+
+use crate::tuning::{note_number, MIDDLE_C};
+
+/*
+    Playing from a computer keyboard the way trackers and DAWs do: the home row holds the white keys
+    and the row above it the black keys, so `a w s e d f t g y h u j k` is one octave from C to C,
+    continuing with `o l p ; '` up to the F above. Separate keys shift the whole keyboard by an
+    octave (`z`/`x`) or by a semitone (`c`/`v`).
+
+    A layout can also be read from a text file with one `<key> <value>` pair per line:
+
+        # note keys: semitones above the base note
+        a 0
+        w 1
+        # control keys
+        octave-down z
+        octave-up x
+        transpose-down c
+        transpose-up v
+        # the note played by offset 0, as a name or MIDI number
+        base C4
+
+    Lines starting with '#' are comments.
+
+    Terminals normally only report key presses, repeating them while a key is held, so holding a
+    note needs to know when the key comes back up. `KeyGate` keeps track of which keys are down and
+    takes releases from whatever the platform offers: release events from terminals that speak the
+    kitty keyboard protocol, the key state polled from the X server through `device_query`, or, as
+    a last resort, auto-repeat. A held key repeats every few tens of milliseconds after an initial
+    delay of about half a second, so a key that has stopped repeating for longer than that has been
+    let go.
+ */
+
+#[derive(Debug)]
+pub enum LayoutError {
+    Io(io::Error),
+    /// A line that could not be read, with its 1-based line number.
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Io(err) => write!(f, "{}", err),
+            LayoutError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+impl From<io::Error> for LayoutError {
+    fn from(err: io::Error) -> Self {
+        LayoutError::Io(err)
+    }
+}
+
+/// Which computer key plays which note and which keys shift the keyboard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardLayout {
+    /// Semitones above `base_note` for every note key.
+    pub notes: HashMap<char, i32>,
+    pub base_note: u8,
+    pub octave_down: char,
+    pub octave_up: char,
+    pub transpose_down: char,
+    pub transpose_up: char,
+}
+
+impl Default for KeyboardLayout {
+    fn default() -> Self {
+        let notes = "awsedftgyhujkolp;'"
+            .chars()
+            .enumerate()
+            .map(|(offset, key)| (key, offset as i32))
+            .collect();
+        KeyboardLayout {
+            notes,
+            base_note: MIDDLE_C,
+            octave_down: 'z',
+            octave_up: 'x',
+            transpose_down: 'c',
+            transpose_up: 'v',
+        }
+    }
+}
+
+impl KeyboardLayout {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KeyboardLayout, LayoutError> {
+        KeyboardLayout::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Reads a layout file. Control keys and the base note not given in the file keep their
+    /// defaults; note keys come only from the file.
+    pub fn parse(text: &str) -> Result<KeyboardLayout, LayoutError> {
+        let mut layout = KeyboardLayout { notes: HashMap::new(), ..KeyboardLayout::default() };
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: String| LayoutError::Parse { line: line_number, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (Some(name), Some(value), None) = (words.next(), words.next(), words.next()) else {
+                return Err(error(format!("expected '<key> <value>', found '{}'", line)));
+            };
+            let single_key = |value: &str| {
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(key), None) => Ok(key),
+                    _ => Err(error(format!("'{}' is not a single key", value))),
+                }
+            };
+            match name {
+                "octave-down" => layout.octave_down = single_key(value)?,
+                "octave-up" => layout.octave_up = single_key(value)?,
+                "transpose-down" => layout.transpose_down = single_key(value)?,
+                "transpose-up" => layout.transpose_up = single_key(value)?,
+                "base" => {
+                    layout.base_note = value
+                        .parse()
+                        .ok()
+                        .or_else(|| note_number(value))
+                        .filter(|note| *note <= 127)
+                        .ok_or_else(|| error(format!("'{}' is not a note", value)))?;
+                }
+                key => {
+                    let key = single_key(key)?;
+                    let offset = value
+                        .parse()
+                        .map_err(|_| error(format!("'{}' is not a semitone offset", value)))?;
+                    layout.notes.insert(key, offset);
+                }
+            }
+        }
+        Ok(layout)
+    }
+}
+
+/// What pressing a key did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAction {
+    Note(u8),
+    /// The octave shift changed; holds the new shift in octaves.
+    Octave(i32),
+    /// The transposition changed; holds the new shift in semitones.
+    Transpose(i32),
+}
+
+/// A [`KeyboardLayout`] together with the current octave and transpose shifts.
+#[derive(Clone, Debug)]
+pub struct ComputerKeyboard {
+    layout: KeyboardLayout,
+    octave: i32,
+    transpose: i32,
+}
+
+const MAX_OCTAVE_SHIFT: i32 = 5;
+const MAX_TRANSPOSE: i32 = 11;
+
+impl ComputerKeyboard {
+    pub fn new(layout: KeyboardLayout) -> ComputerKeyboard {
+        ComputerKeyboard { layout, octave: 0, transpose: 0 }
+    }
+
+    pub fn layout(&self) -> &KeyboardLayout {
+        &self.layout
+    }
+
+    pub fn octave(&self) -> i32 {
+        self.octave
+    }
+
+    pub fn transpose(&self) -> i32 {
+        self.transpose
+    }
+
+    /// Note played by `key` with the current shifts, without changing any state.
+    pub fn note(&self, key: char) -> Option<u8> {
+        let offset = self.layout.notes.get(&key.to_ascii_lowercase())?;
+        let note = self.layout.base_note as i32 + 12 * self.octave + self.transpose + offset;
+        u8::try_from(note).ok().filter(|note| *note <= 127)
+    }
+
+    /// Handles a key press. Returns `None` for keys the layout does not use.
+    pub fn press(&mut self, key: char) -> Option<KeyAction> {
+        let key = key.to_ascii_lowercase();
+        if key == self.layout.octave_down {
+            self.octave = (self.octave - 1).max(-MAX_OCTAVE_SHIFT);
+            Some(KeyAction::Octave(self.octave))
+        } else if key == self.layout.octave_up {
+            self.octave = (self.octave + 1).min(MAX_OCTAVE_SHIFT);
+            Some(KeyAction::Octave(self.octave))
+        } else if key == self.layout.transpose_down {
+            self.transpose = (self.transpose - 1).max(-MAX_TRANSPOSE);
+            Some(KeyAction::Transpose(self.transpose))
+        } else if key == self.layout.transpose_up {
+            self.transpose = (self.transpose + 1).min(MAX_TRANSPOSE);
+            Some(KeyAction::Transpose(self.transpose))
+        } else {
+            self.note(key).map(KeyAction::Note)
+        }
+    }
+}
+
+impl Default for ComputerKeyboard {
+    fn default() -> Self {
+        ComputerKeyboard::new(KeyboardLayout::default())
+    }
+}
+
+/// Time a key counts as held after its first press when no release can be seen; a little longer
+/// than the usual auto-repeat delay.
+pub const DEFAULT_REPEAT_DELAY: Duration = Duration::from_millis(600);
+
+/// Time a key counts as held after each repeat; a few auto-repeat intervals.
+pub const DEFAULT_REPEAT_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug)]
+struct HeldKey {
+    last_press: Instant,
+    repeating: bool,
+    /// The key was seen down in a polled key state, so the poll can tell when it is released.
+    seen: bool,
+}
+
+/// Which keys are held down, turning repeated presses into a single key-down and finding the
+/// matching key-up.
+#[derive(Clone, Debug)]
+pub struct KeyGate {
+    held: HashMap<char, HeldKey>,
+    repeat_delay: Duration,
+    repeat_timeout: Duration,
+}
+
+impl KeyGate {
+    pub fn new(repeat_delay: Duration, repeat_timeout: Duration) -> KeyGate {
+        KeyGate { held: HashMap::new(), repeat_delay, repeat_timeout }
+    }
+
+    /// Records a press of `key`. Returns true if the key went down, false if it was already held
+    /// and this is an auto-repeat.
+    pub fn press(&mut self, key: char, now: Instant) -> bool {
+        let key = key.to_ascii_lowercase();
+        match self.held.get_mut(&key) {
+            Some(held) => {
+                held.last_press = now;
+                held.repeating = true;
+                false
+            }
+            None => {
+                self.held.insert(key, HeldKey { last_press: now, repeating: false, seen: false });
+                true
+            }
+        }
+    }
+
+    /// Records a release of `key`. Returns true if the key was held.
+    pub fn release(&mut self, key: char) -> bool {
+        self.held.remove(&key.to_ascii_lowercase()).is_some()
+    }
+
+    pub fn is_held(&self, key: char) -> bool {
+        self.held.contains_key(&key.to_ascii_lowercase())
+    }
+
+    /// Compares the held keys with the keys a poll found down and releases those that came up.
+    /// Keys the poll has never seen down are left alone, since the poll may not see this terminal's
+    /// keys at all.
+    pub fn sync(&mut self, down: &[char]) -> Vec<char> {
+        let mut released = Vec::new();
+        for (key, held) in self.held.iter_mut() {
+            if down.contains(key) {
+                held.seen = true;
+            } else if held.seen {
+                released.push(*key);
+            }
+        }
+        for key in &released {
+            self.held.remove(key);
+        }
+        released
+    }
+
+    /// Releases the keys whose auto-repeat has stopped by `now`.
+    pub fn expire(&mut self, now: Instant) -> Vec<char> {
+        let (repeat_delay, repeat_timeout) = (self.repeat_delay, self.repeat_timeout);
+        let released: Vec<char> = self
+            .held
+            .iter()
+            .filter(|(_, held)| {
+                let timeout = if held.repeating { repeat_timeout } else { repeat_delay };
+                !held.seen && now.duration_since(held.last_press) > timeout
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &released {
+            self.held.remove(key);
+        }
+        released
+    }
+
+    /// Forgets every held key, returning them.
+    pub fn release_all(&mut self) -> Vec<char> {
+        self.held.drain().map(|(key, _)| key).collect()
+    }
+}
+
+impl Default for KeyGate {
+    fn default() -> Self {
+        KeyGate::new(DEFAULT_REPEAT_DELAY, DEFAULT_REPEAT_TIMEOUT)
+    }
+}
+
+/// The character a physical key types on a US keyboard without shift, for the keys a layout can
+/// use.
+pub fn keycode_char(keycode: Keycode) -> Option<char> {
+    let key = match keycode {
+        Keycode::Key0 => '0',
+        Keycode::Key1 => '1',
+        Keycode::Key2 => '2',
+        Keycode::Key3 => '3',
+        Keycode::Key4 => '4',
+        Keycode::Key5 => '5',
+        Keycode::Key6 => '6',
+        Keycode::Key7 => '7',
+        Keycode::Key8 => '8',
+        Keycode::Key9 => '9',
+        Keycode::Space => ' ',
+        Keycode::Grave => '`',
+        Keycode::Minus => '-',
+        Keycode::Equal => '=',
+        Keycode::LeftBracket => '[',
+        Keycode::RightBracket => ']',
+        Keycode::BackSlash => '\\',
+        Keycode::Semicolon => ';',
+        Keycode::Apostrophe => '\'',
+        Keycode::Comma => ',',
+        Keycode::Dot => '.',
+        Keycode::Slash => '/',
+        letter => {
+            // letters are named by their single uppercase character
+            let name = letter.to_string();
+            let mut chars = name.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_uppercase() => Some(c.to_ascii_lowercase()),
+                _ => None,
+            };
+        }
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_layout_is_a_piano_from_middle_c() {
+        let keyboard = ComputerKeyboard::default();
+        let notes: Vec<u8> = "awsedftgyhujk".chars().map(|key| keyboard.note(key).unwrap()).collect();
+        assert_eq!(notes, (60..=72).collect::<Vec<u8>>());
+        assert_eq!(keyboard.note('\''), Some(77));
+        assert_eq!(keyboard.note('A'), Some(60));
+        assert_eq!(keyboard.note('b'), None);
+    }
+
+    #[test]
+    fn shifts_by_octave_and_semitone() {
+        let mut keyboard = ComputerKeyboard::default();
+        assert_eq!(keyboard.press('x'), Some(KeyAction::Octave(1)));
+        assert_eq!(keyboard.press('h'), Some(KeyAction::Note(81)));
+        assert_eq!(keyboard.press('z'), Some(KeyAction::Octave(0)));
+        assert_eq!(keyboard.press('z'), Some(KeyAction::Octave(-1)));
+        assert_eq!(keyboard.press('v'), Some(KeyAction::Transpose(1)));
+        assert_eq!(keyboard.press('a'), Some(KeyAction::Note(49)));
+        assert_eq!(keyboard.press('c'), Some(KeyAction::Transpose(0)));
+        assert_eq!(keyboard.press('1'), None);
+    }
+
+    #[test]
+    fn shifts_are_bounded() {
+        let mut keyboard = ComputerKeyboard::default();
+        for _ in 0..20 {
+            keyboard.press('z');
+            keyboard.press('c');
+        }
+        assert_eq!(keyboard.octave(), -MAX_OCTAVE_SHIFT);
+        assert_eq!(keyboard.transpose(), -MAX_TRANSPOSE);
+        // C4 - 5 octaves - 11 semitones is below note 0.
+        assert_eq!(keyboard.note('a'), None);
+        assert_eq!(keyboard.note('\''), Some(6));
+    }
+
+    #[test]
+    fn reads_layout_files() {
+        let text = "# two keys a fifth apart\nq 0\nw 7\n\noctave-down 1\noctave-up 2\nbase A3\n";
+        let layout = KeyboardLayout::parse(text).unwrap();
+        assert_eq!(layout.notes.len(), 2);
+        assert_eq!(layout.base_note, 57);
+        assert_eq!(layout.transpose_up, 'v');
+
+        let mut keyboard = ComputerKeyboard::new(layout);
+        assert_eq!(keyboard.press('w'), Some(KeyAction::Note(64)));
+        assert_eq!(keyboard.press('2'), Some(KeyAction::Octave(1)));
+        assert_eq!(keyboard.press('q'), Some(KeyAction::Note(69)));
+        assert_eq!(keyboard.press('a'), None);
+    }
+
+    #[test]
+    fn rejects_bad_layout_lines() {
+        assert!(matches!(KeyboardLayout::parse("a\n"), Err(LayoutError::Parse { line: 1, .. })));
+        assert!(matches!(KeyboardLayout::parse("a 0\nab 1\n"), Err(LayoutError::Parse { line: 2, .. })));
+        assert!(matches!(KeyboardLayout::parse("a up\n"), Err(LayoutError::Parse { .. })));
+        assert!(matches!(KeyboardLayout::parse("base H4\n"), Err(LayoutError::Parse { .. })));
+        assert!(matches!(KeyboardLayout::parse("octave-up xy\n"), Err(LayoutError::Parse { .. })));
+    }
+
+    #[test]
+    fn repeats_do_not_press_again() {
+        let start = Instant::now();
+        let mut gate = KeyGate::default();
+        assert!(gate.press('a', start));
+        assert!(!gate.press('a', start + Duration::from_millis(500)));
+        assert!(!gate.press('A', start + Duration::from_millis(530)));
+        assert!(gate.is_held('a'));
+        assert!(gate.release('a'));
+        assert!(!gate.release('a'));
+        assert!(gate.press('a', start + Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn keys_that_stop_repeating_are_released() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut gate = KeyGate::default();
+        gate.press('a', at(0));
+        gate.press('s', at(0));
+        // no repeat yet, but still inside the initial delay
+        assert!(gate.expire(at(400)).is_empty());
+        gate.press('a', at(500));
+        gate.press('a', at(530));
+        // 's' never repeated, 'a' did until 530 ms
+        assert_eq!(gate.expire(at(610)), vec!['s']);
+        assert!(gate.expire(at(620)).is_empty());
+        assert_eq!(gate.expire(at(640)), vec!['a']);
+        assert!(!gate.is_held('a'));
+    }
+
+    #[test]
+    fn polled_state_releases_keys_it_has_seen() {
+        let start = Instant::now();
+        let mut gate = KeyGate::default();
+        gate.press('a', start);
+        gate.press('s', start);
+        assert!(gate.sync(&['a']).is_empty());
+        // 's' was never seen by the poll, so the poll cannot release it
+        assert_eq!(gate.sync(&[]), vec!['a']);
+        assert!(gate.is_held('s'));
+        // a key the poll has seen is not timed out while it is held
+        gate.press('d', start);
+        gate.sync(&['d']);
+        assert_eq!(gate.expire(start + Duration::from_secs(5)), vec!['s']);
+        assert!(gate.is_held('d'));
+    }
+
+    #[test]
+    fn maps_physical_keys_to_characters() {
+        assert_eq!(keycode_char(Keycode::A), Some('a'));
+        assert_eq!(keycode_char(Keycode::Semicolon), Some(';'));
+        assert_eq!(keycode_char(Keycode::Apostrophe), Some('\''));
+        assert_eq!(keycode_char(Keycode::Key7), Some('7'));
+        assert_eq!(keycode_char(Keycode::F1), None);
+        assert_eq!(keycode_char(Keycode::LShift), None);
+    }
+}
@@ -0,0 +1,326 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::mipmap::MipMappedTable;
+use crate::oscillator::WavetableOscillator;
+use crate::score::parse_length;
+use crate::wavetable::{WaveTable, Waveform};
+
+/*
+    A low-frequency oscillator is a `WavetableOscillator` run far below the audible range, so any
+    of the built-in shapes, or the voices' own wave table, can sweep a parameter. Each voice has
+    its own LFOs, restarted at their start phase on every note, and each can fade in over a given
+    time from the note's start.
+
+    The rate is either in Hz or tied to the tempo as a note value, e.g. `1/4` for one cycle per
+    beat or `1/8.` for one per dotted eighth. The voices pass on every tempo change they are sent,
+    so synced LFOs keep time with a score or MIDI file as it plays.
+
+    The basic shapes are drawn straight rather than built from harmonics like the audio tables.
+    Nothing an LFO moves can alias, and a band-limited square would ripple on its plateaus and
+    overshoot its edges, so a square routed to pitch would wobble instead of trilling cleanly.
+ */
+
+/// Samples in the tables built for the basic shapes; plenty at LFO rates.
+const LFO_TABLE_SIZE: usize = 256;
+
+/// Tempo synced rates assume this many beats per minute until told otherwise.
+pub const DEFAULT_TEMPO: f32 = 120.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LfoShape {
+    Wave(Waveform),
+    /// The wave table the voices play, following it when it is swapped.
+    Table,
+}
+
+impl FromStr for LfoShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" | "wavetable" => Ok(LfoShape::Table),
+            _ => s.parse().map(LfoShape::Wave).map_err(|_| {
+                format!("unknown LFO shape '{}' (expected sine, saw, square, triangle, pulse or table)", s)
+            }),
+        }
+    }
+}
+
+impl fmt::Display for LfoShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LfoShape::Wave(waveform) => write!(f, "{}", waveform),
+            LfoShape::Table => write!(f, "table"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfoRate {
+    Hertz(f32),
+    /// One cycle every this many quarter notes at the current tempo.
+    Beats(f32),
+}
+
+impl LfoRate {
+    /// Cycles per second at `tempo` beats per minute.
+    pub fn frequency(self, tempo: f32) -> f32 {
+        match self {
+            LfoRate::Hertz(frequency) => frequency,
+            LfoRate::Beats(beats) => tempo / 60.0 / beats,
+        }
+    }
+}
+
+impl FromStr for LfoRate {
+    type Err = String;
+
+    /// Reads a rate in Hz (`5`, `0.5hz`) or a note value (`1/4`, `1/8.`, `3/16`, `2/1`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = match s.split_once('/') {
+            Some((count, value)) => {
+                let count: f32 = count.parse().ok().filter(|count: &f32| *count > 0.0).ok_or_else(|| format!("bad note count in '{}'", s))?;
+                let beats = parse_length(value).ok_or_else(|| format!("bad note value in '{}'", s))?;
+                LfoRate::Beats(count * beats as f32)
+            }
+            None => {
+                let number = s.to_ascii_lowercase();
+                let number = number.strip_suffix("hz").unwrap_or(&number);
+                let frequency: f32 = number.parse().map_err(|_| format!("'{}' is not a rate", s))?;
+                if !(frequency >= 0.0 && frequency.is_finite()) {
+                    return Err("rate must be zero or more Hz".to_string());
+                }
+                LfoRate::Hertz(frequency)
+            }
+        };
+        Ok(rate)
+    }
+}
+
+/// LFO settings. `phase` is where each note starts in the cycle, from 0 to 1, and `fade_in` the
+/// seconds the LFO takes to reach full depth after a note starts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub rate: LfoRate,
+    pub phase: f32,
+    pub fade_in: f32,
+}
+
+impl LfoSettings {
+    pub fn new(shape: LfoShape, rate: LfoRate, phase: f32, fade_in: f32) -> LfoSettings {
+        LfoSettings { shape, rate, phase: phase.rem_euclid(1.0), fade_in: fade_in.max(0.0) }
+    }
+}
+
+impl Default for LfoSettings {
+    fn default() -> Self {
+        LfoSettings::new(LfoShape::Wave(Waveform::Sine), LfoRate::Hertz(5.0), 0.0, 0.0)
+    }
+}
+
+impl FromStr for LfoSettings {
+    type Err = String;
+
+    /// Reads `shape:rate[:phase[:fade-in]]`, e.g. `sine:5`, `triangle:1/4:0.25` or `table:2:0:1.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let shape = parts.next().unwrap_or_default().parse()?;
+        let rate = parts.next().ok_or_else(|| format!("'{}' has no rate (expected shape:rate[:phase[:fade-in]])", s))?.parse()?;
+        let number = |part: Option<&str>, name: &str| -> Result<f32, String> {
+            match part {
+                Some(part) => part.parse().ok().filter(|value: &f32| *value >= 0.0).ok_or_else(|| format!("bad {} '{}'", name, part)),
+                None => Ok(0.0),
+            }
+        };
+        let phase = number(parts.next(), "phase")?;
+        let fade_in = number(parts.next(), "fade-in")?;
+        if parts.next().is_some() {
+            return Err(format!("too many fields in '{}'", s));
+        }
+        Ok(LfoSettings::new(shape, rate, phase, fade_in))
+    }
+}
+
+/// A running LFO, giving values from -1 to 1.
+pub struct Lfo {
+    settings: LfoSettings,
+    oscillator: WavetableOscillator,
+    fade: f32,
+    fade_step: f32,
+}
+
+impl Lfo {
+    /// `wave_table` is read for [`LfoShape::Table`]; other shapes build their own.
+    pub fn new(sample_rate: u32, settings: LfoSettings, tempo: f32, wave_table: &Arc<MipMappedTable>) -> Lfo {
+        let table = match settings.shape {
+            LfoShape::Wave(waveform) => Arc::new(MipMappedTable::single(lfo_table(waveform, LFO_TABLE_SIZE).into())),
+            LfoShape::Table => wave_table.clone(),
+        };
+        let mut oscillator = WavetableOscillator::with_mipmaps(sample_rate, table);
+        oscillator.set_frequency(settings.rate.frequency(tempo));
+        oscillator.set_phase(settings.phase);
+        let fade_step = if settings.fade_in > 0.0 { 1.0 / (settings.fade_in * sample_rate as f32) } else { 1.0 };
+        Lfo { settings, oscillator, fade: 1.0, fade_step }
+    }
+
+    pub fn settings(&self) -> LfoSettings {
+        self.settings
+    }
+
+    /// Restarts the cycle at the start phase and the fade from silence.
+    pub fn trigger(&mut self) {
+        self.oscillator.set_phase(self.settings.phase);
+        self.fade = if self.settings.fade_in > 0.0 { 0.0 } else { 1.0 };
+    }
+
+    /// Follows a new tempo; rates in Hz stay as they are.
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.oscillator.set_frequency(self.settings.rate.frequency(tempo));
+    }
+
+    /// Follows a swap of the voices' table when this LFO reads it.
+    pub fn set_wave_table(&mut self, wave_table: &Arc<MipMappedTable>) {
+        if self.settings.shape == LfoShape::Table {
+            self.oscillator.set_wave_table(wave_table.clone());
+        }
+    }
+
+    pub fn next_value(&mut self) -> f32 {
+        let value = self.oscillator.get_sample() * self.fade;
+        self.fade = (self.fade + self.fade_step).min(1.0);
+        value
+    }
+}
+
+/// One cycle of `waveform` from -1 to 1 with sharp corners, in phase with the audio tables of
+/// the same shape. The pulse is half a cycle wide, like the one the voices play by default.
+fn lfo_table(waveform: Waveform, size: usize) -> WaveTable {
+    if waveform == Waveform::Sine {
+        return WaveTable::sine(size);
+    }
+    let samples = (0..size)
+        .map(|i| {
+            let t = i as f32 / size as f32;
+            match waveform {
+                Waveform::Saw => 2.0 * t - 1.0,
+                Waveform::Square => if t < 0.5 { 1.0 } else { -1.0 },
+                Waveform::Triangle => 1.0 - 4.0 * ((t + 0.25).fract() - 0.5).abs(),
+                Waveform::Pulse => if (t + 0.25).fract() < 0.5 { 1.0 } else { -1.0 },
+                Waveform::Sine => unreachable!(),
+            }
+        })
+        .collect();
+    WaveTable::from_samples(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine_table() -> Arc<MipMappedTable> {
+        Arc::new(MipMappedTable::from(WaveTable::sine(64)))
+    }
+
+    #[test]
+    fn parses_rates_in_hertz_and_note_values() {
+        assert_eq!("5".parse(), Ok(LfoRate::Hertz(5.0)));
+        assert_eq!("0.5Hz".parse(), Ok(LfoRate::Hertz(0.5)));
+        assert_eq!("1/4".parse(), Ok(LfoRate::Beats(1.0)));
+        assert_eq!("1/8.".parse(), Ok(LfoRate::Beats(0.75)));
+        assert_eq!("2/1".parse(), Ok(LfoRate::Beats(8.0)));
+        assert!("1/3".parse::<LfoRate>().is_err());
+        assert!("fast".parse::<LfoRate>().is_err());
+        assert!("-1".parse::<LfoRate>().is_err());
+        // a quarter note at 120 bpm is half a second
+        assert_eq!(LfoRate::Beats(1.0).frequency(120.0), 2.0);
+        assert_eq!(LfoRate::Beats(0.5).frequency(90.0), 3.0);
+    }
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!("sine:5".parse(), Ok(LfoSettings::new(LfoShape::Wave(Waveform::Sine), LfoRate::Hertz(5.0), 0.0, 0.0)));
+        assert_eq!(
+            "table:1/4:0.25:1.5".parse(),
+            Ok(LfoSettings::new(LfoShape::Table, LfoRate::Beats(1.0), 0.25, 1.5))
+        );
+        assert!("sine".parse::<LfoSettings>().is_err());
+        assert!("noise:5".parse::<LfoSettings>().is_err());
+        assert!("sine:5:0:1:2".parse::<LfoSettings>().is_err());
+    }
+
+    #[test]
+    fn starts_each_note_at_its_phase() {
+        // a quarter of the way into a sine is its peak
+        let settings = LfoSettings::new(LfoShape::Wave(Waveform::Sine), LfoRate::Hertz(1.0), 0.25, 0.0);
+        let mut lfo = Lfo::new(1000, settings, DEFAULT_TEMPO, &sine_table());
+        lfo.trigger();
+        assert!((lfo.next_value() - 1.0).abs() < 1e-3);
+        // 500 samples later, half a cycle on, it is at the bottom
+        let values: Vec<f32> = (0..500).map(|_| lfo.next_value()).collect();
+        assert!((values[499] + 1.0).abs() < 1e-3, "{}", values[499]);
+        lfo.trigger();
+        assert!((lfo.next_value() - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn follows_the_tempo() {
+        // at 120 bpm a 1/4 rate is 2 Hz: from the peak to the trough in 250 samples at 1 kHz
+        let settings = LfoSettings::new(LfoShape::Wave(Waveform::Sine), LfoRate::Beats(1.0), 0.25, 0.0);
+        let mut lfo = Lfo::new(1000, settings, 120.0, &sine_table());
+        let values: Vec<f32> = (0..1001).map(|_| lfo.next_value()).collect();
+        assert!((values[250] + 1.0).abs() < 1e-3 && (values[500] - 1.0).abs() < 1e-3);
+
+        lfo.set_tempo(60.0);
+        lfo.trigger();
+        let values: Vec<f32> = (0..1001).map(|_| lfo.next_value()).collect();
+        assert!((values[500] + 1.0).abs() < 1e-3 && (values[1000] - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fades_in_after_the_note_starts() {
+        // held at the peak of a sine, the LFO's value is the fade itself
+        let settings = LfoSettings::new(LfoShape::Wave(Waveform::Sine), LfoRate::Hertz(0.0), 0.25, 0.5);
+        let mut lfo = Lfo::new(1000, settings, DEFAULT_TEMPO, &sine_table());
+        lfo.trigger();
+        let values: Vec<f32> = (0..1000).map(|_| lfo.next_value()).collect();
+        assert_eq!(values[0], 0.0);
+        assert!((values[250] - 0.5).abs() < 0.01, "{}", values[250]);
+        assert!(values[600..].iter().all(|value| (value - 1.0).abs() < 1e-3));
+    }
+
+    #[test]
+    fn square_holds_full_depth_across_each_half_cycle() {
+        // 1 Hz at 1 kHz: half a cycle is 500 samples, give or take the step through each edge
+        let settings = LfoSettings::new(LfoShape::Wave(Waveform::Square), LfoRate::Hertz(1.0), 0.0, 0.0);
+        let mut lfo = Lfo::new(1000, settings, DEFAULT_TEMPO, &sine_table());
+        for _ in 0..3 {
+            let values: Vec<f32> = (0..1000).map(|_| lfo.next_value()).collect();
+            assert!(values[4..496].iter().all(|value| (value - 1.0).abs() < 1e-6));
+            assert!(values[504..996].iter().all(|value| (value + 1.0).abs() < 1e-6));
+        }
+
+        // the other shapes reach both ends too, and in phase with the audio tables
+        for waveform in [Waveform::Saw, Waveform::Triangle, Waveform::Pulse] {
+            let table = lfo_table(waveform, LFO_TABLE_SIZE);
+            let audio = waveform.table(LFO_TABLE_SIZE, 0.5);
+            let (low, high) = table.samples().iter().fold((0.0f32, 0.0f32), |(low, high), s| (low.min(*s), high.max(*s)));
+            assert!(low == -1.0 && high > 0.99, "{}", waveform);
+            let dot = |a: &WaveTable, b: &WaveTable| a.samples().iter().zip(b.samples()).map(|(a, b)| a * b).sum::<f32>();
+            let correlation = dot(&table, &audio) / (dot(&table, &table) * dot(&audio, &audio)).sqrt();
+            assert!(correlation > 0.95, "{}: {}", waveform, correlation);
+        }
+    }
+
+    #[test]
+    fn reads_the_voices_table() {
+        let settings = LfoSettings::new(LfoShape::Table, LfoRate::Hertz(1.0), 0.25, 0.0);
+        let mut lfo = Lfo::new(1000, settings, DEFAULT_TEMPO, &sine_table());
+        assert!((lfo.next_value() - 1.0).abs() < 1e-3);
+        // swapping in a silent table silences the LFO
+        lfo.set_wave_table(&Arc::new(MipMappedTable::from(WaveTable::from_samples(vec![0.0; 64]))));
+        assert_eq!(lfo.next_value(), 0.0);
+    }
+}
@@ -0,0 +1,51 @@
+//! A small wavetable synthesizer.
+//!
+//! The library holds everything needed to turn a wave table into audio:
+//!
+//! - [`wavetable`] generates single- and multi-frame tables, [`wav`] imports them from WAV files
+//!   and [`mipmap`] band limits them per octave so high notes do not alias.
+//! - [`oscillator::WavetableOscillator`] scans a table at a given frequency, reading between its
+//!   entries with one of the methods in [`interpolation`].
+//! - [`voice`] plays notes on a fixed pool of [`unison`] stacks of oscillators, shaped by the
+//!   ADSR [`envelope`] and an optional resonant [`filter`], and modulated by [`lfo`]s and other
+//!   sources through the matrix in [`modulation`].
+//! - [`engine`] runs the voices on the audio thread for live play, controlled through the
+//!   lock-free queues in [`spsc`].
+//! - [`tuning`] turns note numbers into frequencies, including Scala tunings read by [`scala`].
+//! - [`keyboard`] maps computer keys to notes for interactive play, and [`midi`] turns MIDI
+//!   messages into voice events, read from the ALSA sequencer by `midi_input` on Linux.
+//! - [`source`] and [`playback`] hold the adapters and helpers for playing notes and sequences,
+//!   which can come from Standard MIDI Files read by [`smf`] or text scores read by [`score`].
+//!   [`scheduler`] times their events to the sample frame.
+//!
+//! Everything implements [`rodio::Source`], so it can be played through an
+//! `OutputStream` or pulled sample by sample for offline rendering.
+
+pub mod engine;
+pub mod envelope;
+pub mod filter;
+pub mod interpolation;
+pub mod keyboard;
+pub mod lfo;
+pub mod midi;
+#[cfg(target_os = "linux")]
+pub mod midi_input;
+pub mod mipmap;
+pub mod modulation;
+pub mod oscillator;
+pub mod pan;
+pub mod playback;
+pub mod scala;
+pub mod scheduler;
+pub mod score;
+pub mod smf;
+pub mod source;
+pub mod spsc;
+pub mod tuning;
+pub mod unison;
+pub mod voice;
+pub mod wav;
+pub mod wavetable;
+
+pub use oscillator::WavetableOscillator;
+pub use source::DurationSource;
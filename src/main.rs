@@ -1,300 +1,696 @@
-use rodio::{OutputStream, OutputStreamHandle, source::Source};
 use std::collections::HashMap;
-use std::env::args;
-use std::error::Error;
-use std::io::{self, Read};
-use std::io::{stdin, stdout, Write};
-use std::sync::mpsc;
-use std::thread;
-use std::thread::sleep;
+use std::io::{stdout, Write};
+use std::panic;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
-use termion::async_stdin;
-use termion::input::Keys;
-use termion::event::Key;
-use termion::input::TermRead;
-use termion::raw::IntoRawMode;
-use device_query::{DeviceQuery, DeviceState, Keycode};
+
+use clap::{Args, Parser, Subcommand};
 use crossterm::{
-    event::{self, Event, KeyCode},
+    event::{self, Event, KeyCode, KeyEventKind, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags},
     terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
-/*
-      We want to write a wavetable oscillator: an object that iterates over a specific wave table
-      with speed dictated by the frequency of the tone it should output.
-      That object needs to store the sampling rate, the wave table, current index into the wave table,
-      and the frequency-dependent index increment.
-   */
-
-struct WavetableOscillator {
-    sample_rate: u32,
-    wave_table: Vec<f32>,
-    index: f32,
-    index_increment: f32,
-}
-
-impl WavetableOscillator {
-    fn new(sample_rate: u32, wave_table: Vec<f32>) -> WavetableOscillator {
-        return WavetableOscillator {
-            sample_rate: sample_rate,
-            wave_table: wave_table,
-            index: 0.0,
-            index_increment: 0.0,
-        };
-    }
+use device_query::{DeviceQuery, DeviceState};
+use rodio::{OutputStream, Sink, Source};
+
+use wavetable_synth::engine::{Engine, DEFAULT_QUEUE_CAPACITY};
+use wavetable_synth::envelope::{Adsr, EnvelopeCurve};
+use wavetable_synth::filter::{FilterMode, FilterModel, FilterModulation, FilterSettings};
+use wavetable_synth::interpolation::Interpolation;
+use wavetable_synth::keyboard::{keycode_char, ComputerKeyboard, KeyAction, KeyGate, KeyboardLayout, DEFAULT_REPEAT_TIMEOUT};
+use wavetable_synth::lfo::{LfoSettings, DEFAULT_TEMPO};
+use wavetable_synth::midi::{MidiMapper, DEFAULT_BEND_RANGE};
+#[cfg(target_os = "linux")]
+use wavetable_synth::midi_input::{list_ports, MidiInput};
+use wavetable_synth::mipmap::MipMappedTable;
+use wavetable_synth::modulation::{ModMatrix, Route};
+use wavetable_synth::pan::PanLaw;
+use wavetable_synth::playback::{midi_file_events, note_events, output_sample_rate, score_events, SequenceSource, DEFAULT_SAMPLE_RATE};
+use wavetable_synth::scala::{KeyboardMapping, Scale};
+use wavetable_synth::score::Score;
+use wavetable_synth::smf::MidiFile;
+use wavetable_synth::tuning::{note_name, note_number, Tuning, CONCERT_A4};
+use wavetable_synth::unison::{DetuneCurve, UnisonPhase, UnisonSettings, MAX_UNISON};
+use wavetable_synth::voice::{StealPolicy, VoiceEvent, VoiceManager, CHANNELS};
+use wavetable_synth::wav::{load_wavetable, save_wav, SampleFormat};
+use wavetable_synth::wavetable::{MultiFrameTable, Waveform, DEFAULT_TABLE_SIZE};
+
+/// Play a wavetable oscillator from the computer keyboard. Tab changes the wave; press q or Esc to quit.
+///
+/// The keys `a w s e d f t g y h u j k` play an octave from C like a piano, `z`/`x` shift the
+/// octave and `c`/`v` transpose by a semitone. Notes sound for as long as their key is held.
+#[derive(Parser)]
+#[command(name = "wavetable_synth")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    synth: SynthArgs,
+
+    /// Keyboard layout file mapping computer keys to notes.
+    #[arg(long, value_name = "PATH")]
+    layout: Option<PathBuf>,
+
+    /// Seconds a key counts as held before its auto-repeat starts, on terminals that report no
+    /// key releases.
+    #[arg(long, default_value_t = 0.6, value_parser = parse_seconds)]
+    repeat_delay: f32,
+
+    /// Also play from a MIDI port, given as `client:port` or part of its name (see midi-ports).
+    #[arg(long, value_name = "PORT")]
+    midi_in: Option<String>,
+
+    /// Only listen to this MIDI channel, from 1 to 16, instead of all of them.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=16), requires = "midi_in")]
+    midi_channel: Option<u8>,
+}
 
-    /*
-        Sets the frequency of the wavetable oscillator by calculating the index_increment value.
-        The index_increment determines how quickly the oscillator moves through the wavetable
-        to generate the waveform.
+/// Options shared by live play and rendering.
+#[derive(Args)]
+struct SynthArgs {
+    /// Reference pitch of A4 in Hz, e.g. 440, 432, 415.3 or 442.
+    #[arg(long, global = true, default_value_t = CONCERT_A4, value_parser = parse_frequency)]
+    a4: f32,
 
-        Setting the frequency is essential because it determines the pitch of the sound produced by the wavetable oscillator.
-         A higher frequency will result in a higher-pitched sound, while a lower frequency will produce a lower-pitched sound. By adjusting the frequency dynamically, we can generate different musical notes and create melodies
+    /// Scala scale file (.scl) to tune the keyboard with instead of 12-tone equal temperament.
+    #[arg(long, global = true, value_name = "PATH")]
+    scl: Option<PathBuf>,
 
-        The set_frequency function allows us to conveniently update the frequency parameter
-        of the oscillator and adjust its output in real-time.
-     */
-    fn set_frequency(&mut self, frequency: f32) {
-        self.index_increment = frequency * self.wave_table.len() as f32
-            / self.sample_rate as f32;
-    }
+    /// Scala keyboard mapping (.kbm) for --scl; its reference frequency replaces --a4.
+    #[arg(long, global = true, value_name = "PATH", requires = "scl")]
+    kbm: Option<PathBuf>,
 
-    /*
-        Generating a sample consists of linear interpolation of the wave table values according to the index value and incrementing the index.
-     */
+    /// Wave table shape: sine, saw, square, triangle or pulse.
+    #[arg(long, global = true, default_value = "sine")]
+    wave: Waveform,
 
-    fn get_sample(&mut self) -> f32 {
-        let sample = self.lerp();
-        self.index += self.index_increment;
-        self.index %= self.wave_table.len() as f32;
-        return sample;
-    }
+    /// Duty cycle used by the pulse wave, between 0 and 1.
+    #[arg(long, global = true, default_value_t = 0.5, value_parser = parse_pulse_width)]
+    pulse_width: f32,
 
-    fn lerp(&self) -> f32 {
-        let truncated_index = self.index as usize;
-        let next_index = (truncated_index + 1) % self.wave_table.len();
+    /// Load the wave table from a WAV file instead of generating one.
+    #[arg(long, global = true, value_name = "PATH")]
+    wavetable: Option<PathBuf>,
 
-        let next_index_weight = self.index - truncated_index as f32;
-        let truncated_index_weight = 1.0 - next_index_weight;
+    /// Samples per frame in --wavetable, overriding any `clm` chunk.
+    #[arg(long, global = true, requires = "wavetable")]
+    frame_size: Option<usize>,
 
-        return truncated_index_weight * self.wave_table[truncated_index]
-            + next_index_weight * self.wave_table[next_index];
-    }
+    /// Samples per cycle in every wave table, from 8 to 65536; powers of two play fastest.
+    #[arg(long, global = true, default_value_t = DEFAULT_TABLE_SIZE, value_parser = parse_table_size)]
+    table_size: usize,
+
+    /// How the wave table is read between its samples: none, linear, hermite, lagrange or sinc.
+    #[arg(long, global = true, default_value = "linear")]
+    interpolation: Interpolation,
+
+    /// Sample rate in Hz; by default the output device's, and 44100 when rendering.
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(8000..=192000))]
+    sample_rate: Option<u32>,
+
+    /// Number of voices that can sound at once.
+    #[arg(long, global = true, default_value_t = 8, value_parser = clap::value_parser!(u16).range(1..=64))]
+    polyphony: u16,
+
+    /// Voice to take over when all are busy: oldest, quietest or same-note.
+    #[arg(long, global = true, default_value = "oldest")]
+    steal: StealPolicy,
+
+    /// Envelope attack time in seconds.
+    #[arg(long, global = true, default_value_t = 0.005, value_parser = parse_seconds)]
+    attack: f32,
+
+    /// Envelope decay time in seconds.
+    #[arg(long, global = true, default_value_t = 0.1, value_parser = parse_seconds)]
+    decay: f32,
+
+    /// Envelope sustain level, between 0 and 1.
+    #[arg(long, global = true, default_value_t = 0.8, value_parser = parse_level)]
+    sustain: f32,
+
+    /// Envelope release time in seconds.
+    #[arg(long, global = true, default_value_t = 0.2, value_parser = parse_seconds)]
+    release: f32,
+
+    /// Envelope curve: linear or exponential.
+    #[arg(long, global = true, default_value = "exponential")]
+    curve: EnvelopeCurve,
+
+    /// Filter every voice: lowpass, highpass, bandpass or notch.
+    #[arg(long, global = true)]
+    filter: Option<FilterMode>,
+
+    /// Filter model: svf (12 dB per octave) or ladder (24 dB per octave).
+    #[arg(long, global = true, default_value = "svf")]
+    filter_model: FilterModel,
+
+    /// Filter cutoff in Hz.
+    #[arg(long, global = true, default_value_t = 2000.0, value_parser = parse_frequency)]
+    cutoff: f32,
+
+    /// Filter resonance, between 0 and 1.
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_level)]
+    resonance: f32,
+
+    /// Octaves the filter envelope moves the cutoff at its peak; negative closes the filter.
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_octaves, allow_negative_numbers = true)]
+    filter_env: f32,
+
+    /// Filter envelope attack time in seconds.
+    #[arg(long, global = true, default_value_t = 0.005, value_parser = parse_seconds)]
+    filter_attack: f32,
+
+    /// Filter envelope decay time in seconds.
+    #[arg(long, global = true, default_value_t = 0.3, value_parser = parse_seconds)]
+    filter_decay: f32,
+
+    /// Filter envelope sustain level, between 0 and 1.
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_level)]
+    filter_sustain: f32,
+
+    /// Filter envelope release time in seconds.
+    #[arg(long, global = true, default_value_t = 0.2, value_parser = parse_seconds)]
+    filter_release: f32,
+
+    /// Share of the note's pitch the cutoff follows, between 0 and 1.
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_level)]
+    key_tracking: f32,
+
+    /// How much softer notes shrink the filter envelope, between 0 and 1.
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_level)]
+    filter_velocity: f32,
+
+    /// Oscillators stacked on every note, from 1 to 16.
+    #[arg(long, global = true, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=MAX_UNISON as i64))]
+    unison: u8,
+
+    /// Cents the outermost unison oscillators are detuned from the note.
+    #[arg(long, global = true, default_value_t = 20.0, value_parser = parse_cents)]
+    detune: f32,
+
+    /// How the detune grows towards the outer unison oscillators: linear or exponential.
+    #[arg(long, global = true, default_value = "linear")]
+    detune_curve: DetuneCurve,
+
+    /// How far the unison oscillators spread across the stereo field, between 0 and 1.
+    #[arg(long, global = true, default_value_t = 1.0, value_parser = parse_level)]
+    spread: f32,
+
+    /// Where the unison oscillators start their cycle: random, or a phase between 0 and 1 for
+    /// the middle one, with the others at even steps through the cycle from it.
+    #[arg(long, global = true, default_value = "0")]
+    unison_phase: UnisonPhase,
+
+    /// Mix between the centre unison oscillator (0) and the detuned ones (1).
+    #[arg(long, global = true, default_value_t = 0.5, value_parser = parse_level)]
+    unison_blend: f32,
+
+    /// Where the voices sit in the stereo field, from -1 (left) to 1 (right); pan routes add to it.
+    #[arg(long, global = true, default_value_t = 0.0, value_parser = parse_pan, allow_negative_numbers = true)]
+    pan: f32,
+
+    /// How the voices' gains follow their pan: equal-power (-3 dB in the centre) or linear (-6 dB).
+    #[arg(long, global = true, default_value = "equal-power")]
+    pan_law: PanLaw,
+
+    /// Add an LFO as shape:rate[:phase[:fade-in]], e.g. sine:5 or triangle:1/8.:0.25:2; the
+    /// first is lfo1, the next lfo2 and so on.
+    #[arg(long = "lfo", global = true, value_name = "SHAPE:RATE")]
+    lfos: Vec<LfoSettings>,
+
+    /// Add a modulation route as source:destination:depth, e.g. lfo1:pitch:0.2 or
+    /// velocity:cutoff:2. Any routes replace the default mod-wheel:position:1.
+    #[arg(long = "mod", global = true, value_name = "SOURCE:DEST:DEPTH")]
+    routes: Vec<Route>,
+
+    /// Tempo in beats per minute for LFO rates given as note values, until a score or MIDI file
+    /// sets its own.
+    #[arg(long, global = true, default_value_t = DEFAULT_TEMPO, value_parser = parse_tempo)]
+    tempo: f32,
+
+    /// Pitch bend range in semitones, for MIDI input and files.
+    #[arg(long, global = true, default_value_t = DEFAULT_BEND_RANGE, value_parser = parse_semitones)]
+    bend_range: f32,
 }
 
-impl Iterator for WavetableOscillator {
-    type Item = f32;
+#[derive(Subcommand)]
+enum Command {
+    /// List the MIDI ports --midi-in can connect to.
+    MidiPorts,
+    /// Play a sequence through the sound card.
+    Play(PlayArgs),
+    /// Write a sequence to a WAV file instead of playing it, faster than real time.
+    Render(RenderArgs),
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        return Some(self.get_sample());
-    }
+/// What to play, for play and render.
+#[derive(Args)]
+#[group(id = "input", required = true, multiple = false)]
+struct SequenceArgs {
+    /// Notes to play one after another, as names such as C#4 or MIDI numbers.
+    #[arg(value_parser = parse_note)]
+    notes: Vec<u8>,
+
+    /// Standard MIDI File (.mid) to play instead of notes.
+    #[arg(long, value_name = "PATH")]
+    midi: Option<PathBuf>,
+
+    /// Text score to play instead of notes, e.g. "t=90 C4/8 E4/8 [C4 E4 G4]/2"; see the README.
+    #[arg(long, value_name = "PATH")]
+    score: Option<PathBuf>,
 }
 
-impl Source for WavetableOscillator {
-    fn channels(&self) -> u16 {
-        return 1;
-    }
+#[derive(Args)]
+struct PlayArgs {
+    #[command(flatten)]
+    sequence: SequenceArgs,
 
-    fn sample_rate(&self) -> u32 {
-        return self.sample_rate;
-    }
+    /// Seconds each note is held.
+    #[arg(long, default_value_t = 0.5, value_parser = parse_seconds)]
+    note_length: f32,
+}
 
-    fn current_frame_len(&self) -> Option<usize> {
-        return None;
-    }
+#[derive(Args)]
+struct RenderArgs {
+    /// WAV file to write.
+    output: PathBuf,
 
-    fn total_duration(&self) -> Option<Duration> {
-        return None;
-    }
+    #[command(flatten)]
+    sequence: SequenceArgs,
+
+    /// Seconds each note is held.
+    #[arg(long, default_value_t = 0.5, value_parser = parse_seconds)]
+    note_length: f32,
+
+    /// Length of the file in seconds; by default long enough for the last release.
+    #[arg(long, value_parser = parse_seconds)]
+    length: Option<f32>,
+
+    /// Sample format: 16, 24 or 32f.
+    #[arg(long, default_value = "16")]
+    format: SampleFormat,
 }
 
-struct DurationSource<S> {
-    source: S,
-    duration: Duration,
-    elapsed: Duration,
+/// Where key releases come from.
+enum KeyRelease {
+    /// The terminal reports them (kitty keyboard protocol, or Windows).
+    Events,
+    /// The key state is polled from the X server.
+    Polled(DeviceState),
+    /// A key is up once its auto-repeat stops.
+    AutoRepeat,
 }
 
-impl<S> DurationSource<S>
-    where
-        S: Source,
-        S::Item: rodio::Sample,
-{
-    pub fn new(source: S, duration: Duration) -> Self {
-        DurationSource {
-            source,
-            duration,
-            elapsed: Duration::new(0, 0),
+impl KeyRelease {
+    fn detect() -> KeyRelease {
+        if cfg!(windows) || terminal::supports_keyboard_enhancement().unwrap_or(false) {
+            KeyRelease::Events
+        } else if let Some(state) = open_device_state() {
+            KeyRelease::Polled(state)
+        } else {
+            KeyRelease::AutoRepeat
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            KeyRelease::Events => "terminal events",
+            KeyRelease::Polled(_) => "X key state",
+            KeyRelease::AutoRepeat => "auto-repeat",
         }
     }
 }
 
-impl<S> Source for DurationSource<S>
-    where
-        S: Source,
-        S::Item: rodio::Sample,
-{
-    fn current_frame_len(&self) -> Option<usize> {
-        self.source.current_frame_len()
+fn open_device_state() -> Option<DeviceState> {
+    if cfg!(target_os = "linux") {
+        std::env::var_os("DISPLAY")?;
     }
+    // DeviceState::new panics when it cannot reach the display; keep that message off the screen
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let state = panic::catch_unwind(DeviceState::new).ok();
+    panic::set_hook(hook);
+    state
+}
 
-    fn channels(&self) -> u16 {
-        self.source.channels()
+fn parse_pulse_width(s: &str) -> Result<f32, String> {
+    let width: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if width > 0.0 && width < 1.0 {
+        Ok(width)
+    } else {
+        Err("pulse width must be between 0 and 1".to_string())
     }
+}
 
-    fn sample_rate(&self) -> u32 {
-        self.source.sample_rate()
+fn parse_frequency(s: &str) -> Result<f32, String> {
+    let frequency: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if frequency > 0.0 && frequency.is_finite() {
+        Ok(frequency)
+    } else {
+        Err("frequency must be more than 0 Hz".to_string())
     }
+}
 
-    fn total_duration(&self) -> Option<Duration> {
-        Some(self.duration)
+fn parse_seconds(s: &str) -> Result<f32, String> {
+    let seconds: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if seconds >= 0.0 && seconds.is_finite() {
+        Ok(seconds)
+    } else {
+        Err("time must be zero or more seconds".to_string())
     }
 }
 
-impl<S> Iterator for DurationSource<S>
-    where
-        S: Source,
-        S::Item: rodio::Sample,
-{
-    type Item = S::Item;
+fn parse_note(s: &str) -> Result<u8, String> {
+    s.parse()
+        .ok()
+        .or_else(|| note_number(s))
+        .filter(|note| *note <= 127)
+        .ok_or_else(|| format!("'{}' is not a note", s))
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.elapsed >= self.duration {
-            return None;
-        }
+fn parse_semitones(s: &str) -> Result<f32, String> {
+    let semitones: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if (0.0..=48.0).contains(&semitones) {
+        Ok(semitones)
+    } else {
+        Err("range must be between 0 and 48 semitones".to_string())
+    }
+}
 
-        if let Some(sample) = self.source.next() {
-            self.elapsed += Duration::from_secs_f32(1.0 / self.sample_rate() as f32);
-            Some(sample)
-        } else {
-            None
-        }
+fn parse_octaves(s: &str) -> Result<f32, String> {
+    let octaves: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if (-10.0..=10.0).contains(&octaves) {
+        Ok(octaves)
+    } else {
+        Err("amount must be between -10 and 10 octaves".to_string())
     }
 }
 
+fn parse_cents(s: &str) -> Result<f32, String> {
+    let cents: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if (0.0..=100.0).contains(&cents) {
+        Ok(cents)
+    } else {
+        Err("detune must be between 0 and 100 cents".to_string())
+    }
+}
 
-struct DisplayableKey(Key);
+fn parse_tempo(s: &str) -> Result<f32, String> {
+    let tempo: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if tempo > 0.0 && tempo <= 1000.0 {
+        Ok(tempo)
+    } else {
+        Err("tempo must be more than 0 and at most 1000 beats per minute".to_string())
+    }
+}
 
-impl std::fmt::Display for DisplayableKey {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.0)
+fn parse_pan(s: &str) -> Result<f32, String> {
+    let pan: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if (-1.0..=1.0).contains(&pan) {
+        Ok(pan)
+    } else {
+        Err("pan must be between -1 (left) and 1 (right)".to_string())
     }
 }
 
+fn parse_table_size(s: &str) -> Result<usize, String> {
+    let size: usize = s.parse().map_err(|_| format!("'{}' is not a whole number", s))?;
+    if (8..=65536).contains(&size) {
+        Ok(size)
+    } else {
+        Err("table size must be between 8 and 65536 samples".to_string())
+    }
+}
 
-fn create_note_to_freq_map() -> HashMap<String, f32> {
-    let mut map = HashMap::new();
-    map.insert("A".to_string(), 440.00);
-    map.insert("A#".to_string(), 466.16);
-    map.insert("B".to_string(), 493.88);
-    map.insert("C".to_string(), 523.25);
-    map.insert("C#".to_string(), 554.37);
-    map.insert("D".to_string(), 587.33);
-    map.insert("D#".to_string(), 622.25);
-    map.insert("E".to_string(), 659.25);
-    map.insert("F".to_string(), 698.46);
-    map.insert("F#".to_string(), 739.99);
-    map.insert("G".to_string(), 783.99);
-    map.insert("G#".to_string(), 830.61);
-
-    map
-}
-
-fn create_note_to_freq_map_432() -> HashMap<String, f32> {
-    let mut map = HashMap::new();
-    map.insert("A".to_string(), 432.00);
-    map.insert("A#".to_string(), 457.69);
-    map.insert("B".to_string(), 484.90);
-    map.insert("C".to_string(), 512.33);
-    map.insert("C#".to_string(), 542.29);
-    map.insert("D".to_string(), 576.65);
-    map.insert("D#".to_string(), 608.39);
-    map.insert("E".to_string(), 645.86);
-    map.insert("F".to_string(), 684.72);
-    map.insert("F#".to_string(), 725.38);
-    map.insert("G".to_string(), 768.82);
-    map.insert("G#".to_string(), 815.51);
-
-    map
-}
-
-fn play_notes(notes: Vec<&str>, duration: f32, stream_handle: &OutputStreamHandle, wave_table: Vec<f32>, note_to_freq_map: HashMap<String, f32>) {
-    for note in notes {
-        // set the frequency
-        let frequency = note_to_freq_map.get(note).unwrap_or(&440.0);  // default to A4 if not found
-        let mut oscillator = WavetableOscillator::new(44100, wave_table.clone());
-        oscillator.set_frequency(*frequency);
-        stream_handle.play_raw(oscillator.convert_samples());
-        // sleep for the duration
-        std::thread::sleep(std::time::Duration::from_secs_f32(duration));
+fn parse_level(s: &str) -> Result<f32, String> {
+    let level: f32 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    if (0.0..=1.0).contains(&level) {
+        Ok(level)
+    } else {
+        Err("level must be between 0 and 1".to_string())
     }
 }
 
-fn play_note(note: &str, stream_handle: &OutputStreamHandle, wave_table: Vec<f32>, note_to_freq_map: HashMap<String, f32>, duration: Duration) {
-    let frequency = note_to_freq_map.get(note).unwrap_or(&440.0);
-    let mut oscillator = WavetableOscillator::new(44100, wave_table.clone());
-    oscillator.set_frequency(*frequency);
-    let duration_source = DurationSource::new(oscillator, duration);
-    if let Err(err) = stream_handle.play_raw(duration_source.convert_samples()) {
-        eprintln!("Error playing note {}: {}", note, err);
+fn load_tuning(synth: &SynthArgs) -> Result<Tuning, String> {
+    let Some(scl) = &synth.scl else {
+        return Ok(Tuning::new(synth.a4));
+    };
+    let scale = Scale::open(scl).map_err(|err| format!("Could not load {}: {}", scl.display(), err))?;
+    match &synth.kbm {
+        Some(kbm) => {
+            let mapping = KeyboardMapping::open(kbm).map_err(|err| format!("Could not load {}: {}", kbm.display(), err))?;
+            Ok(Tuning::from_scala(scale, mapping))
+        }
+        None => Ok(Tuning::from_scale(scale, synth.a4)),
     }
 }
 
+fn load_voices(synth: &SynthArgs, sample_rate: u32) -> Result<VoiceManager, String> {
+    let wave_table: MultiFrameTable = match &synth.wavetable {
+        Some(path) => load_wavetable(path, synth.frame_size, synth.table_size)
+            .map_err(|err| format!("Could not load {}: {}", path.display(), err))?,
+        None => synth.wave.table(synth.table_size, synth.pulse_width).into(),
+    };
+    let mut voices = VoiceManager::new(sample_rate, MipMappedTable::new(&wave_table), synth.polyphony as usize, synth.steal);
+    voices.set_envelope(Adsr::new(synth.attack, synth.decay, synth.sustain, synth.release, synth.curve));
+    voices.set_filter(synth.filter.map(|mode| FilterSettings::new(synth.filter_model, mode, synth.cutoff, synth.resonance)));
+    let filter_envelope = Adsr::new(synth.filter_attack, synth.filter_decay, synth.filter_sustain, synth.filter_release, synth.curve);
+    voices.set_filter_modulation(FilterModulation::new(filter_envelope, synth.filter_env, synth.key_tracking, synth.filter_velocity));
+    voices.set_unison(UnisonSettings::new(
+        synth.unison as usize,
+        synth.detune,
+        synth.detune_curve,
+        synth.spread,
+        synth.unison_phase,
+        synth.unison_blend,
+    ));
+    voices.set_interpolation(synth.interpolation);
+    voices.set_pan(synth.pan);
+    voices.set_pan_law(synth.pan_law);
+    let routes = if synth.routes.is_empty() { ModMatrix::default().routes().to_vec() } else { synth.routes.clone() };
+    voices.set_modulation(ModMatrix::new(synth.lfos.clone(), routes, synth.tempo)?);
+    Ok(voices)
+}
 
-fn main() {
-    let args: Vec<String> = args().collect();
-    if args.len() < 1 {
-        eprintln!("Usage: wavetable_synth [440|432] ...");
-        ();
+#[cfg(target_os = "linux")]
+fn list_midi_ports() -> Result<(), String> {
+    let ports = list_ports().map_err(|err| format!("Could not open the ALSA sequencer: {}", err))?;
+    if ports.is_empty() {
+        println!("No MIDI ports found");
+    }
+    for port in ports {
+        println!("{}", port);
     }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn list_midi_ports() -> Result<(), String> {
+    Err("MIDI input is only supported on Linux".to_string())
+}
+
+/// Sends the messages from the `--midi-in` port to `engine` through a controller of their own.
+#[cfg(target_os = "linux")]
+fn connect_midi(cli: &Cli, tuning: &Tuning, engine: &mut Engine) -> Result<Option<MidiInput>, String> {
+    let Some(spec) = &cli.midi_in else { return Ok(None) };
+    let mut mapper = MidiMapper::new(tuning.clone());
+    mapper.set_bend_range(cli.synth.bend_range);
+    mapper.set_channel(cli.midi_channel.map(|channel| channel - 1));
+    let mut controller = engine.controller(DEFAULT_QUEUE_CAPACITY);
+    let input = MidiInput::connect(spec, move |message| {
+        if let Some(event) = mapper.event(message) {
+            controller.handle(event);
+        }
+    });
+    input.map(Some).map_err(|err| format!("Could not open MIDI port {}: {}", spec, err))
+}
 
-    let frequency_standard: u32 = args[1].parse().expect("Invalid frequency standard");
+#[cfg(not(target_os = "linux"))]
+fn connect_midi(cli: &Cli, _tuning: &Tuning, _engine: &mut Engine) -> Result<Option<()>, String> {
+    match cli.midi_in {
+        Some(_) => Err("MIDI input is only supported on Linux".to_string()),
+        None => Ok(None),
+    }
+}
 
-    //A wave table is an array in memory, which contains 1 period of the waveform
-    // we want to play out through our oscillator.
-    let wave_table_size = 64;
-    let mut wave_table: Vec<f32> = Vec::with_capacity(wave_table_size);
+type SequenceEvents = (Vec<(Duration, VoiceEvent)>, Duration);
 
-    /*
-        We calculate the value of the sine waveform for arguments linearly increasing from
-        0 to 2π to calculate the sine value for argument.
+/// The voice events of a sequence, and the time its last note ends.
+fn sequence_events(synth: &SynthArgs, sequence: &SequenceArgs, tuning: &Tuning, note_length: f32) -> Result<SequenceEvents, String> {
+    let mut mapper = MidiMapper::new(tuning.clone());
+    mapper.set_bend_range(synth.bend_range);
+    if let Some(path) = &sequence.midi {
+        let file = MidiFile::open(path).map_err(|err| format!("Could not load {}: {}", path.display(), err))?;
+        return Ok((midi_file_events(&file, &mapper), file.duration()));
+    }
+    if let Some(path) = &sequence.score {
+        let score = Score::open(path).map_err(|err| format!("Could not load {}: {}", path.display(), err))?;
+        return Ok((score_events(&score, tuning), score.length));
+    }
+    let note_length = Duration::from_secs_f32(note_length);
+    Ok((note_events(&sequence.notes, tuning, note_length), note_length * sequence.notes.len() as u32))
+}
 
-        By populating the wave_table array with the calculated sine values,
-         we generate a single cycle of a sine waveform within the specified range.
-         This waveform can then be used as a basis for creating more complex sounds in music synthesis applications.
-     */
-    for n in 0..wave_table_size {
-        wave_table.push((2.0 * std::f32::consts::PI * n as f32 / wave_table_size as f32).sin());
+fn render(synth: &SynthArgs, args: &RenderArgs) -> Result<(), String> {
+    let tuning = load_tuning(synth)?;
+    let sample_rate = synth.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let voices = load_voices(synth, sample_rate)?;
+    let (events, end) = sequence_events(synth, &args.sequence, &tuning, args.note_length)?;
+
+    let started = Instant::now();
+    let mut samples: Vec<f32> = SequenceSource::new(voices, events).with_end(end).collect();
+    if let Some(seconds) = args.length {
+        let frames = (seconds as f64 * sample_rate as f64).round() as usize;
+        samples.resize(frames * CHANNELS as usize, 0.0);
     }
+    save_wav(&args.output, &samples, CHANNELS, sample_rate, args.format)
+        .map_err(|err| format!("Could not write {}: {}", args.output.display(), err))?;
+    println!(
+        "Wrote {:.2} s to {} in {:.2} s",
+        samples.len() as f32 / CHANNELS as f32 / sample_rate as f32,
+        args.output.display(),
+        started.elapsed().as_secs_f32()
+    );
+    Ok(())
+}
 
-    let Ok((_stream, stream_handle)) = OutputStream::try_default() else { todo!() };
+/// --sample-rate, or else the rate the output device mixes at.
+fn live_sample_rate(synth: &SynthArgs) -> u32 {
+    synth.sample_rate.or_else(output_sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE)
+}
 
+fn play_sequence(synth: &SynthArgs, args: &PlayArgs) -> Result<(), String> {
+    let tuning = load_tuning(synth)?;
+    let voices = load_voices(synth, live_sample_rate(synth))?;
+    let (events, end) = sequence_events(synth, &args.sequence, &tuning, args.note_length)?;
+
+    let Ok((_stream, stream_handle)) = OutputStream::try_default() else {
+        return Err("No audio output device found; the render command writes WAV files without one".to_string());
+    };
+    let sink = Sink::try_new(&stream_handle).map_err(|err| format!("Error starting playback: {}", err))?;
+    sink.append(SequenceSource::new(voices, events).with_end(end));
+    sink.sleep_until_end();
+    Ok(())
+}
 
-    let mut stdout = stdout().into_raw_mode().unwrap();
+fn play(cli: &Cli) -> Result<(), String> {
+    let tuning = load_tuning(&cli.synth)?;
+    let layout = match &cli.layout {
+        Some(path) => KeyboardLayout::open(path).map_err(|err| format!("Could not load {}: {}", path.display(), err))?,
+        None => KeyboardLayout::default(),
+    };
+    let mut keyboard = ComputerKeyboard::new(layout);
+
+    let sample_rate = live_sample_rate(&cli.synth);
+    let mut engine = Engine::new(load_voices(&cli.synth, sample_rate)?);
+    let mut voices = engine.controller(DEFAULT_QUEUE_CAPACITY);
+    let midi_input = connect_midi(cli, &tuning, &mut engine)?;
+
+    let Ok((_stream, stream_handle)) = OutputStream::try_default() else {
+        return Err("No audio output device found; the render command writes WAV files without one".to_string());
+    };
+    stream_handle.play_raw(engine.convert_samples()).map_err(|err| format!("Error starting playback: {}", err))?;
+
+    let mut stdout = stdout();
     let _alternate_screen = stdout.execute(EnterAlternateScreen);
 
     terminal::enable_raw_mode().unwrap();
 
+    let key_release = KeyRelease::detect();
+    if let KeyRelease::Events = key_release {
+        let flags = KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+            | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+            | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES;
+        let _ = stdout.execute(PushKeyboardEnhancementFlags(flags));
+    }
+    write!(stdout, "key releases from {}, running at {} Hz\r\n", key_release.name(), sample_rate).unwrap();
+    #[cfg(target_os = "linux")]
+    if let Some(input) = &midi_input {
+        write!(stdout, "MIDI input from {}\r\n", input.port()).unwrap();
+    }
+
+    let mut gate = KeyGate::new(Duration::from_secs_f32(cli.repeat_delay), DEFAULT_REPEAT_TIMEOUT);
+    // the note each held key started, so a release stops it even after an octave change
+    let mut sounding: HashMap<char, u8> = HashMap::new();
+    // Tab steps through the basic shapes, starting after --wave
+    let mut waveform = Waveform::ALL.iter().position(|waveform| *waveform == cli.synth.wave).unwrap_or(0);
+
     loop {
+        let now = Instant::now();
+        let mut released = match &key_release {
+            KeyRelease::Polled(state) => {
+                let down: Vec<char> = state.get_keys().into_iter().filter_map(keycode_char).collect();
+                gate.sync(&down)
+            }
+            _ => Vec::new(),
+        };
+        if !matches!(key_release, KeyRelease::Events) {
+            released.extend(gate.expire(now));
+        }
+        for key in released {
+            if let Some(note) = sounding.remove(&key) {
+                voices.note_off(note);
+            }
+        }
+
         // we handle the event
-        if event::poll(std::time::Duration::from_millis(10)).unwrap() {
-            match event::read().unwrap() {
-                Event::Key(event) => {
-                    match event.code {
-                        KeyCode::Char('q') => break,
-                        KeyCode::Char(c) => {
-                            let note = c.to_uppercase().to_string();
-                            if let Some(frequency) = create_note_to_freq_map().get(&note) {
-                                writeln!(stdout, "{}", note).unwrap();
-                                play_note(note.as_str(), &stream_handle, wave_table.clone(),
-                                          create_note_to_freq_map(), Duration::from_millis(100))
-                            }
+        if event::poll(Duration::from_millis(10)).unwrap() {
+            if let Event::Key(event) = event::read().unwrap() {
+                let pressed = event.kind != KeyEventKind::Release;
+                match event.code {
+                    KeyCode::Esc if pressed => break,
+                    KeyCode::Tab if pressed => {
+                        waveform = (waveform + 1) % Waveform::ALL.len();
+                        let wave = Waveform::ALL[waveform];
+                        write!(stdout, "wave {}\r\n", wave).unwrap();
+                        voices.swap_table(MipMappedTable::new(&wave.table(cli.synth.table_size, cli.synth.pulse_width).into()));
+                    }
+                    KeyCode::Char(c) if !pressed && gate.release(c) => {
+                        if let Some(note) = sounding.remove(&c.to_ascii_lowercase()) {
+                            voices.note_off(note);
                         }
-                        _ => {}
                     }
+                    KeyCode::Char(_) if !pressed => {}
+                    // repeats of a held key change nothing
+                    KeyCode::Char(c) if gate.press(c, now) => match keyboard.press(c) {
+                        Some(KeyAction::Note(note)) => {
+                            if let Some(frequency) = tuning.frequency(note) {
+                                write!(stdout, "{}\r\n", note_name(note)).unwrap();
+                                voices.note_on(note, frequency, 1.0);
+                                sounding.insert(c.to_ascii_lowercase(), note);
+                            }
+                        }
+                        Some(KeyAction::Octave(octave)) => write!(stdout, "octave {:+}\r\n", octave).unwrap(),
+                        Some(KeyAction::Transpose(semitones)) => write!(stdout, "transpose {:+}\r\n", semitones).unwrap(),
+                        None if c == 'q' => break,
+                        None => {}
+                    },
+                    _ => {}
                 }
-                _ => {}
             }
         }
     }
+    drop(midi_input);
+    voices.all_notes_off();
+    if let KeyRelease::Events = key_release {
+        let _ = stdout.execute(PopKeyboardEnhancementFlags);
+    }
+    terminal::disable_raw_mode().unwrap();
     let _ = stdout.execute(LeaveAlternateScreen);
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Some(Command::MidiPorts) => list_midi_ports(),
+        Some(Command::Play(args)) => play_sequence(&cli.synth, args),
+        Some(Command::Render(args)) => render(&cli.synth, args),
+        None => play(&cli),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 }
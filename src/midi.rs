@@ -0,0 +1,203 @@
+use crate::tuning::Tuning;
+use crate::voice::VoiceEvent;
+
+/*
+    MIDI channel messages start with a status byte whose high nibble is the kind of message and
+    whose low nibble is the channel, followed by one or two data bytes of 7 bits each. Pitch bend
+    packs a 14-bit value into its two data bytes, least significant first, with 8192 meaning no
+    bend. A note-on with velocity 0 is another way of writing a note-off.
+
+    `MidiMapper` turns these messages into `VoiceEvent`s: notes are tuned with a `Tuning`, pitch bend
+    covers a configurable range in semitones, the mod wheel and channel aftertouch feed the
+    modulation matrix, the pan controller places the voices and the sustain pedal holds released
+    notes.
+ */
+
+/// Controller number of the modulation wheel.
+pub const MOD_WHEEL: u8 = 1;
+
+/// Controller number of the pan position, with 64 in the centre.
+pub const PAN: u8 = 10;
+
+/// Controller number of the sustain (damper) pedal.
+pub const SUSTAIN_PEDAL: u8 = 64;
+
+/// Channel mode message that silences every note at once.
+pub const ALL_SOUND_OFF: u8 = 120;
+
+/// Channel mode message that releases every note.
+pub const ALL_NOTES_OFF: u8 = 123;
+
+/// Default pitch bend range in semitones, as on most synthesizers.
+pub const DEFAULT_BEND_RANGE: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// Bend from -8192 to 8191, 0 being no bend.
+    PitchBend { channel: u8, value: i16 },
+}
+
+impl MidiMessage {
+    /// Reads a channel message from its bytes. Returns `None` for system messages, messages this
+    /// synth ignores and malformed ones.
+    pub fn parse(bytes: &[u8]) -> Option<MidiMessage> {
+        let (&status, data) = bytes.split_first()?;
+        if status & 0x80 == 0 || data.iter().any(|byte| byte & 0x80 != 0) {
+            return None;
+        }
+        let channel = status & 0x0f;
+        let message = match (status & 0xf0, data) {
+            (0x80, &[note, velocity]) => MidiMessage::NoteOff { channel, note, velocity },
+            (0x90, &[note, 0]) => MidiMessage::NoteOff { channel, note, velocity: 64 },
+            (0x90, &[note, velocity]) => MidiMessage::NoteOn { channel, note, velocity },
+            (0xb0, &[controller, value]) => MidiMessage::ControlChange { channel, controller, value },
+            (0xd0, &[pressure]) => MidiMessage::ChannelPressure { channel, pressure },
+            (0xe0, &[lsb, msb]) => {
+                let value = ((msb as i16) << 7 | lsb as i16) - 8192;
+                MidiMessage::PitchBend { channel, value }
+            }
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => channel,
+        }
+    }
+}
+
+/// Turns MIDI messages into voice events.
+#[derive(Clone, Debug)]
+pub struct MidiMapper {
+    tuning: Tuning,
+    bend_range: f32,
+    channel: Option<u8>,
+}
+
+impl MidiMapper {
+    /// Listens on every channel with a bend range of [`DEFAULT_BEND_RANGE`].
+    pub fn new(tuning: Tuning) -> MidiMapper {
+        MidiMapper { tuning, bend_range: DEFAULT_BEND_RANGE, channel: None }
+    }
+
+    /// Semitones the pitch bend wheel reaches at either end.
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        self.bend_range = semitones;
+    }
+
+    /// Only listens to `channel` (0 to 15), or to every channel for `None`.
+    pub fn set_channel(&mut self, channel: Option<u8>) {
+        self.channel = channel;
+    }
+
+    pub fn tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    /// The voice event for `message`, if it has one.
+    pub fn event(&self, message: MidiMessage) -> Option<VoiceEvent> {
+        if self.channel.is_some_and(|channel| channel != message.channel()) {
+            return None;
+        }
+        match message {
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                let frequency = self.tuning.frequency(note)?;
+                Some(VoiceEvent::NoteOn { note, frequency, velocity: velocity as f32 / 127.0 })
+            }
+            MidiMessage::NoteOff { note, .. } => Some(VoiceEvent::NoteOff { note }),
+            MidiMessage::PitchBend { value, .. } => {
+                let amount = if value < 0 { value as f32 / 8192.0 } else { value as f32 / 8191.0 };
+                Some(VoiceEvent::PitchBend(amount * self.bend_range))
+            }
+            MidiMessage::ControlChange { controller, value, .. } => match controller {
+                MOD_WHEEL => Some(VoiceEvent::ModWheel(value as f32 / 127.0)),
+                PAN => Some(VoiceEvent::Pan(((value as f32 - 64.0) / 63.0).max(-1.0))),
+                SUSTAIN_PEDAL => Some(VoiceEvent::Sustain(value >= 64)),
+                ALL_SOUND_OFF => Some(VoiceEvent::AllSoundOff),
+                ALL_NOTES_OFF => Some(VoiceEvent::AllNotesOff),
+                _ => None,
+            },
+            MidiMessage::ChannelPressure { pressure, .. } => Some(VoiceEvent::Aftertouch(pressure as f32 / 127.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_channel_messages() {
+        assert_eq!(MidiMessage::parse(&[0x90, 60, 100]), Some(MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 }));
+        assert_eq!(MidiMessage::parse(&[0x83, 60, 10]), Some(MidiMessage::NoteOff { channel: 3, note: 60, velocity: 10 }));
+        assert_eq!(MidiMessage::parse(&[0x9f, 61, 0]), Some(MidiMessage::NoteOff { channel: 15, note: 61, velocity: 64 }));
+        assert_eq!(
+            MidiMessage::parse(&[0xb0, 64, 127]),
+            Some(MidiMessage::ControlChange { channel: 0, controller: 64, value: 127 })
+        );
+        assert_eq!(MidiMessage::parse(&[0xd2, 90]), Some(MidiMessage::ChannelPressure { channel: 2, pressure: 90 }));
+    }
+
+    #[test]
+    fn parses_pitch_bend_around_the_centre() {
+        let bend = |lsb, msb| match MidiMessage::parse(&[0xe0, lsb, msb]) {
+            Some(MidiMessage::PitchBend { value, .. }) => value,
+            other => panic!("not a pitch bend: {:?}", other),
+        };
+        assert_eq!(bend(0x00, 0x40), 0);
+        assert_eq!(bend(0x00, 0x00), -8192);
+        assert_eq!(bend(0x7f, 0x7f), 8191);
+        assert_eq!(bend(0x01, 0x40), 1);
+    }
+
+    #[test]
+    fn rejects_other_bytes() {
+        assert_eq!(MidiMessage::parse(&[]), None);
+        assert_eq!(MidiMessage::parse(&[60, 100]), None);
+        assert_eq!(MidiMessage::parse(&[0x90, 60]), None);
+        assert_eq!(MidiMessage::parse(&[0x90, 60, 200]), None);
+        assert_eq!(MidiMessage::parse(&[0xf8]), None);
+        assert_eq!(MidiMessage::parse(&[0xc0, 5]), None);
+    }
+
+    #[test]
+    fn maps_messages_to_voice_events() {
+        let mut mapper = MidiMapper::new(Tuning::default());
+        mapper.set_bend_range(12.0);
+        assert_eq!(
+            mapper.event(MidiMessage::NoteOn { channel: 0, note: 69, velocity: 127 }),
+            Some(VoiceEvent::NoteOn { note: 69, frequency: 440.0, velocity: 1.0 })
+        );
+        assert_eq!(mapper.event(MidiMessage::NoteOff { channel: 0, note: 69, velocity: 0 }), Some(VoiceEvent::NoteOff { note: 69 }));
+        assert_eq!(mapper.event(MidiMessage::PitchBend { channel: 0, value: -8192 }), Some(VoiceEvent::PitchBend(-12.0)));
+        assert_eq!(mapper.event(MidiMessage::PitchBend { channel: 0, value: 8191 }), Some(VoiceEvent::PitchBend(12.0)));
+        let control = |controller, value| MidiMessage::ControlChange { channel: 0, controller, value };
+        assert_eq!(mapper.event(control(MOD_WHEEL, 127)), Some(VoiceEvent::ModWheel(1.0)));
+        assert_eq!(mapper.event(control(PAN, 0)), Some(VoiceEvent::Pan(-1.0)));
+        assert_eq!(mapper.event(control(PAN, 64)), Some(VoiceEvent::Pan(0.0)));
+        assert_eq!(mapper.event(control(PAN, 127)), Some(VoiceEvent::Pan(1.0)));
+        assert_eq!(mapper.event(MidiMessage::ChannelPressure { channel: 0, pressure: 127 }), Some(VoiceEvent::Aftertouch(1.0)));
+        assert_eq!(mapper.event(control(SUSTAIN_PEDAL, 100)), Some(VoiceEvent::Sustain(true)));
+        assert_eq!(mapper.event(control(SUSTAIN_PEDAL, 10)), Some(VoiceEvent::Sustain(false)));
+        assert_eq!(mapper.event(control(ALL_NOTES_OFF, 0)), Some(VoiceEvent::AllNotesOff));
+        assert_eq!(mapper.event(control(7, 100)), None);
+    }
+
+    #[test]
+    fn listens_to_one_channel_when_asked() {
+        let mut mapper = MidiMapper::new(Tuning::default());
+        mapper.set_channel(Some(1));
+        assert_eq!(mapper.event(MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 }), None);
+        assert_eq!(mapper.event(MidiMessage::NoteOff { channel: 1, note: 60, velocity: 0 }), Some(VoiceEvent::NoteOff { note: 60 }));
+    }
+}
@@ -0,0 +1,263 @@
+use std::ffi::CString;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use alsa::poll::Descriptors;
+use alsa::seq::{Addr, ClientIter, EvCtrl, EvNote, EventType, PortCap, PortIter, PortSubscribe, PortType, Seq};
+use alsa::Direction;
+
+use crate::midi::MidiMessage;
+
+/*
+    MIDI input through the ALSA sequencer, which hardware keyboards, USB controllers and software
+    such as virtual keyboards all show up in as clients with ports. The synth opens its own client
+    with one writable port and subscribes it to the port it was asked to listen to. Other programs
+    can also connect to that port themselves, e.g. with `aconnect`.
+
+    Events are read on a separate thread, which waits on the sequencer for at most
+    `POLL_TIMEOUT_MS` at a time so that dropping the `MidiInput` can stop it.
+ */
+
+const CLIENT_NAME: &str = "wavetable_synth";
+const POLL_TIMEOUT_MS: i32 = 100;
+
+#[derive(Debug)]
+pub enum MidiError {
+    Alsa(alsa::Error),
+    /// No readable port matched the given name or address.
+    NoSuchPort(String),
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiError::Alsa(err) => write!(f, "{}", err),
+            MidiError::NoSuchPort(port) => write!(f, "no MIDI port matches '{}'", port),
+        }
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+impl From<alsa::Error> for MidiError {
+    fn from(err: alsa::Error) -> Self {
+        MidiError::Alsa(err)
+    }
+}
+
+/// A sequencer port that MIDI can be read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MidiPort {
+    pub client: i32,
+    pub port: i32,
+    pub client_name: String,
+    pub name: String,
+}
+
+impl fmt::Display for MidiPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}\t{}: {}", self.client, self.port, self.client_name, self.name)
+    }
+}
+
+fn open_sequencer() -> Result<Seq, MidiError> {
+    let seq = Seq::open(None, None, true)?;
+    seq.set_client_name(&CString::new(CLIENT_NAME).unwrap())?;
+    Ok(seq)
+}
+
+fn readable_ports(seq: &Seq) -> Result<Vec<MidiPort>, MidiError> {
+    let own_client = seq.client_id()?;
+    let mut ports = Vec::new();
+    for client in ClientIter::new(seq) {
+        // client 0 is the system timer and announcements
+        if client.get_client() == 0 || client.get_client() == own_client {
+            continue;
+        }
+        for port in PortIter::new(seq, client.get_client()) {
+            let caps = port.get_capability();
+            if !caps.contains(PortCap::READ | PortCap::SUBS_READ) || caps.contains(PortCap::NO_EXPORT) {
+                continue;
+            }
+            ports.push(MidiPort {
+                client: port.get_client(),
+                port: port.get_port(),
+                client_name: client.get_name()?.to_string(),
+                name: port.get_name()?.to_string(),
+            });
+        }
+    }
+    Ok(ports)
+}
+
+/// Every port MIDI input can connect to.
+pub fn list_ports() -> Result<Vec<MidiPort>, MidiError> {
+    readable_ports(&open_sequencer()?)
+}
+
+/// Finds a port by its `client:port` address or by part of its client or port name, ignoring case.
+fn find_port(seq: &Seq, spec: &str) -> Result<MidiPort, MidiError> {
+    let ports = readable_ports(seq)?;
+    let found = match spec.parse::<Addr>() {
+        Ok(addr) => ports.into_iter().find(|port| port.client == addr.client && port.port == addr.port),
+        Err(_) => {
+            let spec = spec.to_lowercase();
+            ports.into_iter().find(|port| {
+                port.client_name.to_lowercase().contains(&spec) || port.name.to_lowercase().contains(&spec)
+            })
+        }
+    };
+    found.ok_or_else(|| MidiError::NoSuchPort(spec.to_string()))
+}
+
+/// An open connection to a MIDI port. Messages are passed to a callback on a separate thread
+/// until the connection is dropped.
+pub struct MidiInput {
+    port: MidiPort,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MidiInput {
+    /// Connects to the port matching `spec`, a `client:port` address or part of a name, and calls
+    /// `callback` with every channel message it sends.
+    pub fn connect<F>(spec: &str, callback: F) -> Result<MidiInput, MidiError>
+    where
+        F: FnMut(MidiMessage) + Send + 'static,
+    {
+        let seq = open_sequencer()?;
+        let port = find_port(&seq, spec)?;
+        let own_port = seq.create_simple_port(
+            &CString::new("input").unwrap(),
+            PortCap::WRITE | PortCap::SUBS_WRITE,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )?;
+        let subscription = PortSubscribe::empty()?;
+        subscription.set_sender(Addr { client: port.client, port: port.port });
+        subscription.set_dest(Addr { client: seq.client_id()?, port: own_port });
+        seq.subscribe_port(&subscription)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                if let Err(err) = read_events(&seq, &running, callback) {
+                    eprintln!("MIDI input stopped: {}", err);
+                }
+            })
+        };
+        Ok(MidiInput { port, running, thread: Some(thread) })
+    }
+
+    /// The port this input is connected to.
+    pub fn port(&self) -> &MidiPort {
+        &self.port
+    }
+}
+
+impl Drop for MidiInput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn read_events<F: FnMut(MidiMessage)>(seq: &Seq, running: &AtomicBool, mut callback: F) -> Result<(), MidiError> {
+    let mut input = seq.input();
+    let mut fds = (seq, Some(Direction::Capture)).get()?;
+    while running.load(Ordering::Relaxed) {
+        alsa::poll::poll(&mut fds, POLL_TIMEOUT_MS)?;
+        while input.event_input_pending(true)? > 0 {
+            let event = input.event_input()?;
+            if let Some(message) = message_from_event(&event) {
+                callback(message);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn message_from_event(event: &alsa::seq::Event) -> Option<MidiMessage> {
+    let message = match event.get_type() {
+        EventType::Noteon => {
+            let EvNote { channel, note, velocity, .. } = event.get_data()?;
+            // the sequencer passes a note-on with velocity 0 through unchanged
+            MidiMessage::parse(&[0x90 | channel & 0x0f, note, velocity])?
+        }
+        EventType::Noteoff => {
+            let EvNote { channel, note, velocity, .. } = event.get_data()?;
+            MidiMessage::NoteOff { channel, note, velocity }
+        }
+        EventType::Controller => {
+            let EvCtrl { channel, param, value } = event.get_data()?;
+            MidiMessage::ControlChange { channel, controller: param as u8, value: value as u8 }
+        }
+        EventType::Chanpress => {
+            let EvCtrl { channel, value, .. } = event.get_data()?;
+            MidiMessage::ChannelPressure { channel, pressure: value as u8 }
+        }
+        EventType::Pitchbend => {
+            let EvCtrl { channel, value, .. } = event.get_data()?;
+            MidiMessage::PitchBend { channel, value: value.clamp(-8192, 8191) as i16 }
+        }
+        _ => return None,
+    };
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // Plays the part of a MIDI keyboard with a virtual port, so no hardware is needed, but the
+    // ALSA sequencer has to be available (the snd-seq module loaded and /dev/snd/seq readable).
+    #[test]
+    #[ignore = "needs the ALSA sequencer"]
+    fn receives_messages_from_a_virtual_port() {
+        let keyboard = Seq::open(None, None, false).unwrap();
+        keyboard.set_client_name(&CString::new("virtual keyboard").unwrap()).unwrap();
+        let keyboard_port = keyboard
+            .create_simple_port(
+                &CString::new("keys").unwrap(),
+                PortCap::READ | PortCap::SUBS_READ,
+                PortType::MIDI_GENERIC | PortType::APPLICATION,
+            )
+            .unwrap();
+        let address = format!("{}:{}", keyboard.client_id().unwrap(), keyboard_port);
+        assert!(list_ports().unwrap().iter().any(|port| port.name == "keys"));
+
+        let (sender, receiver) = mpsc::channel();
+        let input = MidiInput::connect(&address, move |message| sender.send(message).unwrap()).unwrap();
+        assert_eq!(input.port().client_name, "virtual keyboard");
+
+        let send = |mut event: alsa::seq::Event| {
+            event.set_subs();
+            event.set_direct();
+            event.set_source(keyboard_port);
+            keyboard.event_output_direct(&mut event).unwrap();
+        };
+        let note = EvNote { channel: 0, note: 60, velocity: 100, off_velocity: 0, duration: 0 };
+        send(alsa::seq::Event::new(EventType::Noteon, &note));
+        send(alsa::seq::Event::new(EventType::Pitchbend, &EvCtrl { channel: 0, param: 0, value: -8192 }));
+        send(alsa::seq::Event::new(EventType::Controller, &EvCtrl { channel: 0, param: 64, value: 127 }));
+        send(alsa::seq::Event::new(EventType::Noteon, &EvNote { velocity: 0, ..note }));
+
+        let next = || receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(next(), MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 });
+        assert_eq!(next(), MidiMessage::PitchBend { channel: 0, value: -8192 });
+        assert_eq!(next(), MidiMessage::ControlChange { channel: 0, controller: 64, value: 127 });
+        assert_eq!(next(), MidiMessage::NoteOff { channel: 0, note: 60, velocity: 64 });
+    }
+
+    #[test]
+    #[ignore = "needs the ALSA sequencer"]
+    fn unknown_ports_are_an_error() {
+        assert!(matches!(MidiInput::connect("no such port at all", |_| {}), Err(MidiError::NoSuchPort(_))));
+    }
+}
@@ -0,0 +1,221 @@
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::wavetable::{MultiFrameTable, WaveTable};
+
+/*
+    Playing a table faster than its own harmonic content allows makes the upper partials fold back
+    below Nyquist as inharmonic aliasing. To avoid it we keep one copy of the table per octave:
+    level 0 holds every harmonic the table can represent, and each following level keeps half as
+    many. The copies are made by taking the FFT of the cycle, zeroing every bin above the level's
+    harmonic limit and transforming back, so all levels have the same length and phase. Each
+    frame of a multi-frame table gets its own set of levels.
+
+    While playing, the oscillator asks for the two levels around its current pitch and crossfades
+    between them, so sweeping the frequency never produces a sudden jump in brightness.
+ */
+#[derive(Clone, Debug)]
+pub struct MipMappedTable {
+    // levels[level][frame]
+    levels: Vec<Vec<WaveTable>>,
+    top_harmonic: usize,
+}
+
+impl MipMappedTable {
+    /// Builds every octave level of every frame in `table`.
+    pub fn new(table: &MultiFrameTable) -> MipMappedTable {
+        let size = table.frame_len();
+        let top_harmonic = (size / 2).saturating_sub(1).max(1);
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(size);
+        let inverse = planner.plan_fft_inverse(size);
+        let spectra: Vec<Vec<Complex<f32>>> = table
+            .frames()
+            .iter()
+            .map(|frame| {
+                let mut spectrum: Vec<Complex<f32>> = frame.samples().iter().map(|s| Complex::new(*s, 0.0)).collect();
+                forward.process(&mut spectrum);
+                spectrum
+            })
+            .collect();
+
+        let mut levels = Vec::new();
+        let mut limit = top_harmonic;
+        loop {
+            levels.push(
+                spectra
+                    .iter()
+                    .map(|spectrum| WaveTable::from_samples(truncate(spectrum, limit, inverse.as_ref())))
+                    .collect(),
+            );
+            if limit == 1 {
+                break;
+            }
+            limit /= 2;
+        }
+        MipMappedTable { levels, top_harmonic }
+    }
+
+    /// Wraps `table` as a single level without any band limiting.
+    pub fn single(table: MultiFrameTable) -> MipMappedTable {
+        let top_harmonic = (table.frame_len() / 2).saturating_sub(1).max(1);
+        MipMappedTable { levels: vec![table.frames().to_vec()], top_harmonic }
+    }
+
+    /// Samples per frame; every level has the length of the source table.
+    pub fn len(&self) -> usize {
+        self.levels[0][0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels[0][0].is_empty()
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// One frame of one level; `level` is clamped to the darkest level.
+    pub fn frame(&self, level: usize, frame: usize) -> &WaveTable {
+        &self.levels[level.min(self.levels.len() - 1)][frame]
+    }
+
+    /// Highest harmonic kept in `level`.
+    pub fn max_harmonic(&self, level: usize) -> usize {
+        (self.top_harmonic >> level).max(1)
+    }
+
+    /// Picks the pair of levels to crossfade for a note at `frequency`.
+    ///
+    /// Returns the brighter level and how much of the next, darker level to mix in. Both levels
+    /// are always free of harmonics above `sample_rate / 2`.
+    pub fn select(&self, frequency: f32, sample_rate: u32) -> (usize, f32) {
+        if self.levels.len() == 1 || frequency <= 0.0 {
+            return (0, 0.0);
+        }
+        let allowed = sample_rate as f32 / 2.0 / frequency;
+        // Level `l` is safe once its harmonic limit top / 2^l is at most `allowed`; shifting by
+        // one octave keeps the brighter side of the crossfade on the safe side as well.
+        let position = ((self.top_harmonic as f32 / allowed).log2() + 1.0).max(0.0);
+        let last = self.levels.len() - 1;
+        let level = position.floor() as usize;
+        if level >= last {
+            return (last, 0.0);
+        }
+        (level, position.fract())
+    }
+}
+
+impl From<WaveTable> for MipMappedTable {
+    fn from(table: WaveTable) -> Self {
+        MipMappedTable::new(&table.into())
+    }
+}
+
+impl From<MultiFrameTable> for MipMappedTable {
+    fn from(table: MultiFrameTable) -> Self {
+        MipMappedTable::new(&table)
+    }
+}
+
+/// Zeroes every bin above `limit` (and DC) and returns the real part of the inverse transform.
+fn truncate(spectrum: &[Complex<f32>], limit: usize, inverse: &dyn rustfft::Fft<f32>) -> Vec<f32> {
+    let size = spectrum.len();
+    let mut bins: Vec<Complex<f32>> = spectrum
+        .iter()
+        .enumerate()
+        .map(|(k, bin)| {
+            let harmonic = k.min(size - k);
+            if harmonic == 0 || harmonic > limit || 2 * harmonic == size {
+                Complex::new(0.0, 0.0)
+            } else {
+                *bin
+            }
+        })
+        .collect();
+    inverse.process(&mut bins);
+    bins.iter().map(|bin| bin.re / size as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_one_level_per_octave() {
+        let mipmap = MipMappedTable::from(WaveTable::saw(2048));
+        // 1023, 511, 255, …, 1 harmonics.
+        assert_eq!(mipmap.level_count(), 10);
+        assert_eq!(mipmap.len(), 2048);
+        assert_eq!(mipmap.max_harmonic(0), 1023);
+        assert_eq!(mipmap.max_harmonic(9), 1);
+    }
+
+    #[test]
+    fn top_level_is_the_fundamental() {
+        let mipmap = MipMappedTable::from(WaveTable::square(256));
+        let top = mipmap.frame(mipmap.level_count() - 1, 0);
+        let sine = WaveTable::sine(256);
+        let scale = top.samples()[64] / sine.samples()[64];
+        for (a, b) in top.samples().iter().zip(sine.samples()) {
+            assert!((a - b * scale).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn level_zero_keeps_a_band_limited_table_intact() {
+        let saw = WaveTable::saw(512);
+        let mipmap = MipMappedTable::from(saw.clone());
+        for (a, b) in mipmap.frame(0, 0).samples().iter().zip(saw.samples()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn band_limits_every_frame() {
+        let table = MultiFrameTable::new(vec![WaveTable::saw(256), WaveTable::square(256)]);
+        let mipmap = MipMappedTable::new(&table);
+        assert_eq!(mipmap.frame_count(), 2);
+        let last = mipmap.level_count() - 1;
+        // The darkest level of both frames is a single sine partial, so each crosses zero
+        // only twice per cycle.
+        for frame in 0..2 {
+            let samples = mipmap.frame(last, frame).samples();
+            let crossings = (0..samples.len())
+                .filter(|&i| (samples[i] >= 0.0) != (samples[(i + 1) % samples.len()] >= 0.0))
+                .count();
+            assert_eq!(crossings, 2, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn selected_levels_stay_below_nyquist() {
+        let mipmap = MipMappedTable::from(WaveTable::saw(2048));
+        for frequency in [27.5, 110.0, 440.0, 1000.0, 3520.0, 8000.0] {
+            let (level, _) = mipmap.select(frequency, 44100);
+            assert!(mipmap.max_harmonic(level) as f32 * frequency <= 22050.0, "{} Hz", frequency);
+        }
+        assert_eq!(mipmap.select(5.0, 44100), (0, 0.0));
+    }
+
+    #[test]
+    fn crossfade_is_continuous_across_pitch() {
+        let mipmap = MipMappedTable::from(WaveTable::saw(2048));
+        let position = |f: f32| {
+            let (level, mix) = mipmap.select(f, 44100);
+            level as f32 + mix
+        };
+        let mut previous = position(100.0);
+        let mut f = 100.0;
+        while f < 10000.0 {
+            f *= 1.01;
+            let current = position(f);
+            assert!(current >= previous && current - previous < 0.05);
+            previous = current;
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::lfo::{LfoSettings, DEFAULT_TEMPO};
+
+/*
+    The modulation matrix is a list of routes, each taking one source, scaling it by its depth and
+    adding it to one destination. Several routes may share a source or a destination; routes to
+    the same destination add up.
+
+    Sources are per voice except for the mod wheel and aftertouch, which every voice shares:
+
+    - `lfo1`, `lfo2`, ... run from -1 to 1 (see `lfo`);
+    - `env` and `filter-env` are the voice's amplitude and filter envelopes, from 0 to 1;
+    - `velocity`, `mod-wheel` and `aftertouch` run from 0 to 1;
+    - `key` is the note's distance from middle C in octaves, negative below it.
+
+    Depths are in the destination's own unit: semitones for `pitch`, the whole table for
+    `position`, octaves for `cutoff`, and full scale for `amplitude` (a depth of 1 can double or
+    silence the voice) and `pan` (a depth of 1 moves it from the centre to one side).
+
+    Without any routes of its own, the matrix sends the mod wheel through the wave table.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModSource {
+    /// One of the matrix's LFOs, counting from 0.
+    Lfo(usize),
+    Envelope,
+    FilterEnvelope,
+    Velocity,
+    ModWheel,
+    Aftertouch,
+    Key,
+}
+
+impl FromStr for ModSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        if let Some(number) = name.strip_prefix("lfo") {
+            return match number.parse::<usize>() {
+                Ok(number) if number > 0 => Ok(ModSource::Lfo(number - 1)),
+                _ => Err(format!("bad LFO '{}' (they count from lfo1)", s)),
+            };
+        }
+        match name.as_str() {
+            "env" | "envelope" | "amp-env" => Ok(ModSource::Envelope),
+            "filter-env" | "filter-envelope" => Ok(ModSource::FilterEnvelope),
+            "velocity" | "vel" => Ok(ModSource::Velocity),
+            "mod-wheel" | "modwheel" | "wheel" => Ok(ModSource::ModWheel),
+            "aftertouch" | "pressure" => Ok(ModSource::Aftertouch),
+            "key" | "note" => Ok(ModSource::Key),
+            _ => Err(format!(
+                "unknown modulation source '{}' (expected lfo1, lfo2, ..., env, filter-env, velocity, mod-wheel, aftertouch or key)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ModSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModSource::Lfo(index) => write!(f, "lfo{}", index + 1),
+            ModSource::Envelope => write!(f, "env"),
+            ModSource::FilterEnvelope => write!(f, "filter-env"),
+            ModSource::Velocity => write!(f, "velocity"),
+            ModSource::ModWheel => write!(f, "mod-wheel"),
+            ModSource::Aftertouch => write!(f, "aftertouch"),
+            ModSource::Key => write!(f, "key"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModDestination {
+    Pitch,
+    Position,
+    Cutoff,
+    Amplitude,
+    Pan,
+}
+
+impl FromStr for ModDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pitch" => Ok(ModDestination::Pitch),
+            "position" | "pos" => Ok(ModDestination::Position),
+            "cutoff" => Ok(ModDestination::Cutoff),
+            "amplitude" | "amp" => Ok(ModDestination::Amplitude),
+            "pan" => Ok(ModDestination::Pan),
+            _ => Err(format!("unknown modulation destination '{}' (expected pitch, position, cutoff, amplitude or pan)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ModDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ModDestination::Pitch => "pitch",
+            ModDestination::Position => "position",
+            ModDestination::Cutoff => "cutoff",
+            ModDestination::Amplitude => "amplitude",
+            ModDestination::Pan => "pan",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Adds `source`, scaled by `depth`, to `destination`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Route {
+    pub source: ModSource,
+    pub destination: ModDestination,
+    pub depth: f32,
+}
+
+impl Route {
+    pub fn new(source: ModSource, destination: ModDestination, depth: f32) -> Route {
+        Route { source, destination, depth }
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Reads `source:destination:depth`, e.g. `lfo1:pitch:0.5` or `velocity:cutoff:-2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let [source, destination, depth] = parts[..] else {
+            return Err(format!("'{}' is not a route (expected source:destination:depth)", s));
+        };
+        let depth: f32 = depth.parse().ok().filter(|depth: &f32| depth.is_finite()).ok_or_else(|| format!("bad depth '{}'", depth))?;
+        Ok(Route::new(source.parse()?, destination.parse()?, depth))
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.destination, self.depth)
+    }
+}
+
+/// The current value of every source a voice can read.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SourceValues<'a> {
+    pub lfos: &'a [f32],
+    pub envelope: f32,
+    pub filter_envelope: f32,
+    pub velocity: f32,
+    pub mod_wheel: f32,
+    pub aftertouch: f32,
+    pub key: f32,
+}
+
+impl SourceValues<'_> {
+    pub fn get(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::Lfo(index) => self.lfos.get(index).copied().unwrap_or(0.0),
+            ModSource::Envelope => self.envelope,
+            ModSource::FilterEnvelope => self.filter_envelope,
+            ModSource::Velocity => self.velocity,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::Aftertouch => self.aftertouch,
+            ModSource::Key => self.key,
+        }
+    }
+}
+
+/// What the routes add to each destination, in the destinations' units.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Modulation {
+    pub pitch: f32,
+    pub position: f32,
+    pub cutoff: f32,
+    pub amplitude: f32,
+    pub pan: f32,
+}
+
+impl Modulation {
+    /// Frequency ratio of the pitch offset.
+    pub fn pitch_ratio(&self) -> f32 {
+        2f32.powf(self.pitch / 12.0)
+    }
+
+    /// Factor to apply to the voice's level, never below 0.
+    pub fn gain(&self) -> f32 {
+        (1.0 + self.amplitude).max(0.0)
+    }
+
+    /// Factor to apply to the cutoff.
+    pub fn cutoff_ratio(&self) -> f32 {
+        2f32.powf(self.cutoff)
+    }
+}
+
+/// LFO settings and the routes between sources and destinations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModMatrix {
+    lfos: Vec<LfoSettings>,
+    routes: Vec<Route>,
+    tempo: f32,
+}
+
+impl ModMatrix {
+    /// Fails if a route reads an LFO that is not in `lfos`. `tempo` is in beats per minute, for
+    /// the LFOs synced to it.
+    pub fn new(lfos: Vec<LfoSettings>, routes: Vec<Route>, tempo: f32) -> Result<ModMatrix, String> {
+        if let Some(route) = routes.iter().find(|route| matches!(route.source, ModSource::Lfo(index) if index >= lfos.len())) {
+            return Err(format!("route '{}' reads {}, which has not been set up", route, route.source));
+        }
+        Ok(ModMatrix { lfos, routes, tempo })
+    }
+
+    pub fn lfos(&self) -> &[LfoSettings] {
+        &self.lfos
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo;
+    }
+
+    /// Whether any route goes to `destination`.
+    pub fn modulates(&self, destination: ModDestination) -> bool {
+        self.routes.iter().any(|route| route.destination == destination)
+    }
+
+    /// Sums every route's contribution for one voice.
+    pub fn evaluate(&self, values: &SourceValues) -> Modulation {
+        let mut modulation = Modulation::default();
+        for route in &self.routes {
+            let amount = values.get(route.source) * route.depth;
+            match route.destination {
+                ModDestination::Pitch => modulation.pitch += amount,
+                ModDestination::Position => modulation.position += amount,
+                ModDestination::Cutoff => modulation.cutoff += amount,
+                ModDestination::Amplitude => modulation.amplitude += amount,
+                ModDestination::Pan => modulation.pan += amount,
+            }
+        }
+        modulation
+    }
+}
+
+impl Default for ModMatrix {
+    /// No LFOs, and the mod wheel sweeping the whole wave table.
+    fn default() -> Self {
+        ModMatrix {
+            lfos: Vec::new(),
+            routes: vec![Route::new(ModSource::ModWheel, ModDestination::Position, 1.0)],
+            tempo: DEFAULT_TEMPO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_routes() {
+        assert_eq!("lfo2:pitch:0.5".parse(), Ok(Route::new(ModSource::Lfo(1), ModDestination::Pitch, 0.5)));
+        assert_eq!("velocity:cutoff:-2".parse(), Ok(Route::new(ModSource::Velocity, ModDestination::Cutoff, -2.0)));
+        assert_eq!("Mod-Wheel:pos:1".parse(), Ok(Route::new(ModSource::ModWheel, ModDestination::Position, 1.0)));
+        assert!("lfo0:pitch:1".parse::<Route>().is_err());
+        assert!("key:volume:1".parse::<Route>().is_err());
+        assert!("key:pitch".parse::<Route>().is_err());
+        assert!("key:pitch:x".parse::<Route>().is_err());
+        let route = Route::new(ModSource::FilterEnvelope, ModDestination::Pan, -0.25);
+        assert_eq!(route.to_string().parse(), Ok(route));
+    }
+
+    #[test]
+    fn rejects_routes_from_missing_lfos() {
+        let route = Route::new(ModSource::Lfo(1), ModDestination::Pitch, 1.0);
+        assert!(ModMatrix::new(vec![LfoSettings::default()], vec![route], DEFAULT_TEMPO).is_err());
+        assert!(ModMatrix::new(vec![LfoSettings::default(); 2], vec![route], DEFAULT_TEMPO).is_ok());
+    }
+
+    #[test]
+    fn adds_up_routes_to_each_destination() {
+        let routes = vec![
+            Route::new(ModSource::Lfo(0), ModDestination::Pitch, 2.0),
+            Route::new(ModSource::Key, ModDestination::Pitch, 12.0),
+            Route::new(ModSource::Velocity, ModDestination::Amplitude, -1.0),
+            Route::new(ModSource::Aftertouch, ModDestination::Cutoff, 3.0),
+        ];
+        let matrix = ModMatrix::new(vec![LfoSettings::default()], routes, DEFAULT_TEMPO).unwrap();
+        assert!(matrix.modulates(ModDestination::Pitch) && !matrix.modulates(ModDestination::Pan));
+        let values = SourceValues { lfos: &[-0.5], velocity: 0.25, aftertouch: 0.5, key: 1.0, ..Default::default() };
+        let modulation = matrix.evaluate(&values);
+        assert_eq!(modulation, Modulation { pitch: 11.0, cutoff: 1.5, amplitude: -0.25, ..Default::default() });
+        assert_eq!(modulation.gain(), 0.75);
+        assert!((modulation.pitch_ratio() - 2f32.powf(11.0 / 12.0)).abs() < 1e-6);
+
+        // a deep enough route silences the voice instead of inverting it
+        assert_eq!(Modulation { amplitude: -3.0, ..Default::default() }.gain(), 0.0);
+    }
+
+    #[test]
+    fn mod_wheel_sweeps_the_table_by_default() {
+        let matrix = ModMatrix::default();
+        let modulation = matrix.evaluate(&SourceValues { mod_wheel: 0.75, ..Default::default() });
+        assert_eq!(modulation.position, 0.75);
+    }
+}
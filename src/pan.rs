@@ -0,0 +1,99 @@
+use std::f32::consts::FRAC_PI_4;
+use std::fmt;
+use std::str::FromStr;
+
+/*
+    Panning splits a signal between the left and right channels, and a pan law decides how the
+    two gains move as the signal goes from one side to the other. Both laws here have unity gain
+    in the channel a signal is panned hard to, so panning never makes anything louder than it was.
+
+    With the equal-power law the gains follow a quarter of a circle, cos and sin of an angle from
+    0 (left) to 90° (right), so the sum of their squares, and with it the loudness of an
+    uncorrelated signal, stays the same wherever the signal sits; the centre is 3 dB down in each
+    channel. The linear law keeps the sum of the gains constant instead, which leaves the centre
+    6 dB down and suits signals that are summed back to mono.
+ */
+
+/// How a signal's gains in the two channels follow its pan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanLaw {
+    /// Constant power: -3 dB in each channel at the centre.
+    #[default]
+    EqualPower,
+    /// Constant amplitude: -6 dB in each channel at the centre.
+    Linear,
+}
+
+impl PanLaw {
+    /// Left and right gains placing a signal at `pan`, from -1 (left) to 1 (right).
+    pub fn gains(self, pan: f32) -> [f32; 2] {
+        let pan = pan.clamp(-1.0, 1.0);
+        match self {
+            PanLaw::EqualPower => {
+                let angle = (pan + 1.0) * FRAC_PI_4;
+                [angle.cos(), angle.sin()]
+            }
+            PanLaw::Linear => [(1.0 - pan) / 2.0, (1.0 + pan) / 2.0],
+        }
+    }
+}
+
+impl FromStr for PanLaw {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "equal-power" | "equal_power" | "power" => Ok(PanLaw::EqualPower),
+            "linear" => Ok(PanLaw::Linear),
+            _ => Err(format!("unknown pan law '{}' (expected equal-power or linear)", s)),
+        }
+    }
+}
+
+impl fmt::Display for PanLaw {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PanLaw::EqualPower => write!(f, "equal-power"),
+            PanLaw::Linear => write!(f, "linear"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_power_keeps_the_power_wherever_the_signal_sits() {
+        let law = PanLaw::EqualPower;
+        assert_eq!(law.gains(-1.0), [1.0, 0.0]);
+        let [left, right] = law.gains(7.0);
+        assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+        for pan in [-0.8, -0.3, 0.0, 0.01, 0.5, 0.9] {
+            let [left, right] = law.gains(pan);
+            assert!((left * left + right * right - 1.0).abs() < 1e-6, "{}", pan);
+            assert!(left <= 1.0 && right <= 1.0);
+        }
+        let [left, right] = law.gains(0.0);
+        assert!((left - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6 && left == right);
+    }
+
+    #[test]
+    fn linear_keeps_the_sum_of_the_gains() {
+        let law = PanLaw::Linear;
+        assert_eq!(law.gains(0.0), [0.5, 0.5]);
+        assert_eq!(law.gains(-1.0), [1.0, 0.0]);
+        for pan in [-0.7, 0.2, 0.6] {
+            let [left, right] = law.gains(pan);
+            assert!((left + right - 1.0).abs() < 1e-6 && (left > right) == (pan < 0.0));
+        }
+    }
+
+    #[test]
+    fn parses_law_names() {
+        assert_eq!("Equal-Power".parse(), Ok(PanLaw::EqualPower));
+        assert_eq!("linear".parse(), Ok(PanLaw::Linear));
+        assert!("sine".parse::<PanLaw>().is_err());
+        assert_eq!(PanLaw::default().to_string().parse(), Ok(PanLaw::EqualPower));
+    }
+}
@@ -0,0 +1,331 @@
+use std::time::Duration;
+
+use rodio::cpal::traits::HostTrait;
+use rodio::{cpal, DeviceTrait, Source};
+
+use crate::midi::MidiMapper;
+use crate::scheduler::Scheduler;
+use crate::score::Score;
+use crate::smf::MidiFile;
+use crate::source::frames_in;
+use crate::tuning::Tuning;
+use crate::voice::{VoiceEvent, VoiceManager, CHANNELS};
+
+/// Sample rate used when there is no output device to ask, e.g. for renders.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Sample rate of the default output device's default config, which is the rate rodio mixes at.
+///
+/// Running the voices at this rate spares rodio from resampling them.
+pub fn output_sample_rate() -> Option<u32> {
+    let device = cpal::default_host().default_output_device()?;
+    device.default_output_config().ok().map(|config| config.sample_rate().0)
+}
+
+/// Note-on and note-off events playing `notes` one after another, each held for `note_length`.
+/// Notes the tuning leaves out are rests.
+pub fn note_events(notes: &[u8], tuning: &Tuning, note_length: Duration) -> Vec<(Duration, VoiceEvent)> {
+    let mut events = Vec::new();
+    for (index, &note) in notes.iter().enumerate() {
+        let start = note_length * index as u32;
+        if let Some(frequency) = tuning.frequency(note) {
+            events.push((start, VoiceEvent::NoteOn { note, frequency, velocity: 1.0 }));
+            events.push((start + note_length, VoiceEvent::NoteOff { note }));
+        }
+    }
+    events
+}
+
+/// Voice events for every channel message in a MIDI file, at the times its tempo map gives them,
+/// and for every change of tempo.
+pub fn midi_file_events(file: &MidiFile, mapper: &MidiMapper) -> Vec<(Duration, VoiceEvent)> {
+    let mut events: Vec<(Duration, VoiceEvent)> =
+        file.tempo_map().into_iter().map(|(time, tempo)| (time, VoiceEvent::Tempo(tempo))).collect();
+    events.extend(file.timeline().into_iter().filter_map(|(time, message)| Some((time, mapper.event(message)?))));
+    // tempo changes come before the notes at the same time, as the sort is stable
+    events.sort_by_key(|(time, _)| *time);
+    events
+}
+
+/// Note-on and note-off events for every note of a score, and its tempo changes. Notes the tuning
+/// leaves out are skipped.
+pub fn score_events(score: &Score, tuning: &Tuning) -> Vec<(Duration, VoiceEvent)> {
+    let mut events: Vec<(Duration, VoiceEvent)> =
+        score.tempos.iter().map(|&(time, tempo)| (time, VoiceEvent::Tempo(tempo as f32))).collect();
+    for note in &score.notes {
+        if let Some(frequency) = tuning.frequency(note.note) {
+            events.push((note.start, VoiceEvent::NoteOn { note: note.note, frequency, velocity: 1.0 }));
+            events.push((note.start + note.length, VoiceEvent::NoteOff { note: note.note }));
+        }
+    }
+    // a note ends before one of the same pitch starts on the same frame, as the sort is stable
+    events.sort_by_key(|(time, _)| *time);
+    events
+}
+
+/// Plays timed voice events on a [`VoiceManager`] as interleaved stereo, taking each one from a
+/// [`Scheduler`] on the frame it falls on.
+///
+/// Once the last event has been applied, and the sequence's end has been reached, every note is
+/// released, and the source ends when the voices have gone quiet.
+pub struct SequenceSource {
+    voices: VoiceManager,
+    scheduler: Scheduler,
+    frame: u64,
+    end_frame: u64,
+    // The frame being played and the channel given out next.
+    samples: [f32; 2],
+    channel: usize,
+}
+
+impl SequenceSource {
+    /// Events at the same time are applied in the order they are given.
+    pub fn new(voices: VoiceManager, events: Vec<(Duration, VoiceEvent)>) -> SequenceSource {
+        let scheduler = Scheduler::from_events(voices.sample_rate(), events);
+        SequenceSource::with_scheduler(voices, scheduler)
+    }
+
+    pub fn with_scheduler(voices: VoiceManager, scheduler: Scheduler) -> SequenceSource {
+        let end_frame = scheduler.last_frame().unwrap_or(0);
+        SequenceSource { voices, scheduler, frame: 0, end_frame, samples: [0.0; 2], channel: 0 }
+    }
+
+    /// Plays a MIDI file, turning its messages into voice events with `mapper`. Notes still held
+    /// at the file's last event are released there.
+    pub fn from_midi_file(voices: VoiceManager, file: &MidiFile, mapper: &MidiMapper) -> SequenceSource {
+        SequenceSource::new(voices, midi_file_events(file, mapper)).with_end(file.duration())
+    }
+
+    /// Plays a score, keeping time for a trailing rest.
+    pub fn from_score(voices: VoiceManager, score: &Score, tuning: &Tuning) -> SequenceSource {
+        SequenceSource::new(voices, score_events(score, tuning)).with_end(score.length)
+    }
+
+    /// Keeps the notes held until at least `end`, even if the last event comes earlier.
+    pub fn with_end(mut self, end: Duration) -> Self {
+        self.end_frame = self.end_frame.max(frames_in(end, self.voices.sample_rate()));
+        self
+    }
+
+    /// Frames played so far.
+    pub fn position(&self) -> u64 {
+        self.frame
+    }
+}
+
+impl Iterator for SequenceSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.channel == 0 {
+            while let Some(event) = self.scheduler.next_due(self.frame) {
+                self.voices.handle(event);
+            }
+            if self.scheduler.is_empty() && self.frame >= self.end_frame {
+                if self.frame == self.end_frame {
+                    self.voices.set_sustain(false);
+                    self.voices.all_notes_off();
+                }
+                if self.voices.active_voices() == 0 {
+                    return None;
+                }
+            }
+            self.frame += 1;
+            self.samples = self.voices.render_frame();
+        }
+        let sample = self.samples[self.channel];
+        self.channel = (self.channel + 1) % CHANNELS as usize;
+        Some(sample)
+    }
+}
+
+impl Source for SequenceSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.voices.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use crate::envelope::{Adsr, EnvelopeCurve};
+    use crate::mipmap::MipMappedTable;
+    use crate::voice::StealPolicy;
+    use crate::wav::{write_wav, SampleFormat, WavFile};
+    use crate::wavetable::{WaveTable, DEFAULT_TABLE_SIZE};
+
+    // The left channel of interleaved frames, checking that every note was rendered in the centre.
+    fn mono(samples: impl IntoIterator<Item = f32>) -> Vec<f32> {
+        let samples: Vec<f32> = samples.into_iter().collect();
+        assert_eq!(samples.len() % CHANNELS as usize, 0);
+        samples
+            .chunks(CHANNELS as usize)
+            .map(|frame| {
+                assert_eq!(frame[0], frame[1]);
+                frame[0]
+            })
+            .collect()
+    }
+
+    // Four notes through the same sequence `render` plays, cut or padded to half a second.
+    fn arpeggio() -> Vec<f32> {
+        let table = MipMappedTable::from(WaveTable::saw(DEFAULT_TABLE_SIZE));
+        let mut voices = VoiceManager::new(22050, table, 4, StealPolicy::Oldest);
+        voices.set_envelope(Adsr::new(0.01, 0.05, 0.6, 0.1, EnvelopeCurve::Exponential));
+        let notes = [60, 64, 67, 72];
+        let note_length = Duration::from_millis(100);
+        let events = note_events(&notes, &Tuning::default(), note_length);
+        let mut samples: Vec<f32> = SequenceSource::new(voices, events).with_end(note_length * notes.len() as u32).collect();
+        samples.resize(frames_in(Duration::from_millis(500), 22050) as usize * CHANNELS as usize, 0.0);
+        mono(samples)
+    }
+
+    #[test]
+    fn renders_the_requested_length() {
+        let samples = arpeggio();
+        assert_eq!(samples.len(), 11025);
+        // every note sounds, and the last one has faded out by the end
+        for note in 0..4 {
+            let start = note * 2205;
+            assert!(samples[start + 200..start + 2205].iter().any(|sample| sample.abs() > 0.1), "note {}", note);
+        }
+        assert!(samples[11000..].iter().all(|sample| sample.abs() < 0.01));
+    }
+
+    // Regenerate with UPDATE_GOLDEN=1 cargo test after a deliberate change to the sound.
+    #[test]
+    fn render_matches_the_golden_file() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/arpeggio.wav");
+        let mut rendered = Vec::new();
+        write_wav(&mut rendered, &arpeggio(), 1, 22050, SampleFormat::Int16).unwrap();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, &rendered).unwrap();
+        }
+        let golden = WavFile::open(&path).expect("golden file missing; run with UPDATE_GOLDEN=1");
+        let rendered = WavFile::read(Cursor::new(rendered)).unwrap();
+        assert_eq!(rendered.samples.len(), golden.samples.len());
+        // allow a step or two of 16-bit rounding for floating point differences between platforms
+        for (index, (a, b)) in rendered.samples.iter().zip(&golden.samples).enumerate() {
+            assert!((a - b).abs() <= 2.0 / 32768.0, "sample {}: {} vs {}", index, a, b);
+        }
+    }
+
+    fn sine_voices(sample_rate: u32) -> VoiceManager {
+        let mut voices = VoiceManager::new(sample_rate, MipMappedTable::from(WaveTable::sine(DEFAULT_TABLE_SIZE)), 4, StealPolicy::Oldest);
+        voices.set_gain(1.0);
+        voices.set_envelope(Adsr::new(0.0, 0.0, 1.0, 0.01, EnvelopeCurve::Linear));
+        voices
+    }
+
+    #[test]
+    fn sequences_start_on_their_exact_frame() {
+        let events = vec![
+            (Duration::from_millis(500), VoiceEvent::NoteOn { note: 69, frequency: 1000.0, velocity: 1.0 }),
+            (Duration::from_millis(750), VoiceEvent::NoteOff { note: 69 }),
+        ];
+        let samples = mono(SequenceSource::new(sine_voices(8000), events));
+        // silent until frame 4000, which starts the note at phase 0, then a 10 ms release after 6000
+        assert!(samples[..4000].iter().all(|sample| *sample == 0.0));
+        assert!(samples[4000].abs() < 1e-6);
+        assert!(samples[4001].abs() > 0.4);
+        assert!(samples.len() > 6000 && samples.len() <= 6081, "{} samples", samples.len());
+    }
+
+    #[test]
+    fn parameter_changes_land_on_their_frame() {
+        let note = (Duration::ZERO, VoiceEvent::NoteOn { note: 69, frequency: 1000.0, velocity: 1.0 });
+        let plain = mono(SequenceSource::new(sine_voices(8000), vec![note]).with_end(Duration::from_millis(50)).take(800));
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(0, note.1);
+        scheduler.schedule(123, VoiceEvent::PitchBend(12.0));
+        let bent = mono(SequenceSource::with_scheduler(sine_voices(8000), scheduler).with_end(Duration::from_millis(50)).take(800));
+        // the bend changes the step to the next sample from frame 123 on, and not before
+        assert_eq!(plain[..=123], bent[..=123]);
+        assert!((plain[124] - bent[124]).abs() > 0.1);
+    }
+
+    #[test]
+    fn plays_midi_files_through_the_mapper() {
+        use crate::smf::{Division, TrackEvent, TrackEventKind};
+        let event = |tick, kind| TrackEvent { tick, kind };
+        // 1000 ticks per quarter note at 120 bpm: a tick is half a millisecond
+        let file = MidiFile {
+            format: 0,
+            division: Division::TicksPerQuarter(1000),
+            tracks: vec![vec![
+                event(0, TrackEventKind::Channel(vec![0xb0, 64, 127])),
+                event(0, TrackEventKind::Channel(vec![0x90, 69, 127])),
+                event(200, TrackEventKind::Channel(vec![0x80, 69, 0])),
+                event(400, TrackEventKind::Meta(0x01, b"end".to_vec())),
+            ]],
+        };
+        let mapper = MidiMapper::new(Tuning::default());
+        let events = midi_file_events(&file, &mapper);
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], (Duration::ZERO, VoiceEvent::Tempo(120.0)));
+        assert_eq!(events[3], (Duration::from_millis(100), VoiceEvent::NoteOff { note: 69 }));
+
+        // the pedal holds the note past its note-off until the last event at 200 ms
+        let mut source = SequenceSource::from_midi_file(sine_voices(8000), &file, &mapper);
+        let samples = mono(source.by_ref());
+        assert!(samples[1400..1600].iter().any(|sample| sample.abs() > 0.5));
+        assert!(samples.len() > 1600 && samples.len() <= 1681, "{} samples", samples.len());
+        assert_eq!(source.position(), samples.len() as u64);
+    }
+
+    #[test]
+    fn plays_scores_with_repeated_notes() {
+        // two eighth notes of the same pitch at 120 bpm: 250 ms each, then a 250 ms rest
+        let score = Score::parse("A4/8 A4 r").unwrap();
+        let events = score_events(&score, &Tuning::default());
+        let times: Vec<u64> = events.iter().map(|(time, _)| time.as_millis() as u64).collect();
+        assert_eq!(times, vec![0, 0, 250, 250, 500]);
+        assert_eq!(events[0].1, VoiceEvent::Tempo(120.0));
+        assert_eq!(events[2].1, VoiceEvent::NoteOff { note: 69 });
+        assert!(matches!(events[3].1, VoiceEvent::NoteOn { note: 69, .. }));
+
+        // the rest keeps the sequence going after the last release has finished
+        let samples = mono(SequenceSource::from_score(sine_voices(8000), &score, &Tuning::default()));
+        assert_eq!(samples.len(), 6000);
+        assert!(samples[4100..6000].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn synced_lfos_follow_the_tempo_of_the_score() {
+        use crate::lfo::{LfoRate, LfoSettings, LfoShape, DEFAULT_TEMPO};
+        use crate::modulation::{ModDestination, ModMatrix, ModSource, Route};
+        use crate::wavetable::Waveform;
+
+        // a sine LFO once a beat pans each note right for the first half beat, then left
+        let mut voices = sine_voices(8000);
+        let lfo = LfoSettings::new(LfoShape::Wave(Waveform::Sine), LfoRate::Beats(1.0), 0.0, 0.0);
+        let route = Route::new(ModSource::Lfo(0), ModDestination::Pan, 1.0);
+        voices.set_modulation(ModMatrix::new(vec![lfo], vec![route], DEFAULT_TEMPO).unwrap());
+        let score = Score::parse("t=240 A4/1 t=60 A4/1").unwrap();
+        let samples: Vec<f32> = SequenceSource::from_score(voices, &score, &Tuning::default()).collect();
+        // whether the right channel is louder over `start..end` ms
+        let right = |start: usize, end: usize| {
+            let frames = &samples[start * 16..end * 16];
+            let peak = |channel: usize| frames.iter().skip(channel).step_by(2).fold(0.0f32, |peak, s| peak.max(s.abs()));
+            peak(1) > peak(0)
+        };
+        // a beat is 250 ms for the first note and a second for the other, not the 500 ms of --tempo
+        assert!(right(20, 100) && !right(150, 230));
+        assert!(right(1020, 1480) && !right(1520, 1980));
+    }
+}
@@ -0,0 +1,365 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+    Scala is the de facto file format for microtonal tunings. A scale file (.scl) lists the pitches
+    of one period of the scale, in cents or as ratios, measured from the unison; the last entry is
+    the period itself, usually the octave 2/1. A keyboard mapping file (.kbm) says which note number
+    plays which scale degree and pins one note to a reference frequency.
+
+    Both formats are line based and ignore lines starting with '!'. See
+    https://www.huygens-fokker.org/scala/scl_format.html for the full description.
+ */
+
+#[derive(Debug)]
+pub enum ScalaError {
+    Io(io::Error),
+    /// A line that could not be read, with its 1-based line number.
+    Parse { line: usize, message: String },
+    /// The file ended before all required fields were read.
+    UnexpectedEnd(&'static str),
+}
+
+impl fmt::Display for ScalaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScalaError::Io(err) => write!(f, "{}", err),
+            ScalaError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            ScalaError::UnexpectedEnd(what) => write!(f, "file ends before {}", what),
+        }
+    }
+}
+
+impl std::error::Error for ScalaError {}
+
+impl From<io::Error> for ScalaError {
+    fn from(err: io::Error) -> Self {
+        ScalaError::Io(err)
+    }
+}
+
+/// Non-comment lines of a Scala file with their line numbers.
+fn content_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.starts_with('!'))
+        .map(|(index, line)| (index + 1, line))
+}
+
+fn parse_error(line: usize, message: impl Into<String>) -> ScalaError {
+    ScalaError::Parse { line, message: message.into() }
+}
+
+/// First whitespace separated word of a line; anything after it is a comment.
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+/// One period of a scale as frequency ratios above the unison, ending with the period.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    ratios: Vec<f64>,
+}
+
+impl Scale {
+    pub fn new(description: impl Into<String>, ratios: Vec<f64>) -> Scale {
+        assert!(!ratios.is_empty(), "a scale needs at least its period");
+        Scale { description: description.into(), ratios }
+    }
+
+    /// `divisions` equal steps per octave.
+    pub fn equal_temperament(divisions: usize) -> Scale {
+        let ratios = (1..=divisions).map(|step| 2f64.powf(step as f64 / divisions as f64)).collect();
+        Scale::new(format!("{} equal divisions of the octave", divisions), ratios)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Scale, ScalaError> {
+        Scale::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Scale, ScalaError> {
+        let mut lines = content_lines(text);
+        let (_, description) = lines.next().ok_or(ScalaError::UnexpectedEnd("the description"))?;
+        let (line, count) = lines.next().ok_or(ScalaError::UnexpectedEnd("the note count"))?;
+        let count: usize = first_word(count)
+            .parse()
+            .map_err(|_| parse_error(line, format!("'{}' is not a note count", count.trim())))?;
+        if count == 0 {
+            return Err(parse_error(line, "a scale needs at least one note"));
+        }
+
+        let mut ratios = Vec::with_capacity(count);
+        for (line, text) in lines.take(count) {
+            ratios.push(parse_pitch(first_word(text)).ok_or_else(|| {
+                parse_error(line, format!("'{}' is not a pitch in cents or a ratio", text.trim()))
+            })?);
+        }
+        if ratios.len() < count {
+            return Err(ScalaError::UnexpectedEnd("all notes were listed"));
+        }
+        Ok(Scale::new(description.trim(), ratios))
+    }
+
+    /// Notes per period, counting the period itself.
+    pub fn len(&self) -> usize {
+        self.ratios.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ratios.is_empty()
+    }
+
+    pub fn period(&self) -> f64 {
+        self.ratios[self.ratios.len() - 1]
+    }
+
+    /// Ratio of scale degree `degree` above degree 0, stepping through periods as needed.
+    pub fn ratio(&self, degree: i32) -> f64 {
+        let len = self.ratios.len() as i32;
+        let periods = degree.div_euclid(len);
+        let step = degree.rem_euclid(len);
+        let within = if step == 0 { 1.0 } else { self.ratios[step as usize - 1] };
+        within * self.period().powi(periods)
+    }
+}
+
+/// Cents contain a period (`386.3137`), ratios do not (`5/4`, `2`).
+fn parse_pitch(word: &str) -> Option<f64> {
+    if word.contains('.') {
+        let cents: f64 = word.parse().ok()?;
+        return Some(2f64.powf(cents / 1200.0));
+    }
+    let ratio = match word.split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator: f64 = numerator.parse::<u64>().ok()? as f64;
+            let denominator: f64 = denominator.parse::<u64>().ok()? as f64;
+            numerator / denominator
+        }
+        None => word.parse::<u64>().ok()? as f64,
+    };
+    (ratio > 0.0 && ratio.is_finite()).then_some(ratio)
+}
+
+/// Which key plays which scale degree, and the frequency of one reference key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    /// Key that plays scale degree 0.
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_frequency: f64,
+    /// Scale degree that counts as the formal octave when the pattern repeats.
+    pub octave_degree: usize,
+    /// Scale degree per key in one repeat of the pattern; `None` keys are silent. An empty
+    /// mapping plays consecutive degrees on consecutive keys.
+    pub mapping: Vec<Option<usize>>,
+}
+
+impl KeyboardMapping {
+    /// Consecutive keys play consecutive degrees, degree 0 on `middle_note`.
+    pub fn linear(middle_note: u8, reference_note: u8, reference_frequency: f64) -> KeyboardMapping {
+        KeyboardMapping {
+            first_note: 0,
+            last_note: 127,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KeyboardMapping, ScalaError> {
+        KeyboardMapping::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<KeyboardMapping, ScalaError> {
+        let mut lines = content_lines(text).filter(|(_, line)| !line.trim().is_empty());
+        let mut field = |what: &'static str| lines.next().map(|(line, text)| (line, first_word(text))).ok_or(ScalaError::UnexpectedEnd(what));
+
+        let map_size: usize = parse_field(field("the map size")?)?;
+        let first_note = parse_note(field("the first note")?)?;
+        let last_note = parse_note(field("the last note")?)?;
+        let middle_note = parse_note(field("the middle note")?)?;
+        let reference_note = parse_note(field("the reference note")?)?;
+        let (line, word) = field("the reference frequency")?;
+        let reference_frequency: f64 = word
+            .parse()
+            .ok()
+            .filter(|frequency: &f64| *frequency > 0.0)
+            .ok_or_else(|| parse_error(line, format!("'{}' is not a frequency", word)))?;
+        let octave_degree: usize = parse_field(field("the octave degree")?)?;
+
+        let mut mapping = Vec::with_capacity(map_size);
+        for _ in 0..map_size {
+            // Scala allows the list to stop early; missing keys are unmapped.
+            let Ok((line, word)) = field("the mapping") else { break };
+            if word.eq_ignore_ascii_case("x") {
+                mapping.push(None);
+            } else {
+                mapping.push(Some(parse_field((line, word))?));
+            }
+        }
+        mapping.resize(map_size, None);
+
+        Ok(KeyboardMapping {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree,
+            mapping,
+        })
+    }
+
+    /// Scale degree played by `note`, or `None` if the key is outside the range or unmapped.
+    ///
+    /// `scale_len` is used as the formal octave when the file leaves it at 0.
+    pub fn degree(&self, note: u8, scale_len: usize) -> Option<i32> {
+        if note < self.first_note || note > self.last_note {
+            return None;
+        }
+        let offset = note as i32 - self.middle_note as i32;
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+        let size = self.mapping.len() as i32;
+        let octave = if self.octave_degree == 0 { scale_len } else { self.octave_degree } as i32;
+        let degree = self.mapping[offset.rem_euclid(size) as usize]? as i32;
+        Some(degree + offset.div_euclid(size) * octave)
+    }
+}
+
+fn parse_field<T: std::str::FromStr>((line, word): (usize, &str)) -> Result<T, ScalaError> {
+    word.parse().map_err(|_| parse_error(line, format!("'{}' is not a valid number", word)))
+}
+
+fn parse_note(field: (usize, &str)) -> Result<u8, ScalaError> {
+    let line = field.0;
+    let note: u8 = parse_field(field)?;
+    if note > 127 {
+        return Err(parse_error(line, format!("note {} is out of the MIDI range", note)));
+    }
+    Ok(note)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEANTONE: &str = "! meanquar.scl
+!
+1/4-comma meantone scale. Pietro Aaron's temperament (1523)
+ 12
+!
+ 76.04900
+ 193.15686
+ 310.26471
+ 5/4
+ 503.42157
+ 579.47057
+ 696.57843
+ 25/16
+ 889.73529
+ 1006.84314
+ 1082.89214
+ 2/1
+";
+
+    #[test]
+    fn parses_cents_and_ratios() {
+        let scale = Scale::parse(MEANTONE).unwrap();
+        assert_eq!(scale.description, "1/4-comma meantone scale. Pietro Aaron's temperament (1523)");
+        assert_eq!(scale.len(), 12);
+        assert_eq!(scale.period(), 2.0);
+        assert_eq!(scale.ratio(4), 1.25);
+        assert!((scale.ratio(7) - 1.495349).abs() < 1e-6);
+        assert_eq!(scale.ratio(12), 2.0);
+        assert_eq!(scale.ratio(-8), 0.5 * 1.25);
+    }
+
+    #[test]
+    fn parses_non_octave_scales() {
+        // Bohlen-Pierce: 13 equal steps of the tritave 3/1.
+        let text = "Bohlen-Pierce\n13\n".to_string()
+            + &(1..=12).map(|step| format!("{:.5}\n", step as f64 * 1901.955 / 13.0)).collect::<String>()
+            + "3/1\n";
+        let scale = Scale::parse(&text).unwrap();
+        assert_eq!(scale.period(), 3.0);
+        assert!((scale.ratio(26) - 9.0).abs() < 1e-9);
+        assert!((scale.ratio(13 + 1) - 3.0 * scale.ratio(1)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ignores_trailing_text_and_rejects_garbage() {
+        let scale = Scale::parse("desc\n2 notes\n3/2 fifth\n2/1 octave\n").unwrap();
+        assert_eq!(scale.ratio(1), 1.5);
+
+        assert!(matches!(Scale::parse("desc\n2\n3/2\n"), Err(ScalaError::UnexpectedEnd(_))));
+        assert!(matches!(Scale::parse("desc\n1\nfifth\n"), Err(ScalaError::Parse { line: 3, .. })));
+        assert!(matches!(Scale::parse("desc\n1\n0/4\n"), Err(ScalaError::Parse { .. })));
+    }
+
+    #[test]
+    fn equal_temperament_matches_twelve_tet() {
+        let scale = Scale::equal_temperament(12);
+        assert!((scale.ratio(7) - 1.498307).abs() < 1e-6);
+        assert!((scale.ratio(12) - 2.0).abs() < 1e-12);
+    }
+
+    const WHITE_KEYS: &str = "! 7 notes of a scale on the white keys
+12
+0
+127
+60
+69
+440.0
+7
+! mapping
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    #[test]
+    fn parses_keyboard_mappings() {
+        let mapping = KeyboardMapping::parse(WHITE_KEYS).unwrap();
+        assert_eq!(mapping.middle_note, 60);
+        assert_eq!(mapping.reference_note, 69);
+        assert_eq!(mapping.reference_frequency, 440.0);
+        assert_eq!(mapping.octave_degree, 7);
+        assert_eq!(mapping.mapping.len(), 12);
+        assert_eq!(mapping.degree(60, 7), Some(0));
+        assert_eq!(mapping.degree(61, 7), None);
+        assert_eq!(mapping.degree(62, 7), Some(1));
+        assert_eq!(mapping.degree(71, 7), Some(6));
+        assert_eq!(mapping.degree(72, 7), Some(7));
+        assert_eq!(mapping.degree(59, 7), Some(-1));
+        assert_eq!(mapping.degree(48, 7), Some(-7));
+    }
+
+    #[test]
+    fn short_mappings_leave_keys_unmapped() {
+        let mapping = KeyboardMapping::parse("4\n10\n100\n60\n60\n261.6\n0\n0\n1\n").unwrap();
+        assert_eq!(mapping.mapping, vec![Some(0), Some(1), None, None]);
+        assert_eq!(mapping.degree(64, 12), Some(12));
+        assert_eq!(mapping.degree(63, 12), None);
+        assert_eq!(mapping.degree(9, 12), None);
+        assert!(matches!(KeyboardMapping::parse("0\n0\n127\n60\n"), Err(ScalaError::UnexpectedEnd(_))));
+        assert!(matches!(KeyboardMapping::parse("0\n0\n200\n"), Err(ScalaError::Parse { line: 3, .. })));
+    }
+}
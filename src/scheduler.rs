@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::source::frames_in;
+use crate::voice::VoiceEvent;
+
+/*
+    Timing notes with `thread::sleep` on the control thread lands them wherever the sleep happens
+    to wake up, a millisecond or more off, and the error piles up from note to note. The
+    scheduler instead keeps events with the sample frame they are due on, and the source that
+    renders the voices takes them from it one frame at a time, so every event starts exactly on
+    its frame however the output device buffers the samples.
+
+    Times are turned into frames from the start each on their own, rather than by adding up note
+    lengths, so rounding never carries over from one event to the next.
+ */
+
+/// Voice events waiting for the frame they are due on, earliest first.
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    events: VecDeque<(u64, VoiceEvent)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    /// Schedules events given as times from the start, at `sample_rate`.
+    pub fn from_events(sample_rate: u32, events: impl IntoIterator<Item = (Duration, VoiceEvent)>) -> Scheduler {
+        let mut scheduler = Scheduler::new();
+        for (time, event) in events {
+            scheduler.schedule_at(time, sample_rate, event);
+        }
+        scheduler
+    }
+
+    /// Queues `event` for `frame`, after any already queued for the same frame. An event for a
+    /// frame that has already been played is due straight away.
+    pub fn schedule(&mut self, frame: u64, event: VoiceEvent) {
+        let index = self.events.partition_point(|&(queued, _)| queued <= frame);
+        self.events.insert(index, (frame, event));
+    }
+
+    /// Queues `event` for the frame `time` falls on at `sample_rate`.
+    pub fn schedule_at(&mut self, time: Duration, sample_rate: u32, event: VoiceEvent) {
+        self.schedule(frames_in(time, sample_rate), event);
+    }
+
+    /// Takes the next event due on or before `frame`, if there is one.
+    pub fn next_due(&mut self, frame: u64) -> Option<VoiceEvent> {
+        match self.events.front() {
+            Some(&(due, event)) if due <= frame => {
+                self.events.pop_front();
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+
+    /// The frame of the last event still queued.
+    pub fn last_frame(&self) -> Option<u64> {
+        self.events.back().map(|&(frame, _)| frame)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn off(note: u8) -> VoiceEvent {
+        VoiceEvent::NoteOff { note }
+    }
+
+    #[test]
+    fn hands_out_events_in_frame_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(20, off(3));
+        scheduler.schedule(10, off(1));
+        scheduler.schedule(20, off(4));
+        scheduler.schedule(10, off(2));
+        assert_eq!(scheduler.last_frame(), Some(20));
+
+        assert_eq!(scheduler.next_due(9), None);
+        assert_eq!(scheduler.next_due(10), Some(off(1)));
+        assert_eq!(scheduler.next_due(10), Some(off(2)));
+        assert_eq!(scheduler.next_due(10), None);
+        // an event scheduled in the past is due at once, ahead of later ones
+        scheduler.schedule(5, off(0));
+        assert_eq!(scheduler.next_due(11), Some(off(0)));
+        assert_eq!(scheduler.next_due(25), Some(off(3)));
+        assert_eq!(scheduler.next_due(25), Some(off(4)));
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn times_do_not_drift() {
+        // a third of a second is not a whole number of nanoseconds, yet note 299 still starts on
+        // frame 299 * 14700 at 44.1 kHz
+        let third = Duration::from_secs_f64(1.0 / 3.0);
+        let mut scheduler = Scheduler::from_events(44100, (0..300u32).map(|index| (third * index, off(0))));
+        assert_eq!(scheduler.len(), 300);
+        for index in 0..300 {
+            let frame = index * 14700;
+            if frame > 0 {
+                assert_eq!(scheduler.next_due(frame - 1), None, "note {}", index);
+            }
+            assert_eq!(scheduler.next_due(frame), Some(off(0)), "note {}", index);
+        }
+    }
+}
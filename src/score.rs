@@ -0,0 +1,506 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::tuning::note_number;
+
+/*
+    A small text notation for melodies, read token by token:
+
+        # a C major arpeggio, then a chord held for a half note
+        t=100
+        C4/8 E4/8 G4/8 C5/8 [C4 E4 G4]/2
+        |: D#5/4. r/8 :|x3    # played three times
+        G4/4~ G4/8            # tied: one note a quarter and an eighth long
+
+    - A note is a name with an octave (`C4`, `D#5`, `Eb3`), a rest is `r`.
+    - `/n` gives the length as a fraction of a whole note (`/4` is a quarter) and every `.` after
+      it adds half of the previous part. Without one, a note is as long as the note before it,
+      starting from a quarter.
+    - `[...]` plays the notes inside together; the length goes after the closing bracket.
+    - `~` after a note or chord ties it to the next one: notes that carry on sound as one.
+    - `t=120` sets the tempo in quarter notes per minute, 120 until the first one.
+    - `|: ... :|` repeats a passage once more, `:|xN` plays it N times in all. A `:|` without a
+      matching `|:` repeats from the start.
+    - `#` where a token could start begins a comment that runs to the end of the line.
+ */
+
+#[derive(Debug)]
+pub enum ScoreError {
+    Io(io::Error),
+    /// Something that could not be read, with its 1-based line number.
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for ScoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScoreError::Io(err) => write!(f, "{}", err),
+            ScoreError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for ScoreError {}
+
+impl From<io::Error> for ScoreError {
+    fn from(err: io::Error) -> Self {
+        ScoreError::Io(err)
+    }
+}
+
+/// One note of a score, with ties already joined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreNote {
+    pub note: u8,
+    pub start: Duration,
+    pub length: Duration,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Score {
+    /// Notes in the order they start.
+    pub notes: Vec<ScoreNote>,
+    /// Time from the start to the end of the last note or rest.
+    pub length: Duration,
+    /// Beats per minute from each time on, starting with the tempo the score begins at.
+    pub tempos: Vec<(Duration, f64)>,
+}
+
+const DEFAULT_TEMPO: f64 = 120.0;
+const MAX_REPEATS: usize = 100;
+/// Notes and rests a score may come to once its repeats are played out.
+const MAX_NOTES: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    ChordStart,
+    ChordEnd,
+    Tie,
+    RepeatStart,
+    /// `:|`, with the number of times the passage plays.
+    RepeatEnd(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    /// Notes starting together, none for a rest, with the length in quarter notes.
+    Notes { notes: Vec<u8>, beats: f64, tie: bool },
+    Tempo(f64),
+    Repeat(Vec<Item>, usize),
+}
+
+impl Item {
+    /// Notes and rests the items come to once their repeats are played out, saturating.
+    fn count(items: &[Item]) -> usize {
+        items.iter().fold(0, |count: usize, item| {
+            count.saturating_add(match item {
+                Item::Notes { notes, .. } => notes.len().max(1),
+                Item::Tempo(_) => 0,
+                Item::Repeat(passage, times) => Item::count(passage).saturating_mul(*times),
+            })
+        })
+    }
+
+    /// Repeats `passage` unless that takes the score past `MAX_NOTES`, reported at `line`.
+    fn repeat(passage: Vec<Item>, times: usize, line: usize) -> Result<Item, ScoreError> {
+        let item = Item::Repeat(passage, times);
+        if Item::count(std::slice::from_ref(&item)) > MAX_NOTES {
+            return Err(parse_error(line, format!("the repeats come to more than {} notes", MAX_NOTES)));
+        }
+        Ok(item)
+    }
+}
+
+impl Score {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Score, ScoreError> {
+        Score::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Score, ScoreError> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, position: 0, line: 1, beats: 1.0 };
+        let items = parser.items()?;
+        if Item::count(&items) > MAX_NOTES {
+            let line = tokens.last().map_or(1, |(line, _)| *line);
+            return Err(parse_error(line, format!("the score comes to more than {} notes", MAX_NOTES)));
+        }
+        let score = Score { tempos: vec![(Duration::ZERO, DEFAULT_TEMPO)], ..Score::default() };
+        let mut builder = Builder { score, time: 0.0, tempo: DEFAULT_TEMPO, tied: Vec::new() };
+        builder.play(&items);
+        builder.score.notes.sort_by_key(|note| note.start);
+        Ok(builder.score)
+    }
+}
+
+fn parse_error(line: usize, message: String) -> ScoreError {
+    ScoreError::Parse { line, message }
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ScoreError> {
+    let mut tokens = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                c if c.is_whitespace() => continue,
+                // inside a word `#` is a sharp
+                '#' => break,
+                '[' => Token::ChordStart,
+                ']' => Token::ChordEnd,
+                '~' => Token::Tie,
+                '|' if chars.peek() == Some(&':') => {
+                    chars.next();
+                    Token::RepeatStart
+                }
+                ':' if chars.peek() == Some(&'|') => {
+                    chars.next();
+                    let mut times = 2;
+                    if chars.peek() == Some(&'x') {
+                        chars.next();
+                        let mut digits = String::new();
+                        while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                            digits.push(digit);
+                        }
+                        times = digits
+                            .parse()
+                            .ok()
+                            .filter(|times| (1..=MAX_REPEATS).contains(times))
+                            .ok_or_else(|| parse_error(line_number, format!("bad repeat count 'x{}'", digits)))?;
+                    }
+                    Token::RepeatEnd(times)
+                }
+                c => {
+                    let mut word = c.to_string();
+                    while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"[]~|".contains(*c)) {
+                        word.push(c);
+                    }
+                    Token::Word(word)
+                }
+            };
+            tokens.push((line_number, token));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    position: usize,
+    /// Line of the last token read.
+    line: usize,
+    /// Length of the last note, for notes that do not give one.
+    beats: f64,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<(usize, &'a Token)> {
+        let (line, token) = self.tokens.get(self.position)?;
+        self.position += 1;
+        self.line = *line;
+        Some((*line, token))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    /// Items up to the end of the score, where a `:|` without a `|:` repeats everything so far.
+    fn items(&mut self) -> Result<Vec<Item>, ScoreError> {
+        let mut items = Vec::new();
+        loop {
+            match self.passage()? {
+                (passage, Some(times)) => {
+                    items.extend(passage);
+                    items = vec![Item::repeat(items, times, self.line)?];
+                }
+                (passage, None) => {
+                    items.extend(passage);
+                    return Ok(items);
+                }
+            }
+        }
+    }
+
+    /// Items up to the end of the score or a `:|`, with the repeat count of the latter.
+    fn passage(&mut self) -> Result<(Vec<Item>, Option<usize>), ScoreError> {
+        let mut items = Vec::new();
+        while let Some((line, token)) = self.next() {
+            let item = match token {
+                Token::RepeatEnd(times) => return Ok((items, Some(*times))),
+                Token::RepeatStart => {
+                    let (passage, times) = self.passage()?;
+                    let times = times.ok_or_else(|| parse_error(line, "'|:' is never closed by ':|'".to_string()))?;
+                    Item::repeat(passage, times, self.line)?
+                }
+                Token::ChordStart => self.chord(line)?,
+                Token::ChordEnd => return Err(parse_error(line, "']' without '['".to_string())),
+                Token::Tie => return Err(parse_error(line, "'~' must follow a note or chord".to_string())),
+                Token::Word(word) => match word.strip_prefix("t=") {
+                    Some(tempo) => Item::Tempo(
+                        tempo
+                            .parse()
+                            .ok()
+                            .filter(|tempo| *tempo > 0.0 && *tempo <= 1000.0)
+                            .ok_or_else(|| parse_error(line, format!("bad tempo '{}'", tempo)))?,
+                    ),
+                    None => {
+                        let (note, beats) = self.note(line, word)?;
+                        Item::Notes { notes: note.into_iter().collect(), beats, tie: self.tie() }
+                    }
+                },
+            };
+            items.push(item);
+        }
+        Ok((items, None))
+    }
+
+    fn chord(&mut self, start_line: usize) -> Result<Item, ScoreError> {
+        let mut notes = Vec::new();
+        loop {
+            match self.next() {
+                Some((_, Token::ChordEnd)) => break,
+                Some((line, Token::Word(word))) => {
+                    let note = note_number(word).ok_or_else(|| parse_error(line, format!("'{}' is not a note", word)))?;
+                    notes.push(note);
+                }
+                Some((line, _)) => return Err(parse_error(line, "only notes can go in a chord".to_string())),
+                None => return Err(parse_error(start_line, "'[' is never closed".to_string())),
+            }
+        }
+        if let Some(Token::Word(word)) = self.peek() {
+            if word.starts_with('/') {
+                let word = word.clone();
+                self.position += 1;
+                self.beats = parse_length(&word[1..]).ok_or_else(|| parse_error(start_line, format!("bad length '{}'", word)))?;
+            }
+        }
+        Ok(Item::Notes { notes, beats: self.beats, tie: self.tie() })
+    }
+
+    /// Reads a note or rest with an optional length; rests have no note.
+    fn note(&mut self, line: usize, word: &str) -> Result<(Option<u8>, f64), ScoreError> {
+        let (name, length) = match word.split_once('/') {
+            Some((name, length)) => (name, Some(length)),
+            None => (word, None),
+        };
+        let note = match name {
+            "r" | "R" => None,
+            name => Some(note_number(name).ok_or_else(|| parse_error(line, format!("'{}' is not a note", name)))?),
+        };
+        if let Some(length) = length {
+            self.beats = parse_length(length).ok_or_else(|| parse_error(line, format!("bad length '/{}'", length)))?;
+        }
+        Ok((note, self.beats))
+    }
+
+    fn tie(&mut self) -> bool {
+        let tie = self.peek() == Some(&Token::Tie);
+        if tie {
+            self.position += 1;
+        }
+        tie
+    }
+}
+
+/// Length in quarter notes of a note value such as `8` or `4.`.
+pub(crate) fn parse_length(text: &str) -> Option<f64> {
+    let dots = text.len() - text.trim_end_matches('.').len();
+    let value: u32 = text[..text.len() - dots].parse().ok()?;
+    if !value.is_power_of_two() || value > 64 {
+        return None;
+    }
+    let beats = 4.0 / value as f64;
+    Some(beats * (2.0 - 0.5f64.powi(dots as i32)))
+}
+
+struct Builder {
+    score: Score,
+    /// Seconds from the start.
+    time: f64,
+    tempo: f64,
+    /// Indices into `score.notes` of the notes tied to whatever comes next.
+    tied: Vec<usize>,
+}
+
+impl Builder {
+    fn play(&mut self, items: &[Item]) {
+        for item in items {
+            match item {
+                Item::Tempo(tempo) => {
+                    self.tempo = *tempo;
+                    let time = Duration::from_secs_f64(self.time);
+                    // a change where the last one took effect replaces it
+                    if self.score.tempos.last().is_some_and(|(start, _)| *start == time) {
+                        self.score.tempos.pop();
+                    }
+                    self.score.tempos.push((time, *tempo));
+                }
+                Item::Repeat(passage, times) => {
+                    for _ in 0..*times {
+                        self.play(passage);
+                    }
+                }
+                Item::Notes { notes, beats, tie } => {
+                    let seconds = beats * 60.0 / self.tempo;
+                    let start = Duration::from_secs_f64(self.time);
+                    let end = Duration::from_secs_f64(self.time + seconds);
+                    let mut tied = Vec::new();
+                    for &note in notes {
+                        // a tied note carries on rather than starting again
+                        let index = match self.tied.iter().find(|&&index| self.score.notes[index].note == note) {
+                            Some(&index) => index,
+                            None => {
+                                self.score.notes.push(ScoreNote { note, start, length: Duration::ZERO });
+                                self.score.notes.len() - 1
+                            }
+                        };
+                        let held = &mut self.score.notes[index];
+                        held.length = end - held.start;
+                        tied.push(index);
+                    }
+                    self.tied = if *tie { tied } else { Vec::new() };
+                    self.time += seconds;
+                    self.score.length = end;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn note(note: &str, start: u64, length: u64) -> ScoreNote {
+        ScoreNote { note: note_number(note).unwrap(), start: ms(start), length: ms(length) }
+    }
+
+    #[test]
+    fn notes_take_their_own_or_the_previous_length() {
+        // at 120 bpm a quarter note is 500 ms
+        let score = Score::parse("C4/8 D#5/4. E4 r/2 Eb3").unwrap();
+        assert_eq!(
+            score.notes,
+            vec![note("C4", 0, 250), note("D#5", 250, 750), note("E4", 1000, 750), note("Eb3", 2750, 1000)]
+        );
+        assert_eq!(score.length, ms(3750));
+        assert_eq!(Score::parse("A4").unwrap().notes, vec![note("A4", 0, 500)]);
+    }
+
+    #[test]
+    fn dots_add_half_of_the_previous_part() {
+        assert_eq!(parse_length("4"), Some(1.0));
+        assert_eq!(parse_length("4."), Some(1.5));
+        assert_eq!(parse_length("2.."), Some(3.5));
+        assert_eq!(parse_length("16"), Some(0.25));
+        assert_eq!(parse_length("3"), None);
+        assert_eq!(parse_length(""), None);
+    }
+
+    #[test]
+    fn chords_start_together() {
+        let score = Score::parse("[C4 E4 G4]/2 [ D4 F4 ] C5").unwrap();
+        assert_eq!(
+            score.notes,
+            vec![
+                note("C4", 0, 1000),
+                note("E4", 0, 1000),
+                note("G4", 0, 1000),
+                note("D4", 1000, 1000),
+                note("F4", 1000, 1000),
+                note("C5", 2000, 1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_join_notes_of_the_same_pitch() {
+        let score = Score::parse("G4/4~ G4/8 G4/8 [C4 E4]/4 ~ [C4 F4] /4").unwrap();
+        assert_eq!(
+            score.notes,
+            vec![
+                note("G4", 0, 750),
+                note("G4", 750, 250),
+                note("C4", 1000, 1000),
+                note("E4", 1000, 500),
+                note("F4", 1500, 500),
+            ]
+        );
+    }
+
+    #[test]
+    fn tempo_changes_take_effect_from_where_they_are() {
+        let score = Score::parse("C4/4 t=60 C4/4 t=240 C4/4").unwrap();
+        assert_eq!(score.notes, vec![note("C4", 0, 500), note("C4", 500, 1000), note("C4", 1500, 250)]);
+        assert_eq!(score.tempos, vec![(ms(0), 120.0), (ms(500), 60.0), (ms(1500), 240.0)]);
+
+        // a tempo at the very start replaces the default rather than following it
+        let score = Score::parse("t=60 C4 t=100").unwrap();
+        assert_eq!(score.tempos, vec![(ms(0), 60.0), (ms(1000), 100.0)]);
+    }
+
+    #[test]
+    fn repeats_play_a_passage_again() {
+        let score = Score::parse("C4/4 |: D4 E4 :| F4").unwrap();
+        let names: Vec<u8> = score.notes.iter().map(|note| note.note).collect();
+        assert_eq!(names, vec![60, 62, 64, 62, 64, 65]);
+
+        let score = Score::parse("|: C4/8 |: D4 :|x3 :|").unwrap();
+        let names: Vec<u8> = score.notes.iter().map(|note| note.note).collect();
+        assert_eq!(names, vec![60, 62, 62, 62, 60, 62, 62, 62]);
+        assert_eq!(score.length, ms(2000));
+
+        // a lone ':|' repeats from the start
+        let score = Score::parse("C4 D4 :|x3").unwrap();
+        assert_eq!(score.notes.len(), 6);
+    }
+
+    #[test]
+    fn refuses_repeats_that_expand_too_far() {
+        // each level is allowed on its own, but together they would come to 10^10 notes
+        let nested = "|: |: |: |:
+|: C4 :|x100 :|x100 :|x100
+:|x100 :|x100
+";
+        match Score::parse(nested) {
+            Err(ScoreError::Parse { line, .. }) => assert_eq!(line, 2),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(Score::parse("|: |: C4 r :|x100 :|x100").is_ok_and(|score| score.notes.len() == 10_000));
+
+        // as does a long run of repeats that are each small enough
+        let passages = "|: |: [C4 E4] :|x100 :|x100
+".repeat(11);
+        assert!(matches!(Score::parse(&passages), Err(ScoreError::Parse { line: 11, .. })));
+    }
+
+    #[test]
+    fn skips_comments_and_reports_lines() {
+        let score = Score::parse("# intro\nC4 # first\n\nD4\n").unwrap();
+        assert_eq!(score.notes.len(), 2);
+
+        let line = |text: &str| match Score::parse(text) {
+            Err(ScoreError::Parse { line, .. }) => line,
+            other => panic!("expected an error, got {:?}", other),
+        };
+        assert_eq!(line("C4\nH4"), 2);
+        assert_eq!(line("C4 C2000000000"), 1);
+        assert_eq!(line("C4/3"), 1);
+        assert_eq!(line("[C4 E4"), 1);
+        assert_eq!(line("C4\n]"), 2);
+        assert_eq!(line("~ C4"), 1);
+        assert_eq!(line("|: C4"), 1);
+        assert_eq!(line("C4 :|x0"), 1);
+        assert_eq!(line("t=fast"), 1);
+        assert_eq!(line("[C4 r]"), 1);
+    }
+}
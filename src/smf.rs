@@ -0,0 +1,433 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::midi::MidiMessage;
+
+/*
+    Standard MIDI Files hold an `MThd` header chunk followed by `MTrk` track chunks. Every event in
+    a track starts with its delta time since the previous event, in ticks, written as a variable
+    length quantity: 7 bits per byte, most significant first, with the top bit set on every byte
+    but the last. Channel messages may leave out their status byte when it repeats the previous
+    one (running status). Meta events (`FF type length data`) carry the tempo, time signature,
+    track names and so on; system exclusive events (`F0`/`F7 length data`) are skipped.
+
+    A type 0 file has a single track; a type 1 file has several that play together, with the
+    tempo map usually in the first. Type 2 files, a set of independent patterns, are not supported.
+
+    The header's division is either a number of ticks per quarter note, in which case tempo
+    events (microseconds per quarter note, 120 bpm until the first one) turn ticks into time, or
+    an SMPTE frame rate and ticks per frame, which fix the length of a tick outright.
+ */
+
+#[derive(Debug)]
+pub enum SmfError {
+    Io(io::Error),
+    NotMidi,
+    Unsupported(String),
+    /// The file ends in the middle of something.
+    Truncated(&'static str),
+    /// A malformed event, with the byte offset it starts at.
+    Parse { offset: usize, message: String },
+}
+
+impl fmt::Display for SmfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmfError::Io(err) => write!(f, "{}", err),
+            SmfError::NotMidi => write!(f, "not a Standard MIDI File"),
+            SmfError::Unsupported(what) => write!(f, "unsupported MIDI file: {}", what),
+            SmfError::Truncated(what) => write!(f, "file ends inside {}", what),
+            SmfError::Parse { offset, message } => write!(f, "byte {}: {}", offset, message),
+        }
+    }
+}
+
+impl std::error::Error for SmfError {}
+
+impl From<io::Error> for SmfError {
+    fn from(err: io::Error) -> Self {
+        SmfError::Io(err)
+    }
+}
+
+/// How ticks are counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Division {
+    TicksPerQuarter(u16),
+    /// Frames per second (24, 25, 29 for 29.97 drop frame, or 30) and ticks per frame.
+    Smpte { fps: u8, ticks_per_frame: u8 },
+}
+
+/// What happens at a point in a track.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackEventKind {
+    /// A channel message, kept as bytes since not every one is a [`MidiMessage`].
+    Channel(Vec<u8>),
+    /// Microseconds per quarter note.
+    Tempo(u32),
+    /// Any other meta event, by type.
+    Meta(u8, Vec<u8>),
+    SysEx(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackEvent {
+    /// Ticks since the start of the track.
+    pub tick: u64,
+    pub kind: TrackEventKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiFile {
+    pub format: u16,
+    pub division: Division,
+    pub tracks: Vec<Vec<TrackEvent>>,
+}
+
+/// Tempo until a file sets one: 120 beats per minute.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+const META_END_OF_TRACK: u8 = 0x2f;
+const META_TEMPO: u8 = 0x51;
+
+impl MidiFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MidiFile, SmfError> {
+        MidiFile::read(File::open(path)?)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<MidiFile, SmfError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        MidiFile::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<MidiFile, SmfError> {
+        if bytes.len() < 14 || &bytes[0..4] != b"MThd" {
+            return Err(SmfError::NotMidi);
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let header_len = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let format = u16_at(8);
+        let track_count = u16_at(10) as usize;
+        let division = match u16_at(12) {
+            raw if raw & 0x8000 != 0 => Division::Smpte { fps: ((raw >> 8) as u8).wrapping_neg(), ticks_per_frame: raw as u8 },
+            0 => return Err(SmfError::Unsupported("zero ticks per quarter note".to_string())),
+            ticks => Division::TicksPerQuarter(ticks),
+        };
+        if format > 1 {
+            return Err(SmfError::Unsupported(format!("type {} file", format)));
+        }
+        if let Division::Smpte { fps, ticks_per_frame } = division {
+            if ![24, 25, 29, 30].contains(&fps) || ticks_per_frame == 0 {
+                return Err(SmfError::Unsupported(format!("SMPTE division {} fps, {} ticks per frame", fps, ticks_per_frame)));
+            }
+        }
+
+        let mut tracks = Vec::with_capacity(track_count);
+        let mut offset = 8 + header_len;
+        while tracks.len() < track_count {
+            if offset + 8 > bytes.len() {
+                return Err(SmfError::Truncated("the track list"));
+            }
+            let id = &bytes[offset..offset + 4];
+            let size = u32::from_be_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let start = offset + 8;
+            let end = start.checked_add(size).filter(|end| *end <= bytes.len()).ok_or(SmfError::Truncated("a track"))?;
+            // unknown chunk types are to be skipped
+            if id == b"MTrk" {
+                tracks.push(parse_track(&bytes[start..end], start)?);
+            }
+            offset = end;
+        }
+        Ok(MidiFile { format, division, tracks })
+    }
+
+    /// The channel messages of every track merged in time order, with the time each one plays at
+    /// after following the tempo map. Events at the same tick keep their track order.
+    pub fn timeline(&self) -> Vec<(Duration, MidiMessage)> {
+        self.timed_events()
+            .into_iter()
+            .filter_map(|(time, event)| match &event.kind {
+                TrackEventKind::Channel(bytes) => MidiMessage::parse(bytes).map(|message| (time, message)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Beats per minute from each time on, starting with the 120 a file plays at until it sets a
+    /// tempo of its own.
+    pub fn tempo_map(&self) -> Vec<(Duration, f32)> {
+        let mut tempos = vec![(Duration::ZERO, 60_000_000.0 / DEFAULT_TEMPO as f32)];
+        for (time, event) in self.timed_events() {
+            if let TrackEventKind::Tempo(micros) = event.kind {
+                // a zero tempo puts every later event at the same time, so there is no beat to follow
+                if micros > 0 {
+                    tempos.push((time, 60_000_000.0 / micros as f32));
+                }
+            }
+        }
+        tempos
+    }
+
+    /// Time of the last event in any track.
+    pub fn duration(&self) -> Duration {
+        self.timed_events().last().map_or(Duration::ZERO, |(time, _)| *time)
+    }
+
+    fn timed_events(&self) -> Vec<(Duration, &TrackEvent)> {
+        let mut events: Vec<&TrackEvent> = self.tracks.iter().flatten().collect();
+        events.sort_by_key(|event| event.tick);
+
+        // time of the last tempo change in microseconds, the tick it happened at and the new tempo
+        let (mut base_micros, mut base_tick, mut tempo) = (0.0f64, 0u64, DEFAULT_TEMPO);
+        let mut timed = Vec::with_capacity(events.len());
+        for event in events {
+            let micros = base_micros + self.tick_micros(tempo) * (event.tick - base_tick) as f64;
+            if let TrackEventKind::Tempo(new_tempo) = event.kind {
+                (base_micros, base_tick, tempo) = (micros, event.tick, new_tempo);
+            }
+            timed.push((Duration::from_nanos((micros * 1000.0).round() as u64), event));
+        }
+        timed
+    }
+
+    fn tick_micros(&self, tempo: u32) -> f64 {
+        match self.division {
+            Division::TicksPerQuarter(ticks) => tempo as f64 / ticks as f64,
+            Division::Smpte { fps, ticks_per_frame } => {
+                let fps = if fps == 29 { 30_000.0 / 1001.0 } else { fps as f64 };
+                1_000_000.0 / (fps * ticks_per_frame as f64)
+            }
+        }
+    }
+}
+
+/// Reads a variable length quantity at `*position`, moving past it.
+fn read_varlen(track: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value: u32 = 0;
+    // at most four bytes, for 28 bits
+    for _ in 0..4 {
+        let byte = *track.get(*position)?;
+        *position += 1;
+        value = (value << 7) | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Number of data bytes after a channel status byte.
+fn data_len(status: u8) -> usize {
+    match status & 0xf0 {
+        0xc0 | 0xd0 => 1,
+        _ => 2,
+    }
+}
+
+fn parse_track(track: &[u8], file_offset: usize) -> Result<Vec<TrackEvent>, SmfError> {
+    let mut events = Vec::new();
+    let mut position = 0;
+    let mut tick = 0u64;
+    let mut running_status = None;
+    while position < track.len() {
+        let start = position;
+        let error = |message: &str| SmfError::Parse { offset: file_offset + start, message: message.to_string() };
+        tick += read_varlen(track, &mut position).ok_or_else(|| error("bad delta time"))? as u64;
+        let &first = track.get(position).ok_or(SmfError::Truncated("an event"))?;
+        let kind = match first {
+            0xff => {
+                let &meta_type = track.get(position + 1).ok_or(SmfError::Truncated("a meta event"))?;
+                position += 2;
+                let data = read_data(track, &mut position).ok_or(SmfError::Truncated("a meta event"))?;
+                match meta_type {
+                    META_END_OF_TRACK => break,
+                    META_TEMPO if data.len() == 3 => {
+                        TrackEventKind::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]]))
+                    }
+                    META_TEMPO => return Err(error("tempo event is not 3 bytes long")),
+                    _ => TrackEventKind::Meta(meta_type, data.to_vec()),
+                }
+            }
+            0xf0 | 0xf7 => {
+                position += 1;
+                let data = read_data(track, &mut position).ok_or(SmfError::Truncated("a system exclusive event"))?;
+                // system messages cancel running status
+                running_status = None;
+                TrackEventKind::SysEx(data.to_vec())
+            }
+            _ => {
+                let status = if first & 0x80 != 0 {
+                    position += 1;
+                    first
+                } else {
+                    running_status.ok_or_else(|| error("data byte without a status byte"))?
+                };
+                if status >= 0xf0 {
+                    return Err(error("system common message in a track"));
+                }
+                running_status = Some(status);
+                let end = position + data_len(status);
+                let data = track.get(position..end).ok_or(SmfError::Truncated("a channel message"))?;
+                position = end;
+                let mut bytes = vec![status];
+                bytes.extend_from_slice(data);
+                TrackEventKind::Channel(bytes)
+            }
+        };
+        events.push(TrackEvent { tick, kind });
+    }
+    Ok(events)
+}
+
+/// Reads a length-prefixed block of data.
+fn read_data<'a>(track: &'a [u8], position: &mut usize) -> Option<&'a [u8]> {
+    let len = read_varlen(track, position)? as usize;
+    let data = track.get(*position..*position + len)?;
+    *position += len;
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varlen(mut value: u32) -> Vec<u8> {
+        let mut bytes = vec![(value & 0x7f) as u8];
+        value >>= 7;
+        while value > 0 {
+            bytes.insert(0, (value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes
+    }
+
+    fn track(events: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (delta, bytes) in events {
+            body.extend(varlen(*delta));
+            body.extend(*bytes);
+        }
+        body.extend([0x00, 0xff, 0x2f, 0x00]);
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend((body.len() as u32).to_be_bytes());
+        chunk.extend(body);
+        chunk
+    }
+
+    fn midi_file(format: u16, division: u16, tracks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend(6u32.to_be_bytes());
+        bytes.extend(format.to_be_bytes());
+        bytes.extend((tracks.len() as u16).to_be_bytes());
+        bytes.extend(division.to_be_bytes());
+        bytes.extend(tracks.concat());
+        bytes
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn reads_variable_length_quantities() {
+        for value in [0, 0x40, 0x7f, 0x80, 0x2000, 0x3fff, 0x4000, 0x0fff_ffff] {
+            let bytes = varlen(value);
+            let mut position = 0;
+            assert_eq!(read_varlen(&bytes, &mut position), Some(value));
+            assert_eq!(position, bytes.len());
+        }
+        assert_eq!(read_varlen(&[0x81, 0x80, 0x80, 0x80, 0x00], &mut 0), None);
+        assert_eq!(read_varlen(&[0x81], &mut 0), None);
+    }
+
+    #[test]
+    fn type_0_with_running_status_and_a_tempo_change() {
+        // 480 ticks per quarter at 120 bpm, so a quarter note lasts 500 ms
+        let bytes = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0x90, 60, 100]),
+                // running status: another note-on, then a note-on with velocity 0 as note-off
+                (0, &[64, 100]),
+                (480, &[60, 0]),
+                (0, &[64, 0]),
+                // 60 bpm from here on
+                (0, &[0xff, 0x51, 0x03, 0x0f, 0x42, 0x40]),
+                (480, &[0x80, 67, 0]),
+            ])],
+        );
+        let file = MidiFile::parse(&bytes).unwrap();
+        assert_eq!(file.format, 0);
+        assert_eq!(file.division, Division::TicksPerQuarter(480));
+        let timeline = file.timeline();
+        let times: Vec<Duration> = timeline.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![ms(0), ms(0), ms(500), ms(500), ms(1500)]);
+        assert_eq!(timeline[1].1, MidiMessage::NoteOn { channel: 0, note: 64, velocity: 100 });
+        assert_eq!(timeline[2].1, MidiMessage::NoteOff { channel: 0, note: 60, velocity: 64 });
+        assert_eq!(file.duration(), ms(1500));
+        assert_eq!(file.tempo_map(), vec![(ms(0), 120.0), (ms(500), 60.0)]);
+    }
+
+    #[test]
+    fn type_1_tracks_follow_the_tempo_map_of_the_first() {
+        let tempo_track = track(&[
+            (0, &[0xff, 0x03, 0x05, b'T', b'e', b'm', b'p', b'o']),
+            // 240 bpm after the first quarter
+            (96, &[0xff, 0x51, 0x03, 0x03, 0xd0, 0x90]),
+        ]);
+        let notes = track(&[(0, &[0x91, 60, 90]), (96, &[0xb1, 64, 127]), (96, &[0x81, 60, 0]), (0, &[0xe1, 0x00, 0x60])]);
+        let file = MidiFile::parse(&midi_file(1, 96, &[tempo_track, notes])).unwrap();
+        assert_eq!(file.tracks.len(), 2);
+        let timeline = file.timeline();
+        assert_eq!(
+            timeline,
+            vec![
+                (ms(0), MidiMessage::NoteOn { channel: 1, note: 60, velocity: 90 }),
+                (ms(500), MidiMessage::ControlChange { channel: 1, controller: 64, value: 127 }),
+                (ms(750), MidiMessage::NoteOff { channel: 1, note: 60, velocity: 0 }),
+                (ms(750), MidiMessage::PitchBend { channel: 1, value: 4096 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn smpte_division_counts_real_time() {
+        // 25 fps with 40 ticks per frame is one tick per millisecond
+        let division = (((-25i8) as u8 as u16) << 8) | 40;
+        let bytes = midi_file(0, division, &[track(&[(250, &[0x90, 60, 100]), (250, &[0x80, 60, 0])])]);
+        let file = MidiFile::parse(&bytes).unwrap();
+        assert_eq!(file.division, Division::Smpte { fps: 25, ticks_per_frame: 40 });
+        let times: Vec<Duration> = file.timeline().iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![ms(250), ms(500)]);
+    }
+
+    #[test]
+    fn skips_sysex_and_other_messages() {
+        let bytes = midi_file(
+            0,
+            96,
+            &[track(&[(0, &[0xf0, 0x03, 0x7e, 0x7f, 0xf7]), (0, &[0xc0, 5]), (0, &[0x90, 60, 100])])],
+        );
+        let file = MidiFile::parse(&bytes).unwrap();
+        assert_eq!(file.tracks[0].len(), 3);
+        assert_eq!(file.timeline(), vec![(ms(0), MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 })]);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(matches!(MidiFile::parse(b"RIFF0000WAVEfmt "), Err(SmfError::NotMidi)));
+        assert!(matches!(MidiFile::parse(&midi_file(2, 96, &[])), Err(SmfError::Unsupported(_))));
+        // -128 frames per second does not negate to anything that fits a byte
+        assert!(matches!(MidiFile::parse(&midi_file(0, 0x8028, &[])), Err(SmfError::Unsupported(_))));
+        let mut truncated = midi_file(0, 96, &[track(&[(0, &[0x90, 60, 100])])]);
+        truncated.truncate(truncated.len() - 3);
+        assert!(matches!(MidiFile::parse(&truncated), Err(SmfError::Truncated(_))));
+        let no_status = midi_file(0, 96, &[track(&[(0, &[60, 100])])]);
+        assert!(matches!(MidiFile::parse(&no_status), Err(SmfError::Parse { offset: 22, .. })));
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use rodio::{Sample, Source};
+
+/// Wraps a source and stops it once `duration` worth of frames has been played.
+///
+/// The length is counted in whole frames, one sample per channel, derived once from the inner
+/// source's sample rate, so the number of samples produced is exact. An optional short fade-out
+/// ramps the last few milliseconds down to silence to avoid a click where the sound is cut.
+pub struct DurationSource<S> {
+    source: S,
+    duration: Duration,
+    total_frames: u64,
+    frames_played: u64,
+    fade_frames: u64,
+    channel: u16,
+}
+
+impl<S> DurationSource<S>
+    where
+        S: Source,
+        S::Item: Sample,
+{
+    pub fn new(source: S, duration: Duration) -> Self {
+        let total_frames = frames_in(duration, source.sample_rate());
+        DurationSource {
+            source,
+            duration,
+            total_frames,
+            frames_played: 0,
+            fade_frames: 0,
+            channel: 0,
+        }
+    }
+
+    /// Fades the last `fade` of the duration linearly down to silence.
+    pub fn with_fade_out(mut self, fade: Duration) -> Self {
+        self.fade_frames = frames_in(fade, self.source.sample_rate()).min(self.total_frames);
+        self
+    }
+
+    /// Length in frames; the number of samples is this times the channel count.
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+}
+
+pub(crate) fn frames_in(duration: Duration, sample_rate: u32) -> u64 {
+    let frames = (duration.as_nanos() * sample_rate as u128 + 500_000_000) / 1_000_000_000;
+    u64::try_from(frames).unwrap_or(u64::MAX)
+}
+
+impl<S> Source for DurationSource<S>
+    where
+        S: Source,
+        S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        let remaining = (self.total_frames - self.frames_played) as usize * self.channels() as usize
+            - self.channel as usize;
+        match self.source.current_frame_len() {
+            Some(len) => Some(len.min(remaining)),
+            None => Some(remaining),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(self.duration)
+    }
+}
+
+impl<S> Iterator for DurationSource<S>
+    where
+        S: Source,
+        S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.frames_played >= self.total_frames {
+            return None;
+        }
+
+        let sample = self.source.next()?;
+        let remaining = self.total_frames - self.frames_played;
+        let sample = if remaining <= self.fade_frames {
+            // Reaches exactly zero on the last frame.
+            sample.amplify((remaining - 1) as f32 / self.fade_frames as f32)
+        } else {
+            sample
+        };
+
+        self.channel += 1;
+        if self.channel >= self.source.channels().max(1) {
+            self.channel = 0;
+            self.frames_played += 1;
+        }
+        Some(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::WavetableOscillator;
+    use crate::wavetable::WaveTable;
+
+    #[test]
+    fn stops_after_the_duration() {
+        let oscillator = WavetableOscillator::new(1000, WaveTable::sine(64));
+        let source = DurationSource::new(oscillator, Duration::from_millis(100));
+        assert_eq!(source.total_duration(), Some(Duration::from_millis(100)));
+        assert_eq!(source.count(), 100);
+    }
+
+    #[test]
+    fn counts_are_exact_at_common_rates() {
+        for (sample_rate, millis, expected) in [(44100, 100, 4410), (44100, 1, 44), (48000, 2500, 120000), (96000, 7, 672)] {
+            let oscillator = WavetableOscillator::new(sample_rate, WaveTable::sine(64));
+            let source = DurationSource::new(oscillator, Duration::from_millis(millis));
+            assert_eq!(source.total_frames(), expected);
+            assert_eq!(source.count() as u64, expected, "{} ms at {} Hz", millis, sample_rate);
+        }
+    }
+
+    #[test]
+    fn counts_long_durations_without_wrapping() {
+        assert_eq!(frames_in(Duration::from_secs(500_000), 48000), 24_000_000_000);
+        assert_eq!(frames_in(Duration::MAX, 192000), u64::MAX);
+    }
+
+    #[test]
+    fn counts_frames_not_samples() {
+        let stereo = rodio::buffer::SamplesBuffer::new(2, 1000, vec![0.5f32; 1000]);
+        let source = DurationSource::new(stereo, Duration::from_millis(100));
+        assert_eq!(source.current_frame_len(), Some(200));
+        assert_eq!(source.count(), 200);
+    }
+
+    #[test]
+    fn fades_out_to_silence() {
+        let stereo = rodio::buffer::SamplesBuffer::new(2, 1000, vec![1.0f32; 1000]);
+        let source = DurationSource::new(stereo, Duration::from_millis(100)).with_fade_out(Duration::from_millis(5));
+        let samples: Vec<f32> = source.collect();
+        assert_eq!(samples.len(), 200);
+        assert!(samples[..190].iter().all(|s| *s == 1.0));
+        // Both channels of a frame share the gain: 4/5, 3/5, … 0.
+        assert_eq!(&samples[190..], &[0.8, 0.8, 0.6, 0.6, 0.4, 0.4, 0.2, 0.2, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn ends_with_its_inner_source() {
+        let inner = rodio::buffer::SamplesBuffer::new(1, 1000, vec![0.25f32; 10]);
+        let source = DurationSource::new(inner, Duration::from_secs(1));
+        assert_eq!(source.count(), 10);
+    }
+}
@@ -0,0 +1,168 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/*
+    A bounded single-producer, single-consumer queue for passing values to and from the audio
+    thread, which must not wait on a lock the UI thread might hold or allocate while it renders.
+
+    The slots live in one buffer allocated up front. `tail` counts the values written and `head`
+    the values read; both only ever grow (wrapping around `usize`), and a value's slot is its count
+    masked to the buffer size, which is a power of two. Each side stores only its own counter and
+    reads the other's, so neither ever waits: the producer sees a full queue when `tail - head`
+    reaches the capacity, and the consumer an empty one when they are equal.
+
+    A slot is written before `tail` is published with `Release`, and the consumer reads `tail`
+    with `Acquire` before reading the slot, so it always sees the whole value; `head` works the
+    same way for handing the slot back.
+ */
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Only the producer writes a slot, and only before publishing it; only the consumer reads it, and
+// only after.
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn slot(&self, count: usize) -> *mut MaybeUninit<T> {
+        self.slots[count & (self.slots.len() - 1)].get()
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            // Values between head and tail were written and never read.
+            unsafe { (*self.slot(head)).assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// Creates a queue that holds at least `capacity` values, rounded up to a power of two.
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let size = capacity.max(1).next_power_of_two();
+    let ring = Arc::new(Ring {
+        slots: (0..size).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (Producer { ring: ring.clone() }, Consumer { ring })
+}
+
+/// Writing end of a queue.
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Producer<T> {
+    /// Adds `value` to the queue, or hands it back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.ring.head.load(Ordering::Acquire)) == self.ring.slots.len() {
+            return Err(value);
+        }
+        unsafe { (*self.ring.slot(tail)).write(value) };
+        self.ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+}
+
+/// Reading end of a queue.
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Consumer<T> {
+    /// Takes the oldest value from the queue.
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.ring.slot(head)).assume_init_read() };
+        self.ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Number of values waiting to be read.
+    pub fn len(&self) -> usize {
+        self.ring.tail.load(Ordering::Acquire).wrapping_sub(self.ring.head.load(Ordering::Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn keeps_values_in_order_until_full() {
+        let (mut producer, mut consumer) = channel(3);
+        assert_eq!(producer.capacity(), 4);
+        for value in 0..4 {
+            assert_eq!(producer.push(value), Ok(()));
+        }
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.len(), 4);
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(producer.push(4), Ok(()));
+        let rest: Vec<i32> = std::iter::from_fn(|| consumer.pop()).collect();
+        assert_eq!(rest, vec![1, 2, 3, 4]);
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn drops_values_left_in_the_queue() {
+        let value = Arc::new(());
+        let (mut producer, mut consumer) = channel(8);
+        for _ in 0..3 {
+            producer.push(value.clone()).unwrap();
+        }
+        drop(consumer.pop());
+        assert_eq!(Arc::strong_count(&value), 3);
+        drop((producer, consumer));
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn passes_values_between_threads() {
+        let (mut producer, mut consumer) = channel(16);
+        let writer = thread::spawn(move || {
+            for value in 0..100_000u32 {
+                let mut value = value;
+                while let Err(back) = producer.push(value) {
+                    value = back;
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 100_000 {
+            match consumer.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::scala::{KeyboardMapping, Scale};
+
+/*
+    Every pitch is computed from a single reference: by default the frequency of A4, MIDI note 69.
+    In twelve-tone equal temperament each semitone multiplies the frequency by 2^(1/12), so any note
+    number maps to reference * 2^((note - 69) / 12). Changing the reference (440, 432, 415.3 for
+    baroque pitch, 442 for many orchestras) retunes every note at once.
+
+    Other tunings come from a Scala scale and keyboard mapping: the mapping picks the scale degree
+    each key plays and the reference key's frequency, and the scale gives the ratio of every degree.
+    The frequencies of all 128 note numbers are worked out once, up front.
+ */
+
+/// MIDI note number of A4, the usual reference pitch.
+pub const A4_NOTE: u8 = 69;
+
+/// MIDI note number of middle C, where Scala puts scale degree 0 by default.
+pub const MIDDLE_C: u8 = 60;
+
+/// Standard concert pitch for A4 in Hz.
+pub const CONCERT_A4: f32 = 440.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    scale: Scale,
+    mapping: KeyboardMapping,
+    frequencies: Vec<Option<f32>>,
+}
+
+impl Tuning {
+    /// Twelve-tone equal temperament with A4 at `a4` Hz.
+    pub fn new(a4: f32) -> Tuning {
+        assert!(a4 > 0.0 && a4.is_finite(), "reference frequency must be positive");
+        Tuning::from_scala(Scale::equal_temperament(12), KeyboardMapping::linear(MIDDLE_C, A4_NOTE, a4 as f64))
+    }
+
+    /// Tunes keys with `mapping` and `scale`.
+    ///
+    /// If the reference key itself is unmapped, the frequency it would have under a linear mapping
+    /// from the middle note is used as the anchor instead.
+    pub fn from_scala(scale: Scale, mapping: KeyboardMapping) -> Tuning {
+        let reference_degree = mapping
+            .degree(mapping.reference_note, scale.len())
+            .unwrap_or(mapping.reference_note as i32 - mapping.middle_note as i32);
+        let base = mapping.reference_frequency / scale.ratio(reference_degree);
+        let frequencies = (0..=127u8)
+            .map(|note| {
+                let degree = mapping.degree(note, scale.len())?;
+                Some((base * scale.ratio(degree)) as f32)
+            })
+            .collect();
+        Tuning { scale, mapping, frequencies }
+    }
+
+    /// A Scala scale on Scala's default keyboard: degree 0 on middle C, A4 at `a4` Hz.
+    pub fn from_scale(scale: Scale, a4: f32) -> Tuning {
+        Tuning::from_scala(scale, KeyboardMapping::linear(MIDDLE_C, A4_NOTE, a4 as f64))
+    }
+
+    pub fn scale(&self) -> &Scale {
+        &self.scale
+    }
+
+    pub fn mapping(&self) -> &KeyboardMapping {
+        &self.mapping
+    }
+
+    pub fn reference_frequency(&self) -> f32 {
+        self.mapping.reference_frequency as f32
+    }
+
+    /// Frequency in Hz of MIDI note `note`, or `None` if the keyboard mapping leaves it silent.
+    pub fn frequency(&self, note: u8) -> Option<f32> {
+        self.frequencies.get(note as usize).copied().flatten()
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning::new(CONCERT_A4)
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Parses a note name with octave, such as `A4`, `C#5`, `Eb3` or `B-1`, into a MIDI note number.
+///
+/// Octaves follow scientific pitch notation, where middle C is `C4` (note 60).
+pub fn note_number(name: &str) -> Option<u8> {
+    let mut chars = name.trim().chars();
+    let letter = chars.next()?.to_ascii_uppercase();
+    let mut semitone: i32 = match letter {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let mut rest = chars.as_str();
+    while let Some(accidental) = rest.chars().next() {
+        match accidental {
+            '#' => semitone += 1,
+            'b' => semitone -= 1,
+            _ => break,
+        }
+        rest = &rest[1..];
+    }
+    // MIDI notes span octaves -1 to 9, and anything further out would overflow below
+    let octave: i8 = rest.parse().ok().filter(|octave| (-1..=9).contains(octave))?;
+    let note = (octave as i32 + 1) * 12 + semitone;
+    u8::try_from(note).ok().filter(|note| *note <= 127)
+}
+
+/// Name of a MIDI note number with sharps, e.g. `C#4` for 61.
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("note should be mapped");
+        assert!((actual - expected).abs() < 0.01, "{} vs {}", actual, expected);
+    }
+
+    #[test]
+    fn concert_pitch_matches_the_usual_table() {
+        let tuning = Tuning::default();
+        assert_eq!(tuning.frequency(A4_NOTE), Some(440.0));
+        assert_close(tuning.frequency(70), 466.16);
+        assert_close(tuning.frequency(72), 523.25);
+        assert_close(tuning.frequency(80), 830.61);
+        assert_close(tuning.frequency(60), 261.63);
+        assert_close(tuning.frequency(81), 880.0);
+    }
+
+    #[test]
+    fn follows_any_reference_pitch() {
+        for a4 in [432.0, 415.3, 442.0] {
+            let tuning = Tuning::new(a4);
+            assert_close(tuning.frequency(A4_NOTE), a4);
+            assert_close(tuning.frequency(A4_NOTE + 12), a4 * 2.0);
+            assert_close(tuning.frequency(A4_NOTE - 24), a4 / 4.0);
+        }
+        assert_close(Tuning::new(432.0).frequency(72), 513.74);
+    }
+
+    // 5-limit just intonation from the Scala archive (ji_12.scl).
+    const JUST: &str = "! ji_12.scl
+!
+5-limit 12-tone just intonation
+12
+!
+16/15
+9/8
+6/5
+5/4
+4/3
+45/32
+3/2
+8/5
+5/3
+9/5
+15/8
+2/1
+";
+
+    #[test]
+    fn just_intonation_on_the_default_keyboard() {
+        let tuning = Tuning::from_scale(Scale::parse(JUST).unwrap(), 440.0);
+        // A4 is degree 9 (5/3) above middle C, so C4 is 440 * 3/5.
+        assert_close(tuning.frequency(A4_NOTE), 440.0);
+        assert_close(tuning.frequency(MIDDLE_C), 264.0);
+        assert_close(tuning.frequency(64), 330.0);
+        assert_close(tuning.frequency(67), 396.0);
+        assert_close(tuning.frequency(72), 528.0);
+        assert_close(tuning.frequency(48), 132.0);
+    }
+
+    #[test]
+    fn keyboard_mapping_anchors_the_reference_key() {
+        let mapping = KeyboardMapping::parse("0\n0\n127\n60\n60\n261.6256\n0\n").unwrap();
+        let tuning = Tuning::from_scala(Scale::parse(JUST).unwrap(), mapping);
+        assert_close(tuning.frequency(60), 261.63);
+        assert_close(tuning.frequency(64), 327.03);
+        assert_close(tuning.frequency(67), 392.44);
+    }
+
+    #[test]
+    fn nineteen_tone_equal_temperament() {
+        let tuning = Tuning::from_scale(Scale::equal_temperament(19), 440.0);
+        // Nineteen keys up from A4 is one octave.
+        assert_close(tuning.frequency(A4_NOTE + 19), 880.0);
+        assert_close(tuning.frequency(A4_NOTE + 1), 440.0 * 2f32.powf(1.0 / 19.0));
+    }
+
+    #[test]
+    fn unmapped_keys_have_no_frequency() {
+        let mapping = KeyboardMapping::parse("12\n21\n108\n60\n69\n440\n12\n0\nx\n2\nx\n4\n5\nx\n7\nx\n9\nx\n11\n").unwrap();
+        let tuning = Tuning::from_scala(Scale::equal_temperament(12), mapping);
+        assert_close(tuning.frequency(69), 440.0);
+        assert_close(tuning.frequency(60), 261.63);
+        assert_eq!(tuning.frequency(61), None);
+        assert_eq!(tuning.frequency(20), None);
+        assert_eq!(tuning.frequency(127), None);
+    }
+
+    #[test]
+    fn parses_note_names() {
+        assert_eq!(note_number("A4"), Some(69));
+        assert_eq!(note_number("C4"), Some(60));
+        assert_eq!(note_number("c#5"), Some(73));
+        assert_eq!(note_number("Eb3"), Some(51));
+        assert_eq!(note_number("C-1"), Some(0));
+        assert_eq!(note_number("G9"), Some(127));
+        assert_eq!(note_number("G#9"), None);
+        assert_eq!(note_number("H2"), None);
+        assert_eq!(note_number("A"), None);
+        assert_eq!(note_number("C2000000000"), None);
+        assert_eq!(note_number("B#-2"), None);
+        assert_eq!(note_number("Cb10"), None);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for note in 0..=127 {
+            assert_eq!(note_number(&note_name(note)), Some(note));
+        }
+        assert_eq!(note_name(61), "C#4");
+    }
+}
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::interpolation::Interpolation;
+use crate::mipmap::MipMappedTable;
+use crate::oscillator::WavetableOscillator;
+use crate::pan::PanLaw;
+
+/*
+    Unison stacks several copies of the voice's oscillator, each slightly detuned, for the thick
+    sound of a supersaw. The copies are spread evenly from -1 to 1; `detune` is how many cents the
+    outermost ones are from the note, and the curve decides how the ones in between are placed:
+    evenly, or bunched up near the note and fanning out towards the edges.
+
+    With an odd number of copies the middle one plays the note itself; `blend` fades between it
+    (0) and the detuned ones (1), and has no effect on even stacks, which have no middle copy.
+    The gains are scaled so the stack is about as loud as a single oscillator.
+
+    Each copy also has a place in the stereo field, from -`spread` to `spread` either side of the
+    pan of the whole stack, and starts either at a random phase or at one of a set spaced evenly through the
+    cycle from a fixed phase. Either way the copies do not all line up at the start of a note,
+    which would make the onset of an N-copy stack √N times louder than the rest of it.
+ */
+
+/// Most oscillators one unison stack may hold.
+pub const MAX_UNISON: usize = 16;
+
+/// How the detune of the copies grows from the middle of the stack to its edges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DetuneCurve {
+    Linear,
+    /// Copies near the middle stay close to the note and the outer ones move away quickly.
+    Exponential,
+}
+
+impl DetuneCurve {
+    /// Maps a place in the stack, from -1 to 1, to a share of the detune.
+    fn apply(self, place: f32) -> f32 {
+        match self {
+            DetuneCurve::Linear => place,
+            DetuneCurve::Exponential => place.signum() * (2.0 * place.abs()).exp_m1() / 2f32.exp_m1(),
+        }
+    }
+}
+
+impl FromStr for DetuneCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "linear" | "lin" => Ok(DetuneCurve::Linear),
+            "exponential" | "exp" => Ok(DetuneCurve::Exponential),
+            _ => Err(format!("unknown detune curve '{}' (expected linear or exponential)", s)),
+        }
+    }
+}
+
+impl fmt::Display for DetuneCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetuneCurve::Linear => write!(f, "linear"),
+            DetuneCurve::Exponential => write!(f, "exponential"),
+        }
+    }
+}
+
+/// Where each copy starts its cycle when a note starts from silence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnisonPhase {
+    Random,
+    /// The middle copy starts here, from 0 to 1, and the others at even steps through the cycle
+    /// either side of it.
+    Fixed(f32),
+}
+
+impl FromStr for UnisonPhase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("random") {
+            return Ok(UnisonPhase::Random);
+        }
+        match s.parse::<f32>() {
+            Ok(phase) if (0.0..=1.0).contains(&phase) => Ok(UnisonPhase::Fixed(phase)),
+            _ => Err(format!("'{}' is not a phase (expected random or a number from 0 to 1)", s)),
+        }
+    }
+}
+
+impl fmt::Display for UnisonPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnisonPhase::Random => write!(f, "random"),
+            UnisonPhase::Fixed(phase) => write!(f, "{}", phase),
+        }
+    }
+}
+
+/// Unison settings. `detune` is in cents, `spread` and `blend` run from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnisonSettings {
+    pub voices: usize,
+    pub detune: f32,
+    pub curve: DetuneCurve,
+    pub spread: f32,
+    pub phase: UnisonPhase,
+    pub blend: f32,
+}
+
+impl UnisonSettings {
+    pub fn new(voices: usize, detune: f32, curve: DetuneCurve, spread: f32, phase: UnisonPhase, blend: f32) -> UnisonSettings {
+        UnisonSettings {
+            voices: voices.clamp(1, MAX_UNISON),
+            detune: detune.max(0.0),
+            curve,
+            spread: spread.clamp(0.0, 1.0),
+            phase,
+            blend: blend.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Default for UnisonSettings {
+    /// A single oscillator in the middle, starting at phase 0.
+    fn default() -> Self {
+        UnisonSettings::new(1, 0.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.0), 0.5)
+    }
+}
+
+struct Layer {
+    oscillator: WavetableOscillator,
+    // Frequency ratio of the detune.
+    ratio: f32,
+    gain: f32,
+    // Place in the stereo field relative to the stack's pan.
+    offset: f32,
+    pan: [f32; 2],
+}
+
+/// A stack of detuned oscillators playing one note.
+pub struct Unison {
+    settings: UnisonSettings,
+    layers: Vec<Layer>,
+    pan: f32,
+    law: PanLaw,
+    // State of the generator for random phases.
+    random: u32,
+}
+
+impl Unison {
+    /// Builds the stack for `settings`. `seed` picks the sequence of random phases, so stacks
+    /// given different seeds start differently.
+    pub fn new(sample_rate: u32, wave_table: Arc<MipMappedTable>, settings: UnisonSettings, seed: u32) -> Unison {
+        let count = settings.voices;
+        let centre = |index: usize| count % 2 == 1 && index == count / 2;
+        let raw_gain = |index: usize| match count {
+            1 => 1.0,
+            _ if centre(index) => 1.0 - settings.blend,
+            _ => settings.blend,
+        };
+        // an odd stack fully blended to one side still has gains to scale
+        let total = (0..count).map(|index| raw_gain(index).powi(2)).sum::<f32>().sqrt().max(f32::EPSILON);
+        let layers = (0..count)
+            .map(|index| {
+                let place = if count == 1 { 0.0 } else { index as f32 * 2.0 / (count - 1) as f32 - 1.0 };
+                let cents = settings.curve.apply(place) * settings.detune;
+                Layer {
+                    oscillator: WavetableOscillator::with_mipmaps(sample_rate, wave_table.clone()),
+                    ratio: 2f32.powf(cents / 1200.0),
+                    gain: raw_gain(index) / total,
+                    offset: place * settings.spread,
+                    pan: PanLaw::default().gains(place * settings.spread),
+                }
+            })
+            .collect();
+        // xorshift needs a state other than 0
+        Unison { settings, layers, pan: 0.0, law: PanLaw::default(), random: seed.wrapping_mul(0x9e37_79b9) | 1 }
+    }
+
+    pub fn settings(&self) -> UnisonSettings {
+        self.settings
+    }
+
+    /// Plays `frequency`, each copy at its own detune.
+    pub fn set_frequency(&mut self, frequency: f32) {
+        for layer in &mut self.layers {
+            layer.oscillator.set_frequency(frequency * layer.ratio);
+        }
+    }
+
+    /// Moves every copy to `position` in the wave table.
+    pub fn set_position(&mut self, position: f32) {
+        for layer in &mut self.layers {
+            layer.oscillator.set_position(position);
+        }
+    }
+
+    /// Reads from another table, keeping the pitch and phase of every copy.
+    pub fn set_wave_table(&mut self, wave_table: &Arc<MipMappedTable>) {
+        for layer in &mut self.layers {
+            layer.oscillator.set_wave_table(wave_table.clone());
+        }
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        for layer in &mut self.layers {
+            layer.oscillator.set_interpolation(interpolation);
+        }
+    }
+
+    /// Starts the copies at phases spread evenly from the fixed one, or each at a random one of
+    /// its own.
+    pub fn reset_phase(&mut self) {
+        let count = self.layers.len();
+        for index in 0..count {
+            let phase = match self.settings.phase {
+                UnisonPhase::Fixed(phase) => phase + (index as f32 - (count / 2) as f32) / count as f32,
+                UnisonPhase::Random => self.next_random(),
+            };
+            self.layers[index].oscillator.set_phase(phase);
+        }
+    }
+
+    /// Moves the whole stack to `pan`, from -1 (left) to 1 (right), placing each copy with `law`.
+    pub fn set_pan(&mut self, pan: f32, law: PanLaw) {
+        if pan == self.pan && law == self.law {
+            return;
+        }
+        self.pan = pan;
+        self.law = law;
+        for layer in &mut self.layers {
+            layer.pan = law.gains(pan + layer.offset);
+        }
+    }
+
+    /// Whether every copy sits at the pan of the stack, so its frames are `next_sample` panned as
+    /// a whole.
+    pub fn is_centred(&self) -> bool {
+        self.layers.len() == 1 || self.settings.spread == 0.0
+    }
+
+    /// Mixes the copies into one channel, ignoring their pans.
+    pub fn next_sample(&mut self) -> f32 {
+        self.layers.iter_mut().map(|layer| layer.oscillator.get_sample() * layer.gain).sum()
+    }
+
+    /// Mixes the copies into a left and right channel, each at its place in the stereo field.
+    pub fn next_frame(&mut self) -> [f32; 2] {
+        let mut frame = [0.0; 2];
+        for layer in &mut self.layers {
+            let sample = layer.oscillator.get_sample() * layer.gain;
+            frame[0] += sample * layer.pan[0];
+            frame[1] += sample * layer.pan[1];
+        }
+        frame
+    }
+
+    /// A number from 0 to 1, from a xorshift generator.
+    fn next_random(&mut self) -> f32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        (self.random >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wavetable::WaveTable;
+
+    fn sine() -> Arc<MipMappedTable> {
+        Arc::new(MipMappedTable::from(WaveTable::sine(64)))
+    }
+
+    fn stack(settings: UnisonSettings) -> Unison {
+        let mut unison = Unison::new(44100, sine(), settings, 1);
+        unison.set_frequency(441.0);
+        unison.reset_phase();
+        unison
+    }
+
+    #[test]
+    fn parses_curves_and_phases() {
+        assert_eq!("exp".parse(), Ok(DetuneCurve::Exponential));
+        assert!("log".parse::<DetuneCurve>().is_err());
+        assert_eq!("Random".parse(), Ok(UnisonPhase::Random));
+        assert_eq!("0.25".parse(), Ok(UnisonPhase::Fixed(0.25)));
+        assert!("2".parse::<UnisonPhase>().is_err());
+        assert_eq!(UnisonSettings::new(40, 10.0, DetuneCurve::Linear, 2.0, UnisonPhase::Random, -1.0).voices, MAX_UNISON);
+    }
+
+    #[test]
+    fn a_single_copy_is_a_plain_oscillator() {
+        let mut unison = stack(UnisonSettings::new(1, 50.0, DetuneCurve::Linear, 1.0, UnisonPhase::Fixed(0.0), 1.0));
+        let mut oscillator = WavetableOscillator::with_mipmaps(44100, sine());
+        oscillator.set_frequency(441.0);
+        for _ in 0..200 {
+            assert_eq!(unison.next_sample(), oscillator.get_sample());
+        }
+    }
+
+    #[test]
+    fn detunes_the_outer_copies_by_the_amount() {
+        let linear = stack(UnisonSettings::new(5, 30.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.0), 0.5));
+        let exponential = stack(UnisonSettings::new(5, 30.0, DetuneCurve::Exponential, 0.0, UnisonPhase::Fixed(0.0), 0.5));
+        let cents = |unison: &Unison| -> Vec<f32> { unison.layers.iter().map(|layer| 1200.0 * layer.ratio.log2()).collect() };
+        let (linear, exponential) = (cents(&linear), cents(&exponential));
+        for (cents, expected) in linear.iter().zip([-30.0, -15.0, 0.0, 15.0, 30.0]) {
+            assert!((cents - expected).abs() < 1e-3, "{:?}", linear);
+        }
+        assert!((exponential[0] + 30.0).abs() < 1e-3 && (exponential[4] - 30.0).abs() < 1e-3);
+        assert!(exponential[2].abs() < 1e-6);
+        // closer to the note than the linear curve on the inner copies
+        assert!(exponential[3] > 0.0 && exponential[3] < 10.0, "{:?}", exponential);
+    }
+
+    #[test]
+    fn blend_fades_between_the_centre_and_the_sides() {
+        let gains = |count, blend| -> Vec<f32> {
+            let unison = stack(UnisonSettings::new(count, 20.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.0), blend));
+            unison.layers.iter().map(|layer| layer.gain).collect()
+        };
+        assert_eq!(gains(3, 0.0), vec![0.0, 1.0, 0.0]);
+        let sides = gains(3, 1.0);
+        assert!((sides[0] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6 && sides[1] == 0.0 && sides[0] == sides[2]);
+        // equal gains at the halfway point, at the power of one oscillator
+        for count in [2, 4, 7] {
+            let gains = gains(count, 0.5);
+            assert!(gains.iter().all(|gain| (gain - gains[0]).abs() < 1e-6));
+            assert!((gains.iter().map(|gain| gain * gain).sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn random_phases_differ_between_copies_and_seeds() {
+        let settings = UnisonSettings::new(4, 0.0, DetuneCurve::Linear, 0.0, UnisonPhase::Random, 0.5);
+        let first: Vec<f32> = stack(settings).layers.iter_mut().map(|layer| layer.oscillator.get_sample()).collect();
+        assert!(first.windows(2).all(|pair| (pair[0] - pair[1]).abs() > 1e-3), "{:?}", first);
+
+        let mut other = Unison::new(44100, sine(), settings, 2);
+        other.set_frequency(441.0);
+        other.reset_phase();
+        let second: Vec<f32> = other.layers.iter_mut().map(|layer| layer.oscillator.get_sample()).collect();
+        assert_ne!(first, second);
+
+        // a fixed phase is where the middle copy starts, and the others are at even steps from it
+        let mut fixed = stack(UnisonSettings::new(4, 0.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.25), 0.5));
+        for (index, layer) in fixed.layers.iter_mut().enumerate() {
+            let expected = (2.0 * std::f32::consts::PI * (0.25 + (index as f32 - 2.0) / 4.0)).sin();
+            assert!((layer.oscillator.get_sample() - expected).abs() < 1e-3, "copy {}", index);
+        }
+    }
+
+    #[test]
+    fn the_onset_is_no_louder_than_a_single_oscillator() {
+        // in phase, N copies at the power of one oscillator would peak at √N; over the first 10 ms
+        // the detune has not yet had time to bring them together
+        for count in [2, 4, 7, 16] {
+            let mut unison = stack(UnisonSettings::new(count, 20.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.0), 0.5));
+            let peak = (0..441).fold(0.0f32, |peak, _| peak.max(unison.next_sample().abs()));
+            assert!(peak < 1.05, "{} copies peak at {}", count, peak);
+        }
+    }
+
+    #[test]
+    fn spread_places_the_copies_across_the_stereo_field() {
+        // fully spread, the lowest copy is only on the left and the highest only on the right
+        let mut spread = stack(UnisonSettings::new(2, 50.0, DetuneCurve::Linear, 1.0, UnisonPhase::Fixed(0.0), 0.5));
+        let mut low = WavetableOscillator::with_mipmaps(44100, sine());
+        low.set_frequency(441.0 * 2f32.powf(-50.0 / 1200.0));
+        low.set_phase(0.5);
+        for _ in 0..200 {
+            let [left, _] = spread.next_frame();
+            // at half the power of the stack, panned hard left
+            assert!((left - low.get_sample() * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-5);
+        }
+        // panning the stack hard right moves the lowest copy to the centre and keeps the highest
+        // out of the left channel
+        spread.set_pan(1.0, PanLaw::Linear);
+        for _ in 0..200 {
+            let [left, _] = spread.next_frame();
+            assert!((left - low.get_sample() * std::f32::consts::FRAC_1_SQRT_2 * 0.5).abs() < 1e-5);
+        }
+        // without spread both channels carry the same mix
+        let mut narrow = stack(UnisonSettings::new(3, 50.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.0), 0.5));
+        assert!(narrow.is_centred() && !spread.is_centred());
+        for _ in 0..200 {
+            let [left, right] = narrow.next_frame();
+            assert!((left - right).abs() < 1e-6);
+        }
+    }
+}
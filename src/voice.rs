@@ -0,0 +1,982 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::envelope::{Adsr, Envelope};
+use crate::filter::{Filter, FilterModulation, FilterSettings, KEY_TRACKING_CENTRE};
+use crate::interpolation::Interpolation;
+use crate::lfo::Lfo;
+use crate::mipmap::MipMappedTable;
+use crate::modulation::{ModDestination, ModMatrix, SourceValues};
+use crate::pan::PanLaw;
+use crate::unison::{Unison, UnisonSettings};
+
+/*
+    Instead of starting a new, independent source for every key press, all notes are played by a
+    fixed pool of voices owned by one `VoiceManager`, which mixes them into a single `Source` of
+    interleaved stereo frames.
+
+    Each voice has its own ADSR envelope. A note-on takes a free voice if there is one. When every
+    voice is busy, the steal policy decides which one is reused; voices that are already releasing
+    go before held ones. A note-off sends every voice holding that note number into its release,
+    and the voice becomes free again once the release has faded out.
+
+    While the sustain pedal is down a note-off only marks the voice; it is released when the pedal
+    comes up. Pitch bend multiplies the frequency of every voice, and the table position applies
+    to every voice at once.
+
+    Each voice plays a unison stack of one or more detuned oscillators (see `unison`), spread across
+    the two channels. With a filter set, every voice runs each channel through a filter of its own
+    before the envelope; a voice that starts from silence starts with cleared filters. A second
+    envelope per voice, gated together with the first, moves the filters' cutoff along with key
+    tracking and velocity (see `FilterModulation`). A stack without any spread is the same in both
+    channels, so it is filtered only once.
+
+    Every voice also runs the LFOs of the modulation matrix (see `modulation`), restarting them
+    with each note, and the matrix's routes move the voice's pitch, table position, cutoff,
+    amplitude and pan on top of their base values every sample. Destinations without a route are
+    left alone. Every voice is then placed in the stereo field with one of the laws in `pan`, at
+    the base pan shared by all voices plus its own modulation; the copies of a spread unison stack
+    are placed around that.
+ */
+
+/// Channels in every frame the voices render: left, then right.
+pub const CHANNELS: u16 = 2;
+
+/// Which busy voice a new note takes over when the pool is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StealPolicy {
+    /// The voice that started longest ago.
+    Oldest,
+    /// The voice with the lowest current level.
+    Quietest,
+    /// Retrigger the voice already playing the same note, even if others are free;
+    /// otherwise behave like `Oldest`.
+    SameNote,
+}
+
+impl FromStr for StealPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "oldest" => Ok(StealPolicy::Oldest),
+            "quietest" => Ok(StealPolicy::Quietest),
+            "same-note" | "same_note" | "retrigger" => Ok(StealPolicy::SameNote),
+            _ => Err(format!("unknown steal policy '{}' (expected oldest, quietest or same-note)", s)),
+        }
+    }
+}
+
+impl fmt::Display for StealPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            StealPolicy::Oldest => "oldest",
+            StealPolicy::Quietest => "quietest",
+            StealPolicy::SameNote => "same-note",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A change to the voices, as sent by a controller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceEvent {
+    NoteOn { note: u8, frequency: f32, velocity: f32 },
+    NoteOff { note: u8 },
+    /// Pitch bend in semitones.
+    PitchBend(f32),
+    /// Base wave table position, from 0 to 1, which modulation adds to.
+    Position(f32),
+    /// Base pan, from -1 (left) to 1 (right), which modulation adds to.
+    Pan(f32),
+    /// Mod wheel, from 0 to 1.
+    ModWheel(f32),
+    /// Channel aftertouch, from 0 to 1.
+    Aftertouch(f32),
+    /// Tempo in beats per minute, for LFO rates given as note values.
+    Tempo(f32),
+    Sustain(bool),
+    AllNotesOff,
+    AllSoundOff,
+}
+
+struct Voice {
+    unison: Unison,
+    // One per channel; a centred stack only uses the first.
+    filters: [Filter; 2],
+    filter_envelope: Envelope,
+    envelope: Envelope,
+    note: u8,
+    // Frequency of the note before pitch bend.
+    frequency: f32,
+    velocity: f32,
+    // Octaves from middle C, as read by the `key` modulation source.
+    key: f32,
+    lfos: Vec<Lfo>,
+    // The LFOs' values for the current sample.
+    lfo_values: Vec<f32>,
+    pan: f32,
+    // The key is up but the sustain pedal holds the note.
+    sustained: bool,
+    // Value of the manager's note counter when this voice was triggered.
+    started: u64,
+}
+
+impl Voice {
+    fn gate_on(&mut self) {
+        self.envelope.gate_on();
+        self.filter_envelope.gate_on();
+        for lfo in &mut self.lfos {
+            lfo.trigger();
+        }
+    }
+
+    fn gate_off(&mut self) {
+        self.envelope.gate_off();
+        self.filter_envelope.gate_off();
+    }
+
+    fn reset(&mut self) {
+        self.envelope.reset();
+        self.filter_envelope.reset();
+    }
+
+    fn is_active(&self) -> bool {
+        self.envelope.is_active()
+    }
+
+    fn level(&self) -> f32 {
+        self.envelope.level() * self.velocity
+    }
+}
+
+pub struct VoiceManager {
+    sample_rate: u32,
+    wave_table: Arc<MipMappedTable>,
+    voices: Vec<Voice>,
+    policy: StealPolicy,
+    envelope: Adsr,
+    filter: Option<FilterSettings>,
+    filter_modulation: FilterModulation,
+    unison: UnisonSettings,
+    interpolation: Interpolation,
+    modulation: ModMatrix,
+    gain: f32,
+    notes_played: u64,
+    // Frequency ratio of the current pitch bend.
+    bend: f32,
+    position: f32,
+    pan: f32,
+    pan_law: PanLaw,
+    mod_wheel: f32,
+    aftertouch: f32,
+    sustain: bool,
+    // The frame being played by the `Iterator`, and the channel it gives out next.
+    frame: [f32; 2],
+    channel: usize,
+}
+
+impl VoiceManager {
+    /// Creates `polyphony` voices that all read from `wave_table`.
+    pub fn new(sample_rate: u32, wave_table: impl Into<Arc<MipMappedTable>>, polyphony: usize, policy: StealPolicy) -> VoiceManager {
+        assert!(polyphony > 0, "need at least one voice");
+        let wave_table = wave_table.into();
+        let voices = (0..polyphony)
+            .map(|index| Voice {
+                unison: Unison::new(sample_rate, wave_table.clone(), UnisonSettings::default(), index as u32),
+                filters: [Filter::new(sample_rate, FilterSettings::default()), Filter::new(sample_rate, FilterSettings::default())],
+                filter_envelope: Envelope::new(sample_rate, FilterModulation::default().envelope),
+                envelope: Envelope::new(sample_rate, Adsr::default()),
+                note: 0,
+                frequency: 0.0,
+                velocity: 0.0,
+                key: 0.0,
+                lfos: Vec::new(),
+                lfo_values: Vec::new(),
+                pan: 0.0,
+                sustained: false,
+                started: 0,
+            })
+            .collect();
+        VoiceManager {
+            sample_rate,
+            wave_table,
+            voices,
+            policy,
+            envelope: Adsr::default(),
+            filter: None,
+            filter_modulation: FilterModulation::default(),
+            unison: UnisonSettings::default(),
+            interpolation: Interpolation::default(),
+            modulation: ModMatrix::default(),
+            gain: 1.0 / (polyphony as f32).sqrt(),
+            notes_played: 0,
+            bend: 1.0,
+            position: 0.0,
+            pan: 0.0,
+            pan_law: PanLaw::default(),
+            mod_wheel: 0.0,
+            aftertouch: 0.0,
+            sustain: false,
+            frame: [0.0; 2],
+            channel: 0,
+        }
+    }
+
+    pub fn polyphony(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn policy(&self) -> StealPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: StealPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn wave_table(&self) -> &Arc<MipMappedTable> {
+        &self.wave_table
+    }
+
+    /// Moves every voice to another table, keeping notes sounding at their pitch and phase, and
+    /// returns the previous table. Nothing is freed here as long as the caller keeps it.
+    pub fn set_wave_table(&mut self, wave_table: Arc<MipMappedTable>) -> Arc<MipMappedTable> {
+        for voice in &mut self.voices {
+            voice.unison.set_wave_table(&wave_table);
+            for lfo in &mut voice.lfos {
+                lfo.set_wave_table(&wave_table);
+            }
+        }
+        std::mem::replace(&mut self.wave_table, wave_table)
+    }
+
+    pub fn envelope(&self) -> Adsr {
+        self.envelope
+    }
+
+    /// Sets the amplitude envelope of every voice; sounding notes pick it up at their next stage.
+    pub fn set_envelope(&mut self, envelope: Adsr) {
+        self.envelope = envelope;
+        for voice in &mut self.voices {
+            voice.envelope.set_settings(envelope);
+        }
+    }
+
+    pub fn filter_settings(&self) -> Option<FilterSettings> {
+        self.filter
+    }
+
+    /// Filters every voice with `settings`, or turns the filters off for `None`. Sounding notes
+    /// keep their filter state.
+    pub fn set_filter(&mut self, settings: Option<FilterSettings>) {
+        self.filter = settings;
+        if let Some(settings) = settings {
+            for voice in &mut self.voices {
+                for filter in &mut voice.filters {
+                    filter.set_settings(settings);
+                }
+            }
+        }
+    }
+
+    pub fn filter_modulation(&self) -> FilterModulation {
+        self.filter_modulation
+    }
+
+    /// Sets the filter envelope and how far it, the note and the velocity move the cutoff.
+    pub fn set_filter_modulation(&mut self, modulation: FilterModulation) {
+        self.filter_modulation = modulation;
+        for voice in &mut self.voices {
+            voice.filter_envelope.set_settings(modulation.envelope);
+        }
+    }
+
+    pub fn unison(&self) -> UnisonSettings {
+        self.unison
+    }
+
+    /// Gives every voice a unison stack built for `settings`. This allocates, so it is meant for
+    /// setting up the voices rather than for the audio thread.
+    pub fn set_unison(&mut self, settings: UnisonSettings) {
+        self.unison = settings;
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            voice.unison = Unison::new(self.sample_rate, self.wave_table.clone(), settings, index as u32);
+            voice.unison.set_interpolation(self.interpolation);
+            voice.unison.set_frequency(voice.frequency * self.bend);
+            voice.unison.set_position(self.position);
+        }
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    /// Changes how every oscillator reads the wave table between its entries.
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+        for voice in &mut self.voices {
+            voice.unison.set_interpolation(interpolation);
+        }
+    }
+
+    pub fn modulation(&self) -> &ModMatrix {
+        &self.modulation
+    }
+
+    /// Replaces the modulation matrix, giving every voice its LFOs. This allocates, so it is
+    /// meant for setting up the voices rather than for the audio thread.
+    pub fn set_modulation(&mut self, modulation: ModMatrix) {
+        for voice in &mut self.voices {
+            voice.lfos = modulation
+                .lfos()
+                .iter()
+                .map(|settings| Lfo::new(self.sample_rate, *settings, modulation.tempo(), &self.wave_table))
+                .collect();
+            voice.lfo_values = vec![0.0; voice.lfos.len()];
+        }
+        self.modulation = modulation;
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.modulation.tempo()
+    }
+
+    /// Changes the tempo in beats per minute that LFOs synced to note values follow, including
+    /// those already running.
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.modulation.set_tempo(tempo);
+        for lfo in self.voices.iter_mut().flat_map(|voice| &mut voice.lfos) {
+            lfo.set_tempo(tempo);
+        }
+    }
+
+    /// Output gain applied to the mix; defaults to `1 / sqrt(polyphony)`.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Bends every voice by `semitones`, up or down.
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        self.bend = 2f32.powf(semitones / 12.0);
+        for voice in &mut self.voices {
+            voice.unison.set_frequency(voice.frequency * self.bend);
+        }
+    }
+
+    /// Moves every voice to `position` in the wave table, from 0 to 1.
+    pub fn set_position(&mut self, position: f32) {
+        self.position = position.clamp(0.0, 1.0);
+        for voice in &mut self.voices {
+            voice.unison.set_position(self.position);
+        }
+    }
+
+    /// Places every voice at `pan`, from -1 (left) to 1 (right), before modulation.
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1.0, 1.0);
+    }
+
+    pub fn pan(&self) -> f32 {
+        self.pan
+    }
+
+    /// Sets how the voices' gains in the two channels follow their pan.
+    pub fn set_pan_law(&mut self, law: PanLaw) {
+        self.pan_law = law;
+    }
+
+    pub fn pan_law(&self) -> PanLaw {
+        self.pan_law
+    }
+
+    /// Sets the mod wheel, from 0 to 1, for the routes that read it.
+    pub fn set_mod_wheel(&mut self, value: f32) {
+        self.mod_wheel = value.clamp(0.0, 1.0);
+    }
+
+    /// Sets the channel aftertouch, from 0 to 1, for the routes that read it.
+    pub fn set_aftertouch(&mut self, value: f32) {
+        self.aftertouch = value.clamp(0.0, 1.0);
+    }
+
+    /// Presses or lifts the sustain pedal. Lifting it releases the notes whose keys are up.
+    pub fn set_sustain(&mut self, sustain: bool) {
+        self.sustain = sustain;
+        if !sustain {
+            for voice in self.voices.iter_mut().filter(|voice| voice.sustained) {
+                voice.sustained = false;
+                voice.gate_off();
+            }
+        }
+    }
+
+    pub fn sustain(&self) -> bool {
+        self.sustain
+    }
+
+    pub fn handle(&mut self, event: VoiceEvent) {
+        match event {
+            VoiceEvent::NoteOn { note, frequency, velocity } => self.note_on(note, frequency, velocity),
+            VoiceEvent::NoteOff { note } => self.note_off(note),
+            VoiceEvent::PitchBend(semitones) => self.set_pitch_bend(semitones),
+            VoiceEvent::Position(position) => self.set_position(position),
+            VoiceEvent::Pan(pan) => self.set_pan(pan),
+            VoiceEvent::ModWheel(value) => self.set_mod_wheel(value),
+            VoiceEvent::Aftertouch(value) => self.set_aftertouch(value),
+            VoiceEvent::Tempo(tempo) => self.set_tempo(tempo),
+            VoiceEvent::Sustain(sustain) => self.set_sustain(sustain),
+            VoiceEvent::AllNotesOff => self.all_notes_off(),
+            VoiceEvent::AllSoundOff => self.all_sound_off(),
+        }
+    }
+
+    /// Number of voices currently sounding, including those in their release.
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.is_active()).count()
+    }
+
+    /// Note numbers of the sounding voices, in voice order.
+    pub fn active_notes(&self) -> Vec<u8> {
+        self.voices.iter().filter(|voice| voice.is_active()).map(|voice| voice.note).collect()
+    }
+
+    /// Note numbers of the voices whose key is still held, in voice order.
+    pub fn held_notes(&self) -> Vec<u8> {
+        self.voices
+            .iter()
+            .filter(|voice| voice.envelope.is_held() && !voice.sustained)
+            .map(|voice| voice.note)
+            .collect()
+    }
+
+    /// Pans of the sounding voices after modulation, from -1 (left) to 1 (right), in voice order.
+    pub fn pans(&self) -> Vec<f32> {
+        self.voices.iter().filter(|voice| voice.is_active()).map(|voice| voice.pan).collect()
+    }
+
+    /// Starts `note` at `frequency`. `velocity` is the note's gain, from 0 to 1.
+    pub fn note_on(&mut self, note: u8, frequency: f32, velocity: f32) {
+        let index = self.allocate(note);
+        self.notes_played += 1;
+        let voice = &mut self.voices[index];
+        // A stolen or retriggered voice keeps its phase and restarts the attack from its current
+        // level, so the takeover does not click.
+        if !voice.is_active() {
+            voice.unison.reset_phase();
+            for filter in &mut voice.filters {
+                filter.reset();
+            }
+            voice.filter_envelope.reset();
+        }
+        voice.unison.set_frequency(frequency * self.bend);
+        voice.unison.set_position(self.position);
+        voice.gate_on();
+        voice.note = note;
+        voice.frequency = frequency;
+        voice.velocity = velocity.clamp(0.0, 1.0);
+        voice.key = (frequency / KEY_TRACKING_CENTRE).log2();
+        voice.sustained = false;
+        voice.started = self.notes_played;
+    }
+
+    /// Releases every voice holding `note`, or leaves it to the sustain pedal if that is down.
+    pub fn note_off(&mut self, note: u8) {
+        let sustain = self.sustain;
+        for voice in self.voices.iter_mut().filter(|voice| voice.envelope.is_held() && voice.note == note) {
+            if sustain {
+                voice.sustained = true;
+            } else {
+                voice.gate_off();
+            }
+        }
+    }
+
+    /// Releases every held voice, including those held by the sustain pedal.
+    pub fn all_notes_off(&mut self) {
+        for voice in &mut self.voices {
+            voice.sustained = false;
+            voice.gate_off();
+        }
+    }
+
+    /// Silences every voice at once, skipping the release.
+    pub fn all_sound_off(&mut self) {
+        for voice in &mut self.voices {
+            voice.sustained = false;
+            voice.reset();
+        }
+    }
+
+    fn allocate(&self, note: u8) -> usize {
+        if self.policy == StealPolicy::SameNote {
+            if let Some(index) = self.voices.iter().position(|voice| voice.is_active() && voice.note == note) {
+                return index;
+            }
+        }
+        if let Some(index) = self.voices.iter().position(|voice| !voice.is_active()) {
+            return index;
+        }
+        let candidates = self.voices.iter().enumerate();
+        let stolen = match self.policy {
+            StealPolicy::Oldest | StealPolicy::SameNote => {
+                candidates.min_by_key(|(_, voice)| (voice.envelope.is_held(), voice.started))
+            }
+            StealPolicy::Quietest => candidates.min_by(|(_, a), (_, b)| {
+                a.level().total_cmp(&b.level()).then(a.started.cmp(&b.started))
+            }),
+        };
+        stolen.map(|(index, _)| index).unwrap()
+    }
+
+    /// Mixes one stereo frame of every sounding voice.
+    pub fn render_frame(&mut self) -> [f32; 2] {
+        let modulates_pitch = self.modulation.modulates(ModDestination::Pitch);
+        let modulates_position = self.modulation.modulates(ModDestination::Position);
+        let mut mix = [0.0; 2];
+        for voice in self.voices.iter_mut().filter(|voice| voice.is_active()) {
+            let envelope = voice.envelope.next_level();
+            let filter_envelope = voice.filter_envelope.next_level();
+            for (value, lfo) in voice.lfo_values.iter_mut().zip(&mut voice.lfos) {
+                *value = lfo.next_value();
+            }
+            let modulation = self.modulation.evaluate(&SourceValues {
+                lfos: &voice.lfo_values,
+                envelope,
+                filter_envelope,
+                velocity: voice.velocity,
+                mod_wheel: self.mod_wheel,
+                aftertouch: self.aftertouch,
+                key: voice.key,
+            });
+            if modulates_pitch {
+                voice.unison.set_frequency(voice.frequency * self.bend * modulation.pitch_ratio());
+            }
+            if modulates_position {
+                voice.unison.set_position(self.position + modulation.position);
+            }
+            voice.pan = (self.pan + modulation.pan).clamp(-1.0, 1.0);
+
+            let channels = if voice.unison.is_centred() { 1 } else { 2 };
+            let mut frame = if channels == 1 {
+                let sample = voice.unison.next_sample();
+                [sample, sample]
+            } else {
+                voice.unison.set_pan(voice.pan, self.pan_law);
+                voice.unison.next_frame()
+            };
+            if let Some(filter) = self.filter {
+                let cutoff = self.filter_modulation.cutoff(filter.cutoff, filter_envelope, voice.frequency, voice.velocity);
+                for (sample, filter) in frame.iter_mut().zip(&mut voice.filters).take(channels) {
+                    filter.set_cutoff(cutoff * modulation.cutoff_ratio());
+                    *sample = filter.process(*sample);
+                }
+                if channels == 1 {
+                    frame[1] = frame[0];
+                }
+            }
+            let level = envelope * voice.velocity * modulation.gain();
+            // a spread stack has already placed its copies
+            let pan = if channels == 1 { self.pan_law.gains(voice.pan) } else { [1.0, 1.0] };
+            mix[0] += frame[0] * (level * pan[0]);
+            mix[1] += frame[1] * (level * pan[1]);
+        }
+        [mix[0] * self.gain, mix[1] * self.gain]
+    }
+}
+
+/// Gives out the frames of [`VoiceManager::render_frame`] one sample at a time, left first.
+impl Iterator for VoiceManager {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.channel == 0 {
+            self.frame = self.render_frame();
+        }
+        let sample = self.frame[self.channel];
+        self.channel = (self.channel + 1) % CHANNELS as usize;
+        Some(sample)
+    }
+}
+
+impl Source for VoiceManager {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::EnvelopeCurve;
+    use crate::wavetable::WaveTable;
+
+    // Instant attack, full sustain and a 10 ms release.
+    fn manager(polyphony: usize, policy: StealPolicy) -> VoiceManager {
+        let table = MipMappedTable::from(WaveTable::sine(64));
+        let mut voices = VoiceManager::new(44100, table, polyphony, policy);
+        voices.set_envelope(Adsr::new(0.0, 0.0, 1.0, 0.01, EnvelopeCurve::Linear));
+        voices
+    }
+
+    // The left channel of the next `frames` frames, which for a centred voice is the same as the
+    // right, brought back up to the level of the mono mix.
+    fn left(voices: &mut VoiceManager, frames: usize) -> impl Iterator<Item = f32> + '_ {
+        let centre = PanLaw::default().gains(0.0)[0];
+        std::iter::repeat_with(move || voices.render_frame()[0] / centre).take(frames)
+    }
+
+    #[test]
+    fn is_silent_until_a_note_is_played() {
+        let mut voices = manager(4, StealPolicy::Oldest);
+        assert!(left(&mut voices, 100).all(|sample| sample == 0.0));
+
+        voices.note_on(69, 440.0, 1.0);
+        assert_eq!(voices.active_voices(), 1);
+        assert!(left(&mut voices, 100).any(|sample| sample.abs() > 0.1));
+
+        voices.note_off(69);
+        assert_eq!(voices.active_voices(), 1);
+        assert!(voices.held_notes().is_empty());
+        let release: Vec<f32> = left(&mut voices, 441).collect();
+        assert!(release[..100].iter().any(|sample| sample.abs() > 0.1));
+        assert_eq!(voices.active_voices(), 0);
+        assert!(left(&mut voices, 100).all(|sample| sample == 0.0));
+    }
+
+    #[test]
+    fn note_off_releases_with_the_envelope() {
+        let mut voices = manager(1, StealPolicy::Oldest);
+        voices.set_gain(1.0);
+        voices.set_envelope(Adsr::new(0.0, 0.0, 1.0, 0.1, EnvelopeCurve::Linear));
+        voices.note_on(69, 441.0, 1.0);
+        left(&mut voices, 100).for_each(drop);
+        voices.note_off(69);
+        // Half-way through the release the peak has dropped to about one half.
+        let release: Vec<f32> = left(&mut voices, 4410).collect();
+        let peak = release[2150..2250].iter().fold(0.0f32, |p, s| p.max(s.abs()));
+        assert!((peak - 0.5).abs() < 0.02, "peak {}", peak);
+        assert_eq!(voices.active_voices(), 0);
+    }
+
+    #[test]
+    fn releasing_voices_are_stolen_first() {
+        let mut voices = manager(2, StealPolicy::Oldest);
+        voices.note_on(60, 261.6, 1.0);
+        voices.note_on(64, 329.6, 1.0);
+        voices.note_off(64);
+        voices.note_on(67, 392.0, 1.0);
+        assert_eq!(voices.active_notes(), vec![60, 67]);
+    }
+
+    #[test]
+    fn mixes_voices_with_velocity() {
+        let mut single = manager(2, StealPolicy::Oldest);
+        single.set_gain(1.0);
+        single.note_on(69, 441.0, 0.5);
+        let mut double = manager(2, StealPolicy::Oldest);
+        double.set_gain(1.0);
+        double.note_on(69, 441.0, 0.5);
+        double.note_on(70, 441.0, 0.5);
+        for _ in 0..100 {
+            let (a, b) = (single.render_frame()[0], double.render_frame()[0]);
+            assert!((2.0 * a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn never_exceeds_its_polyphony() {
+        let mut voices = manager(3, StealPolicy::Oldest);
+        for note in 60..70 {
+            voices.note_on(note, 440.0, 1.0);
+        }
+        assert_eq!(voices.active_voices(), 3);
+        assert_eq!(voices.active_notes(), vec![69, 67, 68]);
+    }
+
+    #[test]
+    fn oldest_policy_steals_the_first_note() {
+        let mut voices = manager(2, StealPolicy::Oldest);
+        voices.note_on(60, 261.6, 1.0);
+        voices.note_on(64, 329.6, 1.0);
+        voices.note_on(67, 392.0, 1.0);
+        assert_eq!(voices.active_notes(), vec![67, 64]);
+    }
+
+    #[test]
+    fn quietest_policy_steals_the_softest_note() {
+        let mut voices = manager(2, StealPolicy::Quietest);
+        voices.note_on(60, 261.6, 1.0);
+        voices.note_on(64, 329.6, 0.2);
+        voices.note_on(67, 392.0, 1.0);
+        assert_eq!(voices.active_notes(), vec![60, 67]);
+    }
+
+    #[test]
+    fn same_note_policy_retriggers_instead_of_doubling() {
+        let mut voices = manager(4, StealPolicy::SameNote);
+        voices.note_on(60, 261.6, 1.0);
+        voices.note_on(60, 261.6, 1.0);
+        assert_eq!(voices.active_notes(), vec![60]);
+
+        let mut voices = manager(4, StealPolicy::Oldest);
+        voices.note_on(60, 261.6, 1.0);
+        voices.note_on(60, 261.6, 1.0);
+        assert_eq!(voices.active_notes(), vec![60, 60]);
+        voices.note_off(60);
+        assert!(voices.held_notes().is_empty());
+    }
+
+    #[test]
+    fn sustain_pedal_holds_released_notes() {
+        let mut voices = manager(4, StealPolicy::Oldest);
+        voices.handle(VoiceEvent::Sustain(true));
+        voices.note_on(60, 261.6, 1.0);
+        voices.note_on(64, 329.6, 1.0);
+        voices.note_off(60);
+        assert_eq!(voices.held_notes(), vec![64]);
+        left(&mut voices, 1000).for_each(drop);
+        assert_eq!(voices.active_notes(), vec![60, 64]);
+
+        voices.handle(VoiceEvent::Sustain(false));
+        assert_eq!(voices.held_notes(), vec![64]);
+        left(&mut voices, 1000).for_each(drop);
+        assert_eq!(voices.active_notes(), vec![64]);
+    }
+
+    #[test]
+    fn pitch_bend_retunes_sounding_and_new_notes() {
+        // upward zero crossings in one second, which may miss the cycle that starts at sample 0
+        fn zero_crossings(voices: &mut VoiceManager) -> usize {
+            let rendered: Vec<f32> = left(voices, 44100).collect();
+            rendered.windows(2).filter(|pair| pair[0] <= 0.0 && pair[1] > 0.0).count()
+        }
+        let mut voices = manager(2, StealPolicy::Oldest);
+        voices.note_on(69, 441.0, 1.0);
+        assert!((440..=441).contains(&zero_crossings(&mut voices)));
+        // an octave up doubles the frequency
+        voices.handle(VoiceEvent::PitchBend(12.0));
+        assert!((881..=882).contains(&zero_crossings(&mut voices)));
+
+        voices.all_sound_off();
+        voices.note_on(57, 220.5, 1.0);
+        assert!((440..=441).contains(&zero_crossings(&mut voices)));
+    }
+
+    #[test]
+    fn filters_every_voice_when_set() {
+        use crate::filter::{FilterMode, FilterModel};
+        fn peak(voices: &mut VoiceManager) -> f32 {
+            voices.note_on(69, 2205.0, 1.0);
+            let peak = left(voices, 4410).skip(2205).fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            voices.all_sound_off();
+            peak
+        }
+        let mut voices = manager(1, StealPolicy::Oldest);
+        voices.set_gain(1.0);
+        assert!(peak(&mut voices) > 0.99);
+
+        // two octaves above a 12 dB per octave cutoff
+        let settings = FilterSettings::new(FilterModel::StateVariable, FilterMode::Lowpass, 551.25, 0.0);
+        voices.set_filter(Some(settings));
+        assert_eq!(voices.filter_settings(), Some(settings));
+        assert!(peak(&mut voices) < 0.07);
+
+        voices.set_filter(None);
+        assert!(peak(&mut voices) > 0.99);
+    }
+
+    #[test]
+    fn filter_envelope_and_key_tracking_move_the_cutoff() {
+        use crate::filter::{FilterMode, FilterModel};
+        fn peak(samples: &[f32]) -> f32 {
+            samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+        }
+        let mut voices = manager(1, StealPolicy::Oldest);
+        voices.set_gain(1.0);
+        // a 2205 Hz note two octaves above the cutoff, with an envelope that opens the filter by
+        // three octaves and closes it again within 50 ms
+        voices.set_filter(Some(FilterSettings::new(FilterModel::StateVariable, FilterMode::Lowpass, 551.25, 0.0)));
+        let envelope = Adsr::new(0.0, 0.05, 0.0, 0.0, EnvelopeCurve::Linear);
+        voices.set_filter_modulation(FilterModulation::new(envelope, 3.0, 0.0, 0.0));
+        voices.note_on(69, 2205.0, 1.0);
+        let opened: Vec<f32> = left(&mut voices, 4410).collect();
+        assert!(peak(&opened[100..300]) > 0.5);
+        assert!(peak(&opened[3000..]) < 0.07);
+
+        // a negative amount closes the filter instead, and softer notes move it less
+        voices.all_sound_off();
+        voices.set_filter_modulation(FilterModulation::new(Adsr::new(0.0, 0.0, 1.0, 0.0, EnvelopeCurve::Linear), -1.0, 0.0, 1.0));
+        voices.note_on(69, 2205.0, 1.0);
+        let loud = peak(&left(&mut voices, 4410).collect::<Vec<_>>()[2205..]);
+        voices.all_sound_off();
+        voices.note_on(69, 2205.0, 0.5);
+        let soft = peak(&left(&mut voices, 4410).collect::<Vec<_>>()[2205..]) / 0.5;
+        assert!(loud < 0.02 && soft > loud * 1.5, "{} {}", loud, soft);
+
+        // with full key tracking the cutoff follows the note up to it
+        voices.all_sound_off();
+        voices.set_filter(Some(FilterSettings::new(FilterModel::StateVariable, FilterMode::Lowpass, 1000.0, 0.0)));
+        voices.set_filter_modulation(FilterModulation::new(Adsr::default(), 0.0, 1.0, 0.0));
+        voices.note_on(69, 4.0 * crate::filter::KEY_TRACKING_CENTRE, 1.0);
+        let tracked = peak(&left(&mut voices, 4410).collect::<Vec<_>>()[2205..]);
+        assert!(tracked > 0.9, "{}", tracked);
+    }
+
+    #[test]
+    fn lfo_routes_move_amplitude_and_pitch() {
+        use crate::lfo::{LfoRate, LfoSettings, LfoShape, DEFAULT_TEMPO};
+        use crate::modulation::{ModSource, Route};
+        use crate::wavetable::Waveform;
+        // a still LFO held at the peak of a sine, so every route gets its full depth
+        let held = LfoSettings::new(LfoShape::Wave(Waveform::Sine), LfoRate::Hertz(0.0), 0.25, 0.0);
+        let render = |routes: Vec<Route>| {
+            let mut voices = manager(1, StealPolicy::Oldest);
+            voices.set_modulation(ModMatrix::new(vec![held], routes, DEFAULT_TEMPO).unwrap());
+            voices.note_on(69, 441.0, 1.0);
+            left(&mut voices, 4410).collect::<Vec<f32>>()
+        };
+        let plain = render(Vec::new());
+        let quieter = render(vec![Route::new(ModSource::Lfo(0), ModDestination::Amplitude, -0.5)]);
+        for (plain, quieter) in plain.iter().zip(&quieter) {
+            assert!((plain * 0.5 - quieter).abs() < 1e-6);
+        }
+
+        let crossings = |samples: &[f32]| samples.windows(2).filter(|pair| pair[0] <= 0.0 && pair[1] > 0.0).count();
+        let octave_up = render(vec![Route::new(ModSource::Lfo(0), ModDestination::Pitch, 12.0)]);
+        assert!((43..=44).contains(&crossings(&plain)));
+        assert!((87..=88).contains(&crossings(&octave_up)));
+    }
+
+    #[test]
+    fn routes_read_the_wheel_velocity_and_key() {
+        use crate::lfo::DEFAULT_TEMPO;
+        use crate::modulation::{ModSource, Route};
+        use crate::wavetable::MultiFrameTable;
+        // frames that hold 0 and 1, so the output is the table position times the level
+        let frames = MultiFrameTable::new(vec![WaveTable::from_samples(vec![0.0; 8]), WaveTable::from_samples(vec![1.0; 8])]);
+        let mut voices = VoiceManager::new(44100, MipMappedTable::single(frames), 2, StealPolicy::Oldest);
+        voices.set_envelope(Adsr::new(0.0, 0.0, 1.0, 0.01, EnvelopeCurve::Linear));
+        voices.set_gain(1.0);
+
+        // the default matrix keeps the mod wheel on the table position
+        voices.note_on(60, 261.6, 1.0);
+        voices.handle(VoiceEvent::ModWheel(0.25));
+        assert!((left(&mut voices, 1).next().unwrap() - 0.25).abs() < 1e-6);
+        voices.all_sound_off();
+
+        let routes = vec![
+            Route::new(ModSource::Velocity, ModDestination::Position, 1.0),
+            Route::new(ModSource::Aftertouch, ModDestination::Amplitude, 1.0),
+            Route::new(ModSource::Key, ModDestination::Pan, 0.5),
+        ];
+        voices.set_modulation(ModMatrix::new(Vec::new(), routes, DEFAULT_TEMPO).unwrap());
+        voices.note_on(72, 2.0 * KEY_TRACKING_CENTRE, 0.5);
+        // position 0.5 at half velocity, an octave above middle C so panned halfway to the right
+        let frame = voices.render_frame();
+        assert!((voices.pans()[0] - 0.5).abs() < 1e-6);
+        let [left, right] = PanLaw::EqualPower.gains(0.5);
+        assert!((frame[0] - 0.25 * left).abs() < 1e-6 && (frame[1] - 0.25 * right).abs() < 1e-6);
+        voices.handle(VoiceEvent::Aftertouch(1.0));
+        let frame = voices.render_frame();
+        assert!((frame[0] - 0.5 * left).abs() < 1e-6 && (frame[1] - 0.5 * right).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pans_every_voice_from_the_base_pan() {
+        use crate::lfo::DEFAULT_TEMPO;
+        use crate::modulation::{ModSource, Route};
+        let mut voices = manager(2, StealPolicy::Oldest);
+        voices.note_on(69, 441.0, 1.0);
+        let centre: Vec<[f32; 2]> = (0..100).map(|_| voices.render_frame()).collect();
+
+        // hard left without any pan route: nothing on the right
+        voices.all_sound_off();
+        voices.handle(VoiceEvent::Pan(-1.0));
+        voices.note_on(69, 441.0, 1.0);
+        for frame in &centre {
+            let [left, right] = voices.render_frame();
+            assert_eq!(right, 0.0);
+            assert!((left - frame[0] / PanLaw::EqualPower.gains(0.0)[0]).abs() < 1e-5);
+        }
+
+        // a route moves each voice on from the base pan: an octave above middle C is back in the centre
+        let routes = vec![Route::new(ModSource::Key, ModDestination::Pan, 1.0)];
+        voices.set_modulation(ModMatrix::new(Vec::new(), routes, DEFAULT_TEMPO).unwrap());
+        voices.note_on(72, 2.0 * KEY_TRACKING_CENTRE, 1.0);
+        voices.render_frame();
+        assert_eq!(voices.pans().last(), Some(&0.0));
+    }
+
+    #[test]
+    fn plays_notes_on_unison_stacks() {
+        use crate::unison::{DetuneCurve, UnisonPhase};
+        let render = |settings: Option<UnisonSettings>| {
+            let mut voices = manager(2, StealPolicy::Oldest);
+            if let Some(settings) = settings {
+                voices.set_unison(settings);
+                assert_eq!(voices.unison(), settings);
+            }
+            voices.note_on(69, 441.0, 1.0);
+            left(&mut voices, 4410).collect::<Vec<f32>>()
+        };
+        let plain = render(None);
+        // blended all the way to the centre, only the undetuned oscillator sounds
+        let centre = render(Some(UnisonSettings::new(3, 20.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.0), 0.0)));
+        assert_eq!(plain, centre);
+
+        // three equal copies start a third of a cycle apart, so the onset is no louder than one
+        // oscillator, then the detuned ones drift away from the note
+        let stacked = render(Some(UnisonSettings::new(3, 20.0, DetuneCurve::Linear, 0.0, UnisonPhase::Fixed(0.0), 0.5)));
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak(&stacked[..441]) <= peak(&plain[..441]) * 1.05, "{} vs {}", peak(&stacked[..441]), peak(&plain[..441]));
+        let difference = plain.iter().zip(&stacked).fold(0.0f32, |peak, (a, b)| peak.max((a - b).abs()));
+        assert!(difference > 0.1, "{}", difference);
+    }
+
+    #[test]
+    fn spreads_unison_stacks_across_the_stereo_field() {
+        use crate::unison::{DetuneCurve, UnisonPhase};
+        let render = |spread: f32| {
+            let mut voices = manager(2, StealPolicy::Oldest);
+            voices.set_unison(UnisonSettings::new(3, 20.0, DetuneCurve::Linear, spread, UnisonPhase::Fixed(0.0), 0.5));
+            voices.note_on(69, 441.0, 1.0);
+            (0..4410).map(|_| voices.render_frame()).collect::<Vec<[f32; 2]>>()
+        };
+        assert!(render(0.0).iter().all(|[left, right]| left == right));
+        let wide = render(1.0);
+        let difference = wide.iter().fold(0.0f32, |peak, [left, right]| peak.max((left - right).abs()));
+        assert!(difference > 0.1, "{}", difference);
+
+        // the iterator interleaves the same frames, left first
+        let mut voices = manager(2, StealPolicy::Oldest);
+        voices.set_unison(UnisonSettings::new(3, 20.0, DetuneCurve::Linear, 1.0, UnisonPhase::Fixed(0.0), 0.5));
+        voices.note_on(69, 441.0, 1.0);
+        assert_eq!(voices.channels(), CHANNELS);
+        let samples: Vec<f32> = voices.take(4410 * 2).collect();
+        assert!(samples.chunks(2).zip(&wide).all(|(interleaved, frame)| interleaved == frame));
+    }
+
+    #[test]
+    fn parses_policy_names() {
+        assert_eq!("same-note".parse::<StealPolicy>(), Ok(StealPolicy::SameNote));
+        assert_eq!("Quietest".parse::<StealPolicy>(), Ok(StealPolicy::Quietest));
+        assert!("newest".parse::<StealPolicy>().is_err());
+    }
+}
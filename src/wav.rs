@@ -0,0 +1,515 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::wavetable::MultiFrameTable;
+
+/*
+    Wavetables are commonly shared as WAV files: either one single cycle, or many cycles of the
+    same length laid end to end. Serum and compatible synths mark the second kind with a `clm `
+    chunk whose text starts with `<!>` followed by the frame size, e.g. `<!>2048 01000000 ...`.
+
+    We only need the samples, so instead of pulling in a full audio decoder this module walks the
+    RIFF chunks itself and decodes the common PCM and float formats, mixing every channel to mono.
+ */
+
+#[derive(Debug)]
+pub enum WavError {
+    Io(io::Error),
+    NotWav,
+    MissingChunk(&'static str),
+    Unsupported(String),
+    /// The data chunk is shorter than one frame of the requested size.
+    NoFrames { samples: usize, frame_size: usize },
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavError::Io(err) => write!(f, "{}", err),
+            WavError::NotWav => write!(f, "not a RIFF/WAVE file"),
+            WavError::MissingChunk(id) => write!(f, "missing '{}' chunk", id),
+            WavError::Unsupported(what) => write!(f, "unsupported WAV format: {}", what),
+            WavError::NoFrames { samples, frame_size } => {
+                write!(f, "{} samples do not hold a single {}-sample frame", samples, frame_size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
+
+impl From<io::Error> for WavError {
+    fn from(err: io::Error) -> Self {
+        WavError::Io(err)
+    }
+}
+
+/// A decoded WAV file, mixed down to mono.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WavFile {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<f32>,
+    /// Frame size announced by a Serum-style `clm ` chunk, if any.
+    pub clm_frame_size: Option<usize>,
+}
+
+impl WavFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<WavFile, WavError> {
+        WavFile::read(File::open(path)?)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<WavFile, WavError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(WavError::NotWav);
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut clm_frame_size = None;
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let id = &bytes[offset..offset + 4];
+            let size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let start = offset + 8;
+            // Some writers put a bogus size on the last chunk; read what is there.
+            let end = (start + size).min(bytes.len());
+            let body = &bytes[start..end];
+            match id {
+                b"fmt " => format = Some(Format::parse(body)?),
+                b"data" => data = Some(body),
+                b"clm " => clm_frame_size = parse_clm(body),
+                _ => {}
+            }
+            // Chunks are padded to an even size.
+            offset = start + size + (size & 1);
+        }
+
+        let format = format.ok_or(WavError::MissingChunk("fmt "))?;
+        let data = data.ok_or(WavError::MissingChunk("data"))?;
+        Ok(WavFile {
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+            samples: format.decode(data),
+            clm_frame_size,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Pcm,
+    Float,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Format {
+    encoding: Encoding,
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+impl Format {
+    fn parse(body: &[u8]) -> Result<Format, WavError> {
+        if body.len() < 16 {
+            return Err(WavError::Unsupported("truncated fmt chunk".to_string()));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+        let mut tag = u16_at(0);
+        if tag == WAVE_FORMAT_EXTENSIBLE && body.len() >= 26 {
+            // The sub-format GUID starts with the plain format tag.
+            tag = u16_at(24);
+        }
+        let encoding = match tag {
+            WAVE_FORMAT_PCM => Encoding::Pcm,
+            WAVE_FORMAT_IEEE_FLOAT => Encoding::Float,
+            other => return Err(WavError::Unsupported(format!("format tag {:#06x}", other))),
+        };
+        let format = Format {
+            encoding,
+            channels: u16_at(2),
+            sample_rate: u32::from_le_bytes(body[4..8].try_into().unwrap()),
+            bits_per_sample: u16_at(14),
+        };
+        match (format.encoding, format.bits_per_sample) {
+            (Encoding::Pcm, 8 | 16 | 24 | 32) | (Encoding::Float, 32 | 64) if format.channels > 0 => Ok(format),
+            (encoding, bits) => Err(WavError::Unsupported(format!(
+                "{} channel {:?} at {} bits", format.channels, encoding, bits
+            ))),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Vec<f32> {
+        let width = self.bits_per_sample as usize / 8;
+        let channels = self.channels as usize;
+        data.chunks_exact(width * channels)
+            .map(|frame| {
+                let sum: f32 = frame.chunks_exact(width).map(|sample| self.decode_sample(sample)).sum();
+                sum / channels as f32
+            })
+            .collect()
+    }
+
+    fn decode_sample(&self, bytes: &[u8]) -> f32 {
+        match (self.encoding, bytes.len()) {
+            (Encoding::Pcm, 1) => (bytes[0] as f32 - 128.0) / 128.0,
+            (Encoding::Pcm, 2) => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            (Encoding::Pcm, 3) => {
+                // Place the 24 bits at the top of an i32 so the sign extends.
+                i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2147483648.0
+            }
+            (Encoding::Pcm, _) => i32::from_le_bytes(bytes.try_into().unwrap()) as f32 / 2147483648.0,
+            (Encoding::Float, 4) => f32::from_le_bytes(bytes.try_into().unwrap()),
+            (Encoding::Float, _) => f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+        }
+    }
+}
+
+/// Reads the frame size out of a `clm ` chunk such as `<!>2048 01000000 wavetable`.
+fn parse_clm(body: &[u8]) -> Option<usize> {
+    let text = String::from_utf8_lossy(body);
+    let rest = &text[text.find("<!>")? + 3..];
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok().filter(|size| *size > 0)
+}
+
+/*
+    Turning a WAV into something the oscillator can play: we split the samples into frames, bring
+    every frame to the engine's table length and normalize the table as a whole.
+
+    The frame size comes from, in order, the caller's override, the `clm ` chunk, or the length of
+    the whole file, which treats it as a single cycle.
+ */
+pub fn load_wavetable<P: AsRef<Path>>(path: P, frame_size: Option<usize>, table_len: usize) -> Result<MultiFrameTable, WavError> {
+    wavetable_from_wav(&WavFile::open(path)?, frame_size, table_len)
+}
+
+pub fn wavetable_from_wav(wav: &WavFile, frame_size: Option<usize>, table_len: usize) -> Result<MultiFrameTable, WavError> {
+    let frame_size = frame_size.or(wav.clm_frame_size).unwrap_or(wav.samples.len());
+    if frame_size == 0 || wav.samples.len() < frame_size {
+        return Err(WavError::NoFrames { samples: wav.samples.len(), frame_size });
+    }
+    let table = MultiFrameTable::from_concatenated(&wav.samples, frame_size);
+    Ok(table.resampled(table_len).normalized())
+}
+
+/*
+    Writing goes the other way for offline renders: a `fmt ` chunk and the interleaved samples in
+    one `data` chunk. Integer formats clip at full scale; 32-bit float keeps whatever overshoot the
+    mix has, and gets the `fact` chunk non-PCM formats are supposed to carry.
+ */
+
+/// Sample encoding of a written WAV file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl SampleFormat {
+    pub fn bits_per_sample(&self) -> u16 {
+        match self {
+            SampleFormat::Int16 => 16,
+            SampleFormat::Int24 => 24,
+            SampleFormat::Float32 => 32,
+        }
+    }
+
+    fn encode(&self, sample: f32, bytes: &mut Vec<u8>) {
+        match self {
+            SampleFormat::Int16 => {
+                let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                bytes.extend(value.to_le_bytes());
+            }
+            SampleFormat::Int24 => {
+                let value = (sample.clamp(-1.0, 1.0) * 8388607.0).round() as i32;
+                bytes.extend(&value.to_le_bytes()[..3]);
+            }
+            SampleFormat::Float32 => bytes.extend(sample.to_le_bytes()),
+        }
+    }
+}
+
+impl FromStr for SampleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "16" | "int16" => Ok(SampleFormat::Int16),
+            "24" | "int24" => Ok(SampleFormat::Int24),
+            "32f" | "32" | "float" | "float32" => Ok(SampleFormat::Float32),
+            _ => Err(format!("unknown sample format '{}' (expected 16, 24 or 32f)", s)),
+        }
+    }
+}
+
+impl fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SampleFormat::Int16 => "16",
+            SampleFormat::Int24 => "24",
+            SampleFormat::Float32 => "32f",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Lengths of the RIFF chunk and its data chunk for `frames` frames of `block_align` bytes, or an
+/// error where they do not fit the header's 32 bits.
+fn chunk_lengths(frames: usize, block_align: u16, float: bool) -> io::Result<(u32, u32)> {
+    let data_len = (frames as u64).saturating_mul(block_align as u64);
+    // fmt, optional fact and data chunk headers after the WAVE id
+    let headers_len = 4 + 8 + 16 + if float { 12 } else { 0 } + 8;
+    let chunks_len = data_len.saturating_add(headers_len + (data_len & 1));
+    match (u32::try_from(chunks_len), u32::try_from(data_len)) {
+        (Ok(chunks_len), Ok(data_len)) => Ok((chunks_len, data_len)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes of samples is too long for a WAV file", data_len),
+        )),
+    }
+}
+
+/// Writes interleaved `samples` as a WAV file with `channels` channels.
+pub fn write_wav<W: Write>(mut writer: W, samples: &[f32], channels: u16, sample_rate: u32, format: SampleFormat) -> io::Result<()> {
+    let bits = format.bits_per_sample();
+    let block_align = channels * bits / 8;
+    let frames = samples.len() / channels as usize;
+    let float = format == SampleFormat::Float32;
+    let (chunks_len, data_len) = chunk_lengths(frames, block_align, float)?;
+
+    let mut header = Vec::new();
+    header.extend(b"RIFF");
+    header.extend(chunks_len.to_le_bytes());
+    header.extend(b"WAVE");
+    header.extend(b"fmt ");
+    header.extend(16u32.to_le_bytes());
+    header.extend((if float { WAVE_FORMAT_IEEE_FLOAT } else { WAVE_FORMAT_PCM }).to_le_bytes());
+    header.extend(channels.to_le_bytes());
+    header.extend(sample_rate.to_le_bytes());
+    header.extend((sample_rate * block_align as u32).to_le_bytes());
+    header.extend(block_align.to_le_bytes());
+    header.extend(bits.to_le_bytes());
+    if float {
+        header.extend(b"fact");
+        header.extend(4u32.to_le_bytes());
+        header.extend((frames as u32).to_le_bytes());
+    }
+    header.extend(b"data");
+    header.extend(data_len.to_le_bytes());
+    writer.write_all(&header)?;
+
+    let mut data = Vec::with_capacity(data_len as usize + 1);
+    for &sample in &samples[..frames * channels as usize] {
+        format.encode(sample, &mut data);
+    }
+    if data_len & 1 == 1 {
+        data.push(0);
+    }
+    writer.write_all(&data)?;
+    writer.flush()
+}
+
+/// Writes `samples` to a WAV file at `path`; see [`write_wav`].
+pub fn save_wav<P: AsRef<Path>>(path: P, samples: &[f32], channels: u16, sample_rate: u32, format: SampleFormat) -> Result<(), WavError> {
+    let file = BufWriter::new(File::create(path)?);
+    Ok(write_wav(file, samples, channels, sample_rate, format)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::wavetable::WaveTable;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        if body.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn fmt_chunk(tag: u16, channels: u16, sample_rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut body = Vec::new();
+        body.extend(tag.to_le_bytes());
+        body.extend(channels.to_le_bytes());
+        body.extend(sample_rate.to_le_bytes());
+        body.extend((sample_rate * block_align as u32).to_le_bytes());
+        body.extend(block_align.to_le_bytes());
+        body.extend(bits.to_le_bytes());
+        chunk(b"fmt ", &body)
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend((body.len() as u32 + 4).to_le_bytes());
+        bytes.extend(b"WAVE");
+        bytes.extend(body);
+        bytes
+    }
+
+    fn float_wav(samples: &[f32], clm: Option<&str>) -> Vec<u8> {
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut chunks = vec![fmt_chunk(WAVE_FORMAT_IEEE_FLOAT, 1, 44100, 32)];
+        if let Some(text) = clm {
+            chunks.push(chunk(b"clm ", text.as_bytes()));
+        }
+        chunks.push(chunk(b"data", &data));
+        riff(&chunks)
+    }
+
+    #[test]
+    fn decodes_16_bit_stereo_to_mono() {
+        let data: Vec<u8> = [16384i16, -16384, 32767, 32767].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let bytes = riff(&[fmt_chunk(WAVE_FORMAT_PCM, 2, 48000, 16), chunk(b"data", &data)]);
+        let wav = WavFile::read(Cursor::new(bytes)).unwrap();
+        assert_eq!(wav.sample_rate, 48000);
+        assert_eq!(wav.channels, 2);
+        assert_eq!(wav.samples.len(), 2);
+        assert_eq!(wav.samples[0], 0.0);
+        assert!((wav.samples[1] - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn decodes_24_bit_pcm() {
+        // -0.5 and +0.25 of full scale.
+        let data = [0x00, 0x00, 0xC0, 0x00, 0x00, 0x20];
+        let bytes = riff(&[fmt_chunk(WAVE_FORMAT_PCM, 1, 44100, 24), chunk(b"data", &data)]);
+        let wav = WavFile::read(Cursor::new(bytes)).unwrap();
+        assert_eq!(wav.samples, vec![-0.5, 0.25]);
+    }
+
+    #[test]
+    fn reads_the_clm_frame_size() {
+        let bytes = float_wav(&[0.0; 8], Some("<!>2048 01000000 wavetable (www.xferrecords.com)"));
+        let wav = WavFile::read(Cursor::new(bytes)).unwrap();
+        assert_eq!(wav.clm_frame_size, Some(2048));
+        assert_eq!(parse_clm(b"no marker"), None);
+    }
+
+    #[test]
+    fn rejects_non_wav_input() {
+        assert!(matches!(WavFile::read(Cursor::new(b"OggS....".to_vec())), Err(WavError::NotWav)));
+        let bytes = riff(&[fmt_chunk(WAVE_FORMAT_PCM, 1, 44100, 16)]);
+        assert!(matches!(WavFile::read(Cursor::new(bytes)), Err(WavError::MissingChunk("data"))));
+        let bytes = riff(&[fmt_chunk(2, 1, 44100, 4), chunk(b"data", &[])]);
+        assert!(matches!(WavFile::read(Cursor::new(bytes)), Err(WavError::Unsupported(_))));
+    }
+
+    #[test]
+    fn splits_frames_using_the_clm_chunk() {
+        let mut samples: Vec<f32> = WaveTable::sine(16).samples().iter().map(|s| s * 0.5).collect();
+        samples.extend(WaveTable::saw(16).samples().iter().map(|s| s * 0.25));
+        let wav = WavFile::read(Cursor::new(float_wav(&samples, Some("<!>16 00000000")))).unwrap();
+
+        let table = wavetable_from_wav(&wav, None, 64).unwrap();
+        assert_eq!(table.frame_count(), 2);
+        assert_eq!(table.frame_len(), 64);
+        // Normalized as a whole: the louder sine frame now peaks at 1, the saw keeps half of that.
+        let sine = WaveTable::sine(64);
+        for (a, b) in table.frame(0).samples().iter().zip(sine.samples()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+        let saw = WaveTable::saw(16).resampled(64);
+        let peak = table.frame(1).samples().iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - 0.5).abs() < 0.05, "{}", peak);
+        for (a, b) in table.frame(1).samples().iter().zip(saw.samples()) {
+            assert!((a - b * 0.5).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn override_beats_clm_and_whole_file_is_one_cycle() {
+        let samples = WaveTable::sine(32).samples().to_vec();
+        let wav = WavFile::read(Cursor::new(float_wav(&samples, Some("<!>2048")))).unwrap();
+        assert_eq!(wavetable_from_wav(&wav, Some(16), 64).unwrap().frame_count(), 2);
+        assert!(matches!(wavetable_from_wav(&wav, None, 64), Err(WavError::NoFrames { .. })));
+
+        let wav = WavFile::read(Cursor::new(float_wav(&samples, None))).unwrap();
+        let table = wavetable_from_wav(&wav, None, 64).unwrap();
+        assert_eq!(table.frame_count(), 1);
+        assert_eq!(table.frame_len(), 64);
+    }
+
+    fn round_trip(samples: &[f32], channels: u16, format: SampleFormat) -> WavFile {
+        let mut bytes = Vec::new();
+        write_wav(&mut bytes, samples, channels, 22050, format).unwrap();
+        WavFile::read(Cursor::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn writes_every_sample_format() {
+        let samples = [0.0, 0.5, -0.5, 0.999, -1.0, 0.25];
+        for (format, tolerance) in [(SampleFormat::Int16, 1e-4), (SampleFormat::Int24, 1e-6), (SampleFormat::Float32, 0.0)] {
+            let wav = round_trip(&samples, 1, format);
+            assert_eq!(wav.sample_rate, 22050);
+            assert_eq!(wav.channels, 1);
+            assert_eq!(wav.samples.len(), samples.len());
+            for (read, written) in wav.samples.iter().zip(samples) {
+                assert!((read - written).abs() <= tolerance, "{}: {} vs {}", format, read, written);
+            }
+        }
+    }
+
+    #[test]
+    fn integer_formats_clip_and_float_does_not() {
+        let samples = [1.5, -2.0];
+        assert_eq!(round_trip(&samples, 1, SampleFormat::Int16).samples, vec![32767.0 / 32768.0, -32767.0 / 32768.0]);
+        assert_eq!(round_trip(&samples, 1, SampleFormat::Float32).samples, vec![1.5, -2.0]);
+    }
+
+    #[test]
+    fn writes_whole_frames_and_pads_odd_chunks() {
+        // three 24-bit stereo samples make one frame and a half
+        let mut bytes = Vec::new();
+        write_wav(&mut bytes, &[0.5, -0.5, 0.1], 2, 8000, SampleFormat::Int24).unwrap();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        let wav = WavFile::read(Cursor::new(bytes)).unwrap();
+        assert_eq!(wav.channels, 2);
+        assert_eq!(wav.samples.len(), 1);
+
+        let mut bytes = Vec::new();
+        write_wav(&mut bytes, &[0.5], 1, 8000, SampleFormat::Int24).unwrap();
+        assert_eq!(bytes.len(), 44 + 4);
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+    }
+
+    #[test]
+    fn refuses_files_too_long_for_the_header() {
+        assert_eq!(chunk_lengths(3, 6, false).unwrap(), (36 + 18, 18));
+        // the largest 16-bit stereo data chunk whose RIFF length still fits in 32 bits
+        let frames = (u32::MAX as usize - 36) / 4;
+        assert_eq!(chunk_lengths(frames, 4, false).unwrap(), (36 + frames as u32 * 4, frames as u32 * 4));
+        let err = chunk_lengths(frames + 1, 4, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // the fact chunk of a float file pushes the same length over
+        assert!(chunk_lengths(frames, 4, true).is_err());
+        assert!(chunk_lengths(usize::MAX / 8, 8, true).is_err());
+    }
+
+    #[test]
+    fn parses_sample_format_names() {
+        assert_eq!("16".parse::<SampleFormat>(), Ok(SampleFormat::Int16));
+        assert_eq!("24".parse::<SampleFormat>(), Ok(SampleFormat::Int24));
+        assert_eq!("32F".parse::<SampleFormat>(), Ok(SampleFormat::Float32));
+        assert!("8".parse::<SampleFormat>().is_err());
+    }
+}
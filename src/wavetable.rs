@@ -0,0 +1,481 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Samples per cycle used by the engine unless told otherwise.
+pub const DEFAULT_TABLE_SIZE: usize = 64;
+
+/*
+    A wave table is an array in memory, which contains 1 period of the waveform
+    we want to play out through our oscillator.
+
+    Every table except the plain sine is built additively: we sum sine partials up to, but not
+    including, the table's own Nyquist harmonic (len / 2). That keeps the stored cycle free of
+    the aliasing a naively drawn saw or square would already contain before it is ever played.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaveTable {
+    samples: Vec<f32>,
+}
+
+impl WaveTable {
+    pub fn from_samples(samples: Vec<f32>) -> WaveTable {
+        assert!(!samples.is_empty(), "a wave table needs at least one sample");
+        WaveTable { samples }
+    }
+
+    /*
+        We calculate the value of the sine waveform for arguments linearly increasing from
+        0 to 2π to calculate the sine value for argument.
+
+        By populating the wave_table array with the calculated sine values,
+         we generate a single cycle of a sine waveform within the specified range.
+         This waveform can then be used as a basis for creating more complex sounds in music synthesis applications.
+     */
+    pub fn sine(size: usize) -> WaveTable {
+        let mut wave_table: Vec<f32> = Vec::with_capacity(size);
+        for n in 0..size {
+            wave_table.push((2.0 * PI * n as f32 / size as f32).sin());
+        }
+        WaveTable::from_samples(wave_table)
+    }
+
+    /// Rising sawtooth: every harmonic at `1/n`.
+    pub fn saw(size: usize) -> WaveTable {
+        let mut builder = WaveTable::additive(size);
+        for n in 1..max_harmonic(size) + 1 {
+            builder = builder.harmonic(n, 1.0 / n as f32, PI);
+        }
+        builder.build()
+    }
+
+    /// Square: odd harmonics at `1/n`.
+    pub fn square(size: usize) -> WaveTable {
+        let mut builder = WaveTable::additive(size);
+        for n in (1..max_harmonic(size) + 1).step_by(2) {
+            builder = builder.harmonic(n, 1.0 / n as f32, 0.0);
+        }
+        builder.build()
+    }
+
+    /// Triangle: odd harmonics at `1/n²` with alternating sign.
+    pub fn triangle(size: usize) -> WaveTable {
+        let mut builder = WaveTable::additive(size);
+        for n in (1..max_harmonic(size) + 1).step_by(2) {
+            let phase = if n % 4 == 1 { 0.0 } else { PI };
+            builder = builder.harmonic(n, 1.0 / (n * n) as f32, phase);
+        }
+        builder.build()
+    }
+
+    /// Pulse with duty cycle `width` in `(0, 1)`; `0.5` is a square.
+    ///
+    /// The DC offset of an asymmetric pulse is dropped so the table stays centred on zero.
+    pub fn pulse(size: usize, width: f32) -> WaveTable {
+        assert!(width > 0.0 && width < 1.0, "pulse width must be between 0 and 1");
+        let mut builder = WaveTable::additive(size);
+        for n in 1..max_harmonic(size) + 1 {
+            let amplitude = (n as f32 * PI * width).sin() / n as f32;
+            builder = builder.harmonic(n, amplitude, PI / 2.0);
+        }
+        builder.build()
+    }
+
+    /// Starts an additive table of `size` samples; see [`AdditiveBuilder`].
+    pub fn additive(size: usize) -> AdditiveBuilder {
+        AdditiveBuilder {
+            size,
+            harmonics: Vec::new(),
+            normalize: true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Resamples the cycle to `len` samples through the frequency domain.
+    ///
+    /// Treating the table as one period of a periodic signal means the result is exact for
+    /// every harmonic both lengths can hold; harmonics above the new Nyquist limit are dropped.
+    pub fn resampled(&self, len: usize) -> WaveTable {
+        let size = self.len();
+        if len == size {
+            return self.clone();
+        }
+        let mut planner = FftPlanner::new();
+        let mut spectrum: Vec<Complex<f32>> = self.samples.iter().map(|s| Complex::new(*s, 0.0)).collect();
+        planner.plan_fft_forward(size).process(&mut spectrum);
+
+        // Copy every harmonic strictly below both Nyquist limits, positive and negative side.
+        let mut bins = vec![Complex::new(0.0, 0.0); len];
+        let harmonics = (size.min(len) - 1) / 2;
+        bins[0] = spectrum[0];
+        for k in 1..=harmonics {
+            bins[k] = spectrum[k];
+            bins[len - k] = spectrum[size - k];
+        }
+        planner.plan_fft_inverse(len).process(&mut bins);
+        WaveTable::from_samples(bins.iter().map(|bin| bin.re / size as f32).collect())
+    }
+
+    /// Scales the table so its loudest sample is at ±1. Silent tables are left alone.
+    pub fn normalized(&self) -> WaveTable {
+        let peak = peak(&self.samples);
+        if peak == 0.0 {
+            return self.clone();
+        }
+        WaveTable::from_samples(self.samples.iter().map(|s| s / peak).collect())
+    }
+}
+
+impl From<Vec<f32>> for WaveTable {
+    fn from(samples: Vec<f32>) -> Self {
+        WaveTable::from_samples(samples)
+    }
+}
+
+/*
+    A multi-frame table is a stack of single-cycle frames of equal length. The oscillator scans it
+    with a continuous position from 0 (first frame) to 1 (last frame), blending the two frames
+    around that position, which lets a sound morph while it plays.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiFrameTable {
+    frames: Vec<WaveTable>,
+}
+
+impl MultiFrameTable {
+    pub fn new(frames: Vec<WaveTable>) -> MultiFrameTable {
+        assert!(!frames.is_empty(), "a multi-frame table needs at least one frame");
+        let frame_len = frames[0].len();
+        assert!(
+            frames.iter().all(|frame| frame.len() == frame_len),
+            "every frame must have the same length"
+        );
+        MultiFrameTable { frames }
+    }
+
+    /// Splits back-to-back frames of `frame_len` samples; a trailing partial frame is dropped.
+    pub fn from_concatenated(samples: &[f32], frame_len: usize) -> MultiFrameTable {
+        assert!(frame_len > 0 && samples.len() >= frame_len, "need at least one whole frame");
+        let frames = samples
+            .chunks_exact(frame_len)
+            .map(|frame| WaveTable::from_samples(frame.to_vec()))
+            .collect();
+        MultiFrameTable::new(frames)
+    }
+
+    /// Sweeps a pulse from `min_width` to `max_width` duty cycle over `frame_count` frames.
+    pub fn pulse_sweep(size: usize, frame_count: usize, min_width: f32, max_width: f32) -> MultiFrameTable {
+        let frames = (0..frame_count.max(1))
+            .map(|i| {
+                let t = if frame_count > 1 { i as f32 / (frame_count - 1) as f32 } else { 0.0 };
+                WaveTable::pulse(size, min_width + t * (max_width - min_width))
+            })
+            .collect();
+        MultiFrameTable::new(frames)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frame_len(&self) -> usize {
+        self.frames[0].len()
+    }
+
+    pub fn frame(&self, index: usize) -> &WaveTable {
+        &self.frames[index]
+    }
+
+    pub fn frames(&self) -> &[WaveTable] {
+        &self.frames
+    }
+
+    /// Resamples every frame to `len` samples; see [`WaveTable::resampled`].
+    pub fn resampled(&self, len: usize) -> MultiFrameTable {
+        MultiFrameTable::new(self.frames.iter().map(|frame| frame.resampled(len)).collect())
+    }
+
+    /// Scales all frames by the same factor so the loudest sample of the whole table is at ±1,
+    /// keeping the relative levels of the frames.
+    pub fn normalized(&self) -> MultiFrameTable {
+        let peak = self.frames.iter().map(|frame| peak(frame.samples())).fold(0.0f32, f32::max);
+        if peak == 0.0 {
+            return self.clone();
+        }
+        let frames = self
+            .frames
+            .iter()
+            .map(|frame| WaveTable::from_samples(frame.samples().iter().map(|s| s / peak).collect()))
+            .collect();
+        MultiFrameTable::new(frames)
+    }
+}
+
+impl From<WaveTable> for MultiFrameTable {
+    fn from(table: WaveTable) -> Self {
+        MultiFrameTable::new(vec![table])
+    }
+}
+
+fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+}
+
+/// Highest harmonic a table of `size` samples can hold below its Nyquist limit.
+fn max_harmonic(size: usize) -> usize {
+    (size / 2).saturating_sub(1).max(1)
+}
+
+/// Sums sine partials into a [`WaveTable`].
+///
+/// Each partial is `amplitude * sin(n * x + phase)` for harmonic number `n`. Harmonics at or
+/// above the table's Nyquist limit are ignored. By default the result is scaled so its peak
+/// is exactly 1.
+pub struct AdditiveBuilder {
+    size: usize,
+    harmonics: Vec<(usize, f32, f32)>,
+    normalize: bool,
+}
+
+impl AdditiveBuilder {
+    pub fn harmonic(mut self, number: usize, amplitude: f32, phase: f32) -> Self {
+        self.harmonics.push((number, amplitude, phase));
+        self
+    }
+
+    /// Adds harmonics 1, 2, 3, … from parallel lists of amplitudes and phases.
+    ///
+    /// Missing phases default to zero.
+    pub fn harmonics(mut self, amplitudes: &[f32], phases: &[f32]) -> Self {
+        for (i, amplitude) in amplitudes.iter().enumerate() {
+            let phase = phases.get(i).copied().unwrap_or(0.0);
+            self.harmonics.push((i + 1, *amplitude, phase));
+        }
+        self
+    }
+
+    pub fn normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    pub fn build(self) -> WaveTable {
+        let nyquist = self.size / 2;
+        let mut samples = vec![0.0f32; self.size];
+        for &(number, amplitude, phase) in &self.harmonics {
+            if number == 0 || number >= nyquist.max(2) {
+                continue;
+            }
+            for (i, sample) in samples.iter_mut().enumerate() {
+                let x = 2.0 * PI * (number * i) as f32 / self.size as f32;
+                *sample += amplitude * (x + phase).sin();
+            }
+        }
+
+        let table = WaveTable::from_samples(samples);
+        if self.normalize {
+            table.normalized()
+        } else {
+            table
+        }
+    }
+}
+
+/// The built-in table shapes, as selected on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Pulse,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 5] = [Waveform::Sine, Waveform::Saw, Waveform::Square, Waveform::Triangle, Waveform::Pulse];
+
+    /// Builds this shape; `pulse_width` only affects [`Waveform::Pulse`].
+    pub fn table(self, size: usize, pulse_width: f32) -> WaveTable {
+        match self {
+            Waveform::Sine => WaveTable::sine(size),
+            Waveform::Saw => WaveTable::saw(size),
+            Waveform::Square => WaveTable::square(size),
+            Waveform::Triangle => WaveTable::triangle(size),
+            Waveform::Pulse => WaveTable::pulse(size, pulse_width),
+        }
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sine" | "sin" => Ok(Waveform::Sine),
+            "saw" | "sawtooth" => Ok(Waveform::Saw),
+            "square" | "sqr" => Ok(Waveform::Square),
+            "triangle" | "tri" => Ok(Waveform::Triangle),
+            "pulse" => Ok(Waveform::Pulse),
+            _ => Err(format!("unknown waveform '{}' (expected sine, saw, square, triangle or pulse)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Waveform::Sine => "sine",
+            Waveform::Saw => "saw",
+            Waveform::Square => "square",
+            Waveform::Triangle => "triangle",
+            Waveform::Pulse => "pulse",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peak(table: &WaveTable) -> f32 {
+        super::peak(table.samples())
+    }
+
+    /// Magnitude of harmonic `n` via a single-bin DFT.
+    fn harmonic_magnitude(table: &WaveTable, n: usize) -> f32 {
+        let size = table.len() as f32;
+        let (mut re, mut im) = (0.0f32, 0.0f32);
+        for (i, s) in table.samples().iter().enumerate() {
+            let x = 2.0 * PI * (n * i) as f32 / size;
+            re += s * x.cos();
+            im += s * x.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / size
+    }
+
+    #[test]
+    fn sine_table_holds_one_cycle() {
+        let table = WaveTable::sine(64);
+        assert_eq!(table.len(), 64);
+        assert_eq!(table.samples()[0], 0.0);
+        assert!((table.samples()[16] - 1.0).abs() < 1e-6);
+        assert!((table.samples()[48] + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn generated_tables_are_normalized() {
+        for waveform in [Waveform::Saw, Waveform::Square, Waveform::Triangle, Waveform::Pulse] {
+            let table = waveform.table(256, 0.25);
+            assert_eq!(table.len(), 256);
+            assert!((peak(&table) - 1.0).abs() < 1e-6, "{} peak {}", waveform, peak(&table));
+        }
+    }
+
+    #[test]
+    fn tables_are_band_limited_to_their_own_nyquist() {
+        let table = WaveTable::saw(64);
+        assert!(harmonic_magnitude(&table, 31) > 0.0);
+        assert!(harmonic_magnitude(&table, 32) < 1e-4);
+    }
+
+    #[test]
+    fn square_and_triangle_only_have_odd_harmonics() {
+        for table in [WaveTable::square(128), WaveTable::triangle(128)] {
+            assert!(harmonic_magnitude(&table, 3) > 1e-3);
+            assert!(harmonic_magnitude(&table, 2) < 1e-4);
+            assert!(harmonic_magnitude(&table, 4) < 1e-4);
+        }
+    }
+
+    #[test]
+    fn pulse_width_shapes_the_spectrum() {
+        // A 25% pulse has no 4th, 8th, … harmonic.
+        let table = WaveTable::pulse(128, 0.25);
+        assert!(harmonic_magnitude(&table, 4) < 1e-4);
+        assert!(harmonic_magnitude(&table, 2) > 1e-3);
+        // Centred on zero.
+        let mean = table.samples().iter().sum::<f32>() / table.len() as f32;
+        assert!(mean.abs() < 1e-4);
+    }
+
+    #[test]
+    fn additive_builder_places_harmonics() {
+        let table = WaveTable::additive(64)
+            .harmonics(&[1.0, 0.0, 0.5], &[0.0, 0.0, PI / 2.0])
+            .normalize(false)
+            .build();
+        assert!((harmonic_magnitude(&table, 1) - 1.0).abs() < 1e-4);
+        assert!(harmonic_magnitude(&table, 2) < 1e-4);
+        assert!((harmonic_magnitude(&table, 3) - 0.5).abs() < 1e-4);
+        // The cosine-phase third harmonic contributes 0.5 at x = 0.
+        assert!((table.samples()[0] - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn splits_concatenated_frames() {
+        let samples: Vec<f32> = (0..10).map(|i| i as f32).collect();
+        let table = MultiFrameTable::from_concatenated(&samples, 4);
+        assert_eq!(table.frame_count(), 2);
+        assert_eq!(table.frame_len(), 4);
+        assert_eq!(table.frame(1).samples(), &[4.0, 5.0, 6.0, 7.0]);
+    }
+
+    #[test]
+    fn pulse_sweep_spans_the_requested_widths() {
+        let table = MultiFrameTable::pulse_sweep(128, 5, 0.1, 0.5);
+        assert_eq!(table.frame_count(), 5);
+        assert_eq!(table.frame(0), &WaveTable::pulse(128, 0.1));
+        assert_eq!(table.frame(4), &WaveTable::pulse(128, 0.5));
+    }
+
+    #[test]
+    #[should_panic(expected = "same length")]
+    fn rejects_frames_of_different_lengths() {
+        MultiFrameTable::new(vec![WaveTable::sine(64), WaveTable::sine(32)]);
+    }
+
+    #[test]
+    fn resampling_keeps_the_harmonics() {
+        let table = WaveTable::additive(2048).harmonics(&[1.0, 0.5, 0.25], &[0.0, 1.0, 2.0]).normalize(false).build();
+        let expected = WaveTable::additive(64).harmonics(&[1.0, 0.5, 0.25], &[0.0, 1.0, 2.0]).normalize(false).build();
+        let down = table.resampled(64);
+        for (a, b) in down.samples().iter().zip(expected.samples()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+        let up = down.resampled(2048);
+        for (a, b) in up.samples().iter().zip(table.samples()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn normalizing_frames_keeps_their_balance() {
+        let quiet = WaveTable::from_samples(vec![0.1, -0.2]);
+        let loud = WaveTable::from_samples(vec![0.4, -0.1]);
+        let table = MultiFrameTable::new(vec![quiet, loud]).normalized();
+        assert_eq!(table.frame(0).samples(), &[0.25, -0.5]);
+        assert_eq!(table.frame(1).samples(), &[1.0, -0.25]);
+    }
+
+    #[test]
+    fn parses_waveform_names() {
+        assert_eq!("Saw".parse::<Waveform>(), Ok(Waveform::Saw));
+        assert_eq!("tri".parse::<Waveform>(), Ok(Waveform::Triangle));
+        assert!("noise".parse::<Waveform>().is_err());
+    }
+}